@@ -0,0 +1,141 @@
+use crate::{
+    backend::{Backend, OutputMode},
+    input::{InputEvent, InputSink, SeatId},
+    protocol::wl_seat::Capability,
+    region::Region,
+    render::Framebuffer,
+};
+
+use nix::{
+    sys::eventfd::{eventfd, EfdFlags},
+    unistd::{read, write},
+};
+
+use std::{
+    io,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::{mpsc, Arc},
+};
+
+/// What an `InputInjector` sends to the backend.
+enum Injected {
+    Input(InputEvent),
+    Capabilities(Capability),
+}
+
+/// A backend without a display or input devices. Outputs are framebuffers in memory and input
+/// is injected through an `InputInjector`.
+pub struct Headless {
+    outputs: Vec<OutputMode>,
+    framebuffers: Vec<Framebuffer>,
+    input_tx: mpsc::Sender<Injected>,
+    input_rx: mpsc::Receiver<Injected>,
+    input_fd: Arc<OwnedFd>,
+    seat_id: Option<SeatId>,
+}
+
+impl Headless {
+    /// Create a backend with virtual outputs, a refresh of 0 uses the fallback refresh rate.
+    pub fn new(outputs: Vec<OutputMode>) -> Self {
+        let (input_tx, input_rx) = mpsc::channel();
+        let fd = eventfd(0, EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC)
+            .expect("failed to create eventfd");
+        // Safety: the fd was just created and is not owned by anything else.
+        let input_fd = Arc::new(unsafe { OwnedFd::from_raw_fd(fd) });
+        // The seat is created on the first drain, which should not wait for injected input.
+        write(fd, &1u64.to_ne_bytes()).expect("failed to write input eventfd");
+
+        let framebuffers = outputs
+            .iter()
+            .map(|mode| Framebuffer::new(mode.width, mode.height))
+            .collect();
+
+        Self {
+            outputs,
+            framebuffers,
+            input_tx,
+            input_rx,
+            input_fd,
+            seat_id: None,
+        }
+    }
+
+    /// A handle to inject input with, which can be sent to other threads.
+    pub fn injector(&self) -> InputInjector {
+        InputInjector {
+            tx: self.input_tx.clone(),
+            fd: self.input_fd.clone(),
+        }
+    }
+
+    /// The last frame presented on an output.
+    pub fn framebuffer(&self, output: usize) -> Option<&Framebuffer> {
+        self.framebuffers.get(output)
+    }
+}
+
+impl Backend for Headless {
+    fn input_fd(&self) -> RawFd {
+        self.input_fd.as_raw_fd()
+    }
+
+    fn drain_input(&mut self, sink: &mut InputSink) -> io::Result<()> {
+        let seat_id = *self.seat_id.get_or_insert_with(|| {
+            sink.create_seat(
+                "seat0",
+                Capability::KEYBOARD | Capability::POINTER | Capability::TOUCH,
+            )
+        });
+
+        let mut counter = 0u64.to_ne_bytes();
+        read(self.input_fd.as_raw_fd(), &mut counter)?;
+
+        while let Ok(injected) = self.input_rx.try_recv() {
+            match injected {
+                Injected::Input(event) => sink.handle_event(seat_id, event),
+                Injected::Capabilities(capabilities) => {
+                    sink.set_capabilities(seat_id, capabilities)
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn outputs(&self) -> Vec<OutputMode> {
+        self.outputs.clone()
+    }
+
+    fn present(&mut self, output: usize, framebuffer: &Framebuffer, damage: &Region) {
+        if let Some(target) = self.framebuffers.get_mut(output) {
+            target.copy_from(framebuffer, damage);
+        }
+    }
+}
+
+/// Injects input into a `Headless` backend.
+#[derive(Clone)]
+pub struct InputInjector {
+    tx: mpsc::Sender<Injected>,
+    fd: Arc<OwnedFd>,
+}
+
+impl InputInjector {
+    /// Queue an event for the seat of the backend. Fails once the backend is dropped.
+    pub fn inject(&self, event: InputEvent) -> io::Result<()> {
+        self.send(Injected::Input(event))
+    }
+
+    /// Change the capabilities of the seat, as if devices were plugged in or removed.
+    pub fn set_capabilities(&self, capabilities: Capability) -> io::Result<()> {
+        self.send(Injected::Capabilities(capabilities))
+    }
+
+    fn send(&self, injected: Injected) -> io::Result<()> {
+        self.tx
+            .send(injected)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        write(self.fd.as_raw_fd(), &1u64.to_ne_bytes())?;
+        Ok(())
+    }
+}
@@ -1,11 +1,43 @@
-use crate::input::InputSink;
+use crate::{input::InputSink, region::Region, render::Framebuffer};
 
 use std::{io, os::unix::io::RawFd};
 
+mod headless;
 mod winit;
-pub use self::winit::Winit;
+pub use self::{
+    headless::{Headless, InputInjector},
+    winit::Winit,
+};
+
+/// Size and refresh rate of an output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputMode {
+    pub width: i32,
+    pub height: i32,
+    /// Refresh rate in mHz, 0 if unknown.
+    pub refresh: u32,
+}
 
 pub trait Backend {
     fn input_fd(&self) -> RawFd;
     fn drain_input(&mut self, sink: &mut InputSink) -> io::Result<()>;
+
+    /// The outputs frames are presented on. Queried on startup and after input was drained,
+    /// which is when backends report mode changes.
+    fn outputs(&self) -> Vec<OutputMode>;
+
+    /// Show a frame on the output at index `output`, only `damage` changed since the
+    /// previous frame.
+    fn present(&mut self, output: usize, framebuffer: &Framebuffer, damage: &Region);
+
+    /// An fd that becomes readable when outputs have refreshed, for backends that know when
+    /// the display refreshes. Outputs of other backends are driven by a timer.
+    fn frame_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Indices into `outputs` of the outputs that refreshed since the last call.
+    fn drain_frames(&mut self) -> io::Result<Vec<usize>> {
+        Ok(vec![])
+    }
 }
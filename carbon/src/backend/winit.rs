@@ -1,57 +1,89 @@
 use crate::{
-    backend::Backend,
-    input::{InputSink, SeatId},
-    protocol::wl_seat::Capability,
+    backend::{Backend, OutputMode},
+    input::{InputEvent, InputSink, SeatId},
+    protocol::{
+        wl_keyboard::KeyState,
+        wl_pointer::{Axis, ButtonState},
+        wl_seat::Capability,
+    },
+    region::Region,
+    render::Framebuffer,
 };
 
 use nix::{
     sys::eventfd::{eventfd, EfdFlags},
     unistd::{close, read, write},
 };
+use raw_window_handle::{
+    DisplayHandle, HandleError, HasDisplayHandle, HasWindowHandle, RawDisplayHandle,
+    RawWindowHandle, WaylandDisplayHandle, WaylandWindowHandle, WindowHandle, XlibDisplayHandle,
+    XlibWindowHandle,
+};
+use raw_window_handle_04::{HasRawWindowHandle, RawWindowHandle as RawWindowHandle04};
+use softbuffer::SoftBufferError;
 use winit::{
-    event::{DeviceEvent, Event, WindowEvent},
+    event::{DeviceEvent, ElementState, Event, MouseButton, MouseScrollDelta, WindowEvent},
     event_loop::{ControlFlow, EventLoop, EventLoopProxy},
-    platform::{run_return::EventLoopExtRunReturn, unix::EventLoopExtUnix},
-    window::WindowBuilder,
+    platform::{
+        run_return::EventLoopExtRunReturn,
+        unix::{EventLoopExtUnix, WindowExtUnix},
+    },
+    window::{Window, WindowBuilder},
 };
 
 use std::{
+    ffi::c_int,
     io,
     mem::drop,
+    num::NonZeroU32,
     os::unix::io::RawFd,
+    ptr::NonNull,
     sync::mpsc::{self, TryRecvError},
     thread,
 };
 
 pub struct Winit {
-    input_rx: mpsc::Receiver<DeviceEvent>,
+    input_rx: mpsc::Receiver<ThreadEvent>,
     input_fd: RawFd,
-    proxy: EventLoopProxy<BackendDropped>,
+    proxy: EventLoopProxy<UserEvent>,
+    thread: Option<thread::JoinHandle<()>>,
     seat_id: Option<SeatId>,
+    /// The window, which is the only output.
+    output: OutputMode,
     closed: bool,
 }
 
 impl Drop for Winit {
     fn drop(&mut self) {
-        let _ = self.proxy.send_event(BackendDropped);
+        let _ = self.proxy.send_event(UserEvent::BackendDropped);
+        // Wait for the window to be closed, the thread writes to the eventfd before exiting.
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::error!("Window thread panicked");
+            }
+        }
         let _ = close(self.input_fd);
     }
 }
 
 impl Winit {
+    /// Open a window on a thread running the winit event loop.
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         let (input_tx, input_rx) = mpsc::channel();
         let (proxy_tx, proxy_rx) = mpsc::sync_channel(1);
         let input_fd = eventfd(0, EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC)
             .expect("failed to create eventfd");
-        thread::spawn(move || run_event_loop(proxy_tx, input_tx, input_fd));
-        let proxy = proxy_rx.recv().unwrap();
+        let thread = thread::spawn(move || run_event_loop(proxy_tx, input_tx, input_fd));
+        let (proxy, output) = proxy_rx.recv().unwrap();
 
         Self {
             input_rx,
             input_fd,
             proxy,
+            thread: Some(thread),
             seat_id: None,
+            output,
             closed: false,
         }
     }
@@ -67,28 +99,17 @@ impl Backend for Winit {
             return Ok(());
         }
 
-        let seat_id = *self
-            .seat_id
-            .get_or_insert_with(|| sink.create_seat(Capability::KEYBOARD | Capability::POINTER));
+        let seat_id = *self.seat_id.get_or_insert_with(|| {
+            sink.create_seat("seat0", Capability::KEYBOARD | Capability::POINTER)
+        });
 
         let mut counter = 0u64.to_ne_bytes();
         read(self.input_fd, &mut counter)?;
 
         loop {
             match self.input_rx.try_recv() {
-                Ok(event) => match event {
-                    DeviceEvent::MouseMotion { delta } => (),
-                    DeviceEvent::MouseWheel { delta } => (),
-                    DeviceEvent::Motion { axis, value } => (),
-                    DeviceEvent::Button { button, state } => match button {
-                        1 | 0x110 => log::debug!("left"),
-                        2 | 0x112 => log::debug!("middle"),
-                        3 | 0x111 => log::debug!("right"),
-                        _ => log::warn!("Unknown button event: {:04x} {:?}", button, state),
-                    },
-                    DeviceEvent::Key(key) => (),
-                    _ => (),
-                },
+                Ok(ThreadEvent::Input(event)) => sink.handle_event(seat_id, event),
+                Ok(ThreadEvent::Resized(mode)) => self.output = mode,
                 Err(TryRecvError::Empty) => break,
                 Err(TryRecvError::Disconnected) => {
                     sink.destroy_seat(seat_id);
@@ -101,48 +122,360 @@ impl Backend for Winit {
 
         Ok(())
     }
+
+    fn outputs(&self) -> Vec<OutputMode> {
+        vec![self.output]
+    }
+
+    fn present(&mut self, _output: usize, framebuffer: &Framebuffer, damage: &Region) {
+        let damage = damage
+            .rects()
+            .filter_map(|rect| {
+                Some(softbuffer::Rect {
+                    x: rect.x as u32,
+                    y: rect.y as u32,
+                    width: NonZeroU32::new(rect.width as u32)?,
+                    height: NonZeroU32::new(rect.height as u32)?,
+                })
+            })
+            .collect();
+        let frame = Frame {
+            width: framebuffer.width as u32,
+            height: framebuffer.height as u32,
+            pixels: framebuffer.pixels.clone(),
+            damage,
+        };
+        // Fails once the window is closed, there is nothing to show frames in then.
+        let _ = self.proxy.send_event(UserEvent::Present(frame));
+    }
+}
+
+enum UserEvent {
+    Present(Frame),
+    BackendDropped,
+}
+
+/// Events sent from the window thread to the backend.
+enum ThreadEvent {
+    Input(InputEvent),
+    /// The window was resized, which changes the mode of the output.
+    Resized(OutputMode),
+}
+
+/// A copy of a framebuffer that is sent to the window thread.
+struct Frame {
+    width: u32,
+    height: u32,
+    pixels: Vec<u32>,
+    damage: Vec<softbuffer::Rect>,
+}
+
+/// Shows frames in the window through a software pixel buffer.
+struct Presenter {
+    surface: softbuffer::Surface<SoftbufferHandle, SoftbufferHandle>,
+    /// The last frame, which is shown again when the window needs to be redrawn.
+    frame: Option<Frame>,
+}
+
+impl Presenter {
+    fn new(window: &Window) -> Result<Self, SoftBufferError> {
+        let handle = SoftbufferHandle {
+            raw: window.raw_window_handle(),
+            screen: window.xlib_screen_id().unwrap_or(0),
+        };
+        let context = softbuffer::Context::new(handle)?;
+        let surface = softbuffer::Surface::new(&context, handle)?;
+
+        Ok(Self {
+            surface,
+            frame: None,
+        })
+    }
+
+    fn present(&mut self, frame: Frame) {
+        if let Err(e) = draw(&mut self.surface, &frame, true) {
+            log::error!("Failed to present frame: {}", e);
+        }
+        self.frame = Some(frame);
+    }
+
+    fn redraw(&mut self) {
+        if let Some(frame) = &self.frame {
+            if let Err(e) = draw(&mut self.surface, frame, false) {
+                log::error!("Failed to redraw window: {}", e);
+            }
+        }
+    }
+}
+
+/// Copy a frame into the pixel buffer of the window and show it, telling the window system
+/// which part changed if `damaged_only` is set.
+fn draw(
+    surface: &mut softbuffer::Surface<SoftbufferHandle, SoftbufferHandle>,
+    frame: &Frame,
+    damaged_only: bool,
+) -> Result<(), SoftBufferError> {
+    let (width, height) = match (NonZeroU32::new(frame.width), NonZeroU32::new(frame.height)) {
+        (Some(width), Some(height)) => (width, height),
+        _ => return Ok(()),
+    };
+    surface.resize(width, height)?;
+
+    let mut buffer = surface.buffer_mut()?;
+    buffer.copy_from_slice(&frame.pixels);
+    if damaged_only {
+        buffer.present_with_damage(&frame.damage)
+    } else {
+        buffer.present()
+    }
+}
+
+/// The window handle of winit converted for softbuffer, which depends on a newer version of
+/// `raw-window-handle` than winit does.
+#[derive(Clone, Copy)]
+struct SoftbufferHandle {
+    raw: RawWindowHandle04,
+    /// X11 screen of the window, which the old handle does not include.
+    screen: c_int,
+}
+
+impl HasDisplayHandle for SoftbufferHandle {
+    fn display_handle(&self) -> Result<DisplayHandle<'_>, HandleError> {
+        let raw = match self.raw {
+            RawWindowHandle04::Xlib(handle) => RawDisplayHandle::Xlib(XlibDisplayHandle::new(
+                NonNull::new(handle.display),
+                self.screen,
+            )),
+            RawWindowHandle04::Wayland(handle) => {
+                let display = NonNull::new(handle.display).ok_or(HandleError::Unavailable)?;
+                RawDisplayHandle::Wayland(WaylandDisplayHandle::new(display))
+            }
+            _ => return Err(HandleError::NotSupported),
+        };
+        // Safety: the display connection of winit outlives the presenter, which is dropped
+        // before the window.
+        Ok(unsafe { DisplayHandle::borrow_raw(raw) })
+    }
+}
+
+impl HasWindowHandle for SoftbufferHandle {
+    fn window_handle(&self) -> Result<WindowHandle<'_>, HandleError> {
+        let raw = match self.raw {
+            RawWindowHandle04::Xlib(handle) => {
+                let mut xlib = XlibWindowHandle::new(handle.window);
+                xlib.visual_id = handle.visual_id;
+                RawWindowHandle::Xlib(xlib)
+            }
+            RawWindowHandle04::Wayland(handle) => {
+                let surface = NonNull::new(handle.surface).ok_or(HandleError::Unavailable)?;
+                RawWindowHandle::Wayland(WaylandWindowHandle::new(surface))
+            }
+            _ => return Err(HandleError::NotSupported),
+        };
+        // Safety: the presenter is dropped before the window.
+        Ok(unsafe { WindowHandle::borrow_raw(raw) })
+    }
+}
+
+const BTN_LEFT: u32 = 0x110;
+const BTN_RIGHT: u32 = 0x111;
+const BTN_MIDDLE: u32 = 0x112;
+const BTN_SIDE: u32 = 0x113;
+const BTN_EXTRA: u32 = 0x114;
+
+/// Scroll distance of one wheel step, the same as libinput reports.
+const WHEEL_STEP: f64 = 15.0;
+
+/// Turn a window event into input of the seat. Window events follow the focus of the window,
+/// and the host keeps sending pointer events to it while a button is held.
+fn translate_window_event(event: &WindowEvent, mut emit: impl FnMut(InputEvent)) {
+    match *event {
+        WindowEvent::CursorMoved { position, .. } => emit(InputEvent::PointerMotionAbsolute {
+            x: position.x,
+            y: position.y,
+        }),
+        WindowEvent::MouseInput { state, button, .. } => {
+            if let Some(button) = evdev_button(button) {
+                emit(InputEvent::PointerButton {
+                    button,
+                    state: match state {
+                        ElementState::Pressed => ButtonState::Pressed,
+                        ElementState::Released => ButtonState::Released,
+                    },
+                });
+            }
+        }
+        // winit reports the direction the content moves in, Wayland the direction of the scroll.
+        WindowEvent::MouseWheel { delta, .. } => match delta {
+            MouseScrollDelta::LineDelta(x, y) => {
+                for (axis, lines) in [(Axis::VerticalScroll, -y), (Axis::HorizontalScroll, -x)] {
+                    if lines == 0.0 {
+                        continue;
+                    }
+                    // High resolution wheels report fractions of a step, which are only
+                    // reported as distance.
+                    let steps = lines.round() as i32;
+                    emit(InputEvent::PointerAxis {
+                        axis,
+                        value: f64::from(lines) * WHEEL_STEP,
+                        discrete: (steps != 0).then_some(steps),
+                    });
+                }
+            }
+            MouseScrollDelta::PixelDelta(position) => {
+                for (axis, value) in [
+                    (Axis::VerticalScroll, -position.y),
+                    (Axis::HorizontalScroll, -position.x),
+                ] {
+                    if value != 0.0 {
+                        emit(InputEvent::PointerAxis {
+                            axis,
+                            value,
+                            discrete: None,
+                        });
+                    }
+                }
+            }
+        },
+        // Scancodes are evdev codes on both X11 and Wayland.
+        WindowEvent::KeyboardInput { input, .. } => emit(InputEvent::Key {
+            key: input.scancode,
+            state: match input.state {
+                ElementState::Pressed => KeyState::Pressed,
+                ElementState::Released => KeyState::Released,
+            },
+        }),
+        WindowEvent::Focused(focused) => emit(InputEvent::Focus { focused }),
+        _ => (),
+    }
+}
+
+/// The evdev code of a button. winit reports other buttons as evdev code on Wayland and as
+/// button number on X11, where scroll buttons are reported as wheel events instead.
+fn evdev_button(button: MouseButton) -> Option<u32> {
+    match button {
+        MouseButton::Left => Some(BTN_LEFT),
+        MouseButton::Right => Some(BTN_RIGHT),
+        MouseButton::Middle => Some(BTN_MIDDLE),
+        MouseButton::Other(8) => Some(BTN_SIDE),
+        MouseButton::Other(9) => Some(BTN_EXTRA),
+        MouseButton::Other(button) if (BTN_LEFT..=0x11f).contains(&u32::from(button)) => {
+            Some(u32::from(button))
+        }
+        MouseButton::Other(4..=7) => None,
+        MouseButton::Other(button) => {
+            log::warn!("Unknown button {:#x}", button);
+            None
+        }
+    }
 }
 
-struct BackendDropped;
+/// The window and what draws into it, in the order they need to be dropped.
+struct WindowState {
+    presenter: Option<Presenter>,
+    _window: Window,
+}
 
 fn run_event_loop(
-    proxy_tx: mpsc::SyncSender<EventLoopProxy<BackendDropped>>,
-    input_tx: mpsc::Sender<DeviceEvent>,
+    proxy_tx: mpsc::SyncSender<(EventLoopProxy<UserEvent>, OutputMode)>,
+    input_tx: mpsc::Sender<ThreadEvent>,
     input_fd: RawFd,
 ) {
     let mut event_loop = EventLoop::new_any_thread();
     let proxy = event_loop.create_proxy();
-    proxy_tx.send(proxy).unwrap();
-    drop(proxy_tx);
 
     let window = WindowBuilder::new()
         .with_title("carbon")
         .build(&event_loop)
         .expect("failed to build window");
+    let size = window.inner_size();
+    // winit does not tell which mode the monitor is in, so the refresh rate is unknown.
+    let output = OutputMode {
+        width: size.width as i32,
+        height: size.height as i32,
+        refresh: 0,
+    };
+    proxy_tx.send((proxy, output)).unwrap();
+    drop(proxy_tx);
+
+    let presenter = match Presenter::new(&window) {
+        Ok(presenter) => Some(presenter),
+        Err(e) => {
+            log::error!("Failed to create pixel buffer, frames are not shown: {}", e);
+            None
+        }
+    };
     // Window needs to be dropped inside the event loop, otherwise it will stay open
     // See: https://github.com/rust-windowing/winit/issues/2345
-    let mut window = Some(window);
+    let mut window = Some(WindowState {
+        presenter,
+        _window: window,
+    });
+
+    let mut thread_events = vec![];
+    // Device events are reported for the whole host, so motion is only used while the cursor
+    // is over the window.
+    let mut hovered = false;
 
     event_loop.run_return(move |event, _window_target, control_flow| {
         match event {
             Event::NewEvents(_) => *control_flow = ControlFlow::Wait,
-            Event::UserEvent(BackendDropped) => *control_flow = ControlFlow::Exit,
-            Event::WindowEvent { event, .. } => match event {
-                WindowEvent::CloseRequested => {
-                    *control_flow = ControlFlow::Exit;
+            Event::UserEvent(UserEvent::BackendDropped) => *control_flow = ControlFlow::Exit,
+            Event::UserEvent(UserEvent::Present(frame)) => {
+                if let Some(presenter) = window.as_mut().and_then(|w| w.presenter.as_mut()) {
+                    presenter.present(frame);
                 }
-                _ => (),
-            },
-            Event::DeviceEvent { event, .. } => match input_tx.send(event) {
-                Ok(_) => {
-                    write(input_fd, &1u64.to_ne_bytes()).expect("failed to write input eventfd");
+            }
+            Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                ..
+            } => *control_flow = ControlFlow::Exit,
+            Event::WindowEvent {
+                event: WindowEvent::Resized(size),
+                ..
+            } if size.width > 0 && size.height > 0 => {
+                thread_events.push(ThreadEvent::Resized(OutputMode {
+                    width: size.width as i32,
+                    height: size.height as i32,
+                    refresh: 0,
+                }))
+            }
+            Event::WindowEvent {
+                event: WindowEvent::CursorEntered { .. },
+                ..
+            } => hovered = true,
+            Event::WindowEvent {
+                event: WindowEvent::CursorLeft { .. },
+                ..
+            } => hovered = false,
+            Event::WindowEvent { event, .. } => translate_window_event(&event, |input| {
+                thread_events.push(ThreadEvent::Input(input))
+            }),
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta: (dx, dy) },
+                ..
+            } if hovered => {
+                thread_events.push(ThreadEvent::Input(InputEvent::RawPointerMotion { dx, dy }))
+            }
+            Event::RedrawRequested(_) => {
+                if let Some(presenter) = window.as_mut().and_then(|w| w.presenter.as_mut()) {
+                    presenter.redraw();
                 }
-                Err(_) => *control_flow = ControlFlow::Exit,
-            },
-            Event::RedrawRequested(_) => (),
+            }
             _ => (),
         }
 
+        if !thread_events.is_empty() {
+            if thread_events
+                .drain(..)
+                .all(|thread_event| input_tx.send(thread_event).is_ok())
+            {
+                write(input_fd, &1u64.to_ne_bytes()).expect("failed to write input eventfd");
+            } else {
+                *control_flow = ControlFlow::Exit;
+            }
+        }
+
         if *control_flow == ControlFlow::Exit {
             window.take();
         }
@@ -1,27 +1,227 @@
 use crate::{
     gateway::{
-        message::MessageStream,
+        event_loop::SourceId,
+        message::{MessageBuf, MessageError, MessageStream, Write},
         registry::{ClientObjects, GlobalObjectId, ObjectId, ObjectRegistry},
+        serial::Serials,
     },
-    protocol::Interface,
+    protocol::{wl_callback, wl_display, wl_shell_surface, xdg_wm_base, Interface},
+    surface,
 };
 
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+
+use std::{fmt, fs, os::unix::io::RawFd, path::PathBuf, time::Instant};
+
+/// Credentials of the process on the other end of a client connection.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Path of the executable the process was started from, if it could be resolved.
+    pub exe: Option<PathBuf>,
+}
+
+impl ClientInfo {
+    /// Query the peer credentials of a connected unix stream with `SO_PEERCRED`.
+    ///
+    /// The credentials are captured at connect time, so they stay valid even if the client
+    /// passes the connection on to another process.
+    pub fn from_stream(stream_fd: RawFd) -> nix::Result<Self> {
+        let creds = getsockopt(stream_fd, PeerCredentials)?;
+        let exe = fs::read_link(format!("/proc/{}/exe", creds.pid())).ok();
+
+        Ok(Self {
+            pid: creds.pid(),
+            uid: creds.uid(),
+            gid: creds.gid(),
+            exe,
+        })
+    }
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pid {}, uid {}, gid {}", self.pid, self.uid, self.gid)?;
+        if let Some(exe) = &self.exe {
+            write!(f, " ({})", exe.to_string_lossy())?;
+        }
+        Ok(())
+    }
+}
+
+/// Liveness of a client, tracked by pinging it through `xdg_wm_base` or `wl_shell_surface`.
+#[derive(Debug, Default)]
+pub struct PingState {
+    last_serial: u32,
+    /// Serial and deadline of the ping that has not been answered yet.
+    pending: Option<(u32, Instant)>,
+    unresponsive: bool,
+}
+
+impl PingState {
+    /// Whether the client did not answer a ping before its deadline and has not answered
+    /// it since.
+    #[inline]
+    pub fn is_unresponsive(&self) -> bool {
+        self.unresponsive
+    }
+
+    /// Handle a pong from the client, pongs that don't match the pending ping are ignored.
+    pub fn pong(&mut self, serial: u32, info: &ClientInfo) {
+        match self.pending {
+            Some((pending, _)) if pending == serial => {
+                self.pending = None;
+                if self.unresponsive {
+                    log::info!("Client {} is responding again", info);
+                    self.unresponsive = false;
+                }
+            }
+            _ => log::debug!(
+                "Client {} sent pong with unexpected serial {}",
+                info,
+                serial
+            ),
+        }
+    }
+
+    fn start(&mut self, deadline: Instant) -> u32 {
+        self.last_serial = self.last_serial.wrapping_add(1);
+        self.pending = Some((self.last_serial, deadline));
+        self.last_serial
+    }
+
+    /// Mark the client as unresponsive if the pending ping is past its deadline. Returns the
+    /// deadline if it has not passed yet.
+    fn check_deadline(&mut self, now: Instant) -> Option<Instant> {
+        let (_, deadline) = self.pending?;
+        if deadline > now {
+            Some(deadline)
+        } else {
+            self.unresponsive = true;
+            None
+        }
+    }
+}
+
 pub struct Client {
     stream: MessageStream,
     objects: ClientObjects,
+    info: ClientInfo,
+    ping: PingState,
+    /// The event source of the stream.
+    source_id: SourceId,
+    /// Whether the stream is registered for `EPOLLOUT` because a flush could not complete.
+    pub wants_writable: bool,
 }
 
 impl Client {
-    pub fn new(stream: MessageStream, display_id: GlobalObjectId) -> Self {
+    pub fn new(
+        stream: MessageStream,
+        display_id: GlobalObjectId,
+        info: ClientInfo,
+        source_id: SourceId,
+    ) -> Self {
         Self {
             stream,
             objects: ClientObjects::new(display_id),
+            info,
+            ping: PingState::default(),
+            source_id,
+            wants_writable: false,
         }
     }
 
     #[inline]
-    pub fn stream_and_objects_mut(&mut self) -> (&mut MessageStream, &mut ClientObjects) {
-        (&mut self.stream, &mut self.objects)
+    pub fn parts_mut(
+        &mut self,
+    ) -> (
+        &mut MessageStream,
+        &mut ClientObjects,
+        &ClientInfo,
+        &mut PingState,
+    ) {
+        (
+            &mut self.stream,
+            &mut self.objects,
+            &self.info,
+            &mut self.ping,
+        )
+    }
+
+    #[inline]
+    pub fn info(&self) -> &ClientInfo {
+        &self.info
+    }
+
+    /// Send a ping to the first `xdg_wm_base` or `wl_shell_surface` of the client, unless
+    /// the previous ping is still pending. Returns whether a ping was sent.
+    fn ping(&mut self, registry: &ObjectRegistry, deadline: Instant) -> Result<bool, MessageError> {
+        if self.ping.pending.is_some() {
+            return Ok(false);
+        }
+
+        let target = self.objects.iter().find_map(|(object_id, global_id)| {
+            match registry.get(global_id)? {
+                Interface::XdgWmBase(_) => Some((object_id, true)),
+                Interface::WlShellSurface(_) => Some((object_id, false)),
+                _ => None,
+            }
+        });
+        let (object_id, is_xdg) = match target {
+            Some(target) => target,
+            None => return Ok(false),
+        };
+
+        let serial = self.ping.start(deadline);
+        let send_buf = self.stream.send_buf_mut();
+        if is_xdg {
+            xdg_wm_base::emit_ping(send_buf, object_id, serial)?;
+        } else {
+            wl_shell_surface::emit_ping(send_buf, object_id, serial)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Send `wl_callback.done` to the frame callbacks of the surfaces in `presented`.
+    /// Callbacks of hidden surfaces wait until they are shown.
+    ///
+    /// Returns whether any callbacks were sent.
+    fn send_frame_callbacks(
+        &mut self,
+        registry: &mut ObjectRegistry,
+        presented: &[GlobalObjectId],
+        time: u32,
+    ) -> Result<bool, MessageError> {
+        let shown: Vec<_> = self
+            .objects
+            .owned()
+            .filter(|global_id| presented.contains(global_id))
+            .collect();
+        let mut callbacks = vec![];
+        for global_id in shown {
+            if let Some(surface) = surface::get_surface_mut(registry, global_id) {
+                callbacks.append(&mut surface.current.frame_callbacks);
+            }
+        }
+
+        let send_buf = self.stream.send_buf_mut();
+        for &callback in &callbacks {
+            wl_callback::emit_done(send_buf, callback, time)?;
+            if let Some(global_id) = self.objects.unregister(callback)? {
+                registry.remove(global_id);
+            }
+            wl_display::emit_delete_id(send_buf, ObjectId::display(), callback.raw())?;
+        }
+
+        Ok(!callbacks.is_empty())
+    }
+
+    #[inline]
+    pub fn source_id(&self) -> SourceId {
+        self.source_id
     }
 
     #[inline]
@@ -32,11 +232,33 @@ impl Client {
 
 pub struct Clients {
     clients: Vec<Option<Client>>,
+    /// Ids of clients that may have events in their send buffer which need to be flushed.
+    dirty: Vec<u32>,
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Clients {
     pub fn new() -> Self {
-        Self { clients: vec![] }
+        Self {
+            clients: vec![],
+            dirty: vec![],
+        }
+    }
+
+    #[inline]
+    pub fn mark_dirty(&mut self, id: u32) {
+        mark_dirty(&mut self.dirty, id);
+    }
+
+    /// Take the ids of all clients that need to be flushed.
+    #[inline]
+    pub fn take_dirty(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.dirty)
     }
 
     pub fn next_id(&self) -> u32 {
@@ -55,18 +277,174 @@ impl Clients {
         }
     }
 
-    pub fn delete(&mut self, id: u32) {
-        if let Some(entry) = self.clients.get_mut(id as usize) {
-            let _ = entry.take();
+    /// Remove the client and all objects it owns from the registry, and forget the serials
+    /// it was sent.
+    ///
+    /// The client is returned so the caller can unregister its event source before the stream
+    /// is closed.
+    pub fn delete(
+        &mut self,
+        id: u32,
+        registry: &mut ObjectRegistry,
+        serials: &mut Serials,
+    ) -> Option<Client> {
+        let client = self.clients.get_mut(id as usize).and_then(Option::take)?;
+        for global_id in client.objects.owned() {
+            registry.remove(global_id);
+        }
+        serials.forget_client(id);
+        Some(client)
+    }
+
+    /// Remove clients whose send buffer overflowed, since the events they missed leave them in
+    /// an inconsistent state.
+    pub fn delete_overflowed(
+        &mut self,
+        registry: &mut ObjectRegistry,
+        serials: &mut Serials,
+    ) -> Vec<Client> {
+        let mut deleted = vec![];
+        for id in 0..self.clients.len() as u32 {
+            if self.get_mut(id).is_some_and(|c| c.stream.is_overflowed()) {
+                log::error!("Send buffer of client overflowed");
+                log::error!("Dropping this client");
+                deleted.extend(self.delete(id, registry, serials));
+            }
+        }
+        deleted
+    }
+
+    /// Try to flush any pending events to every client, then remove them all.
+    pub fn disconnect_all(
+        &mut self,
+        registry: &mut ObjectRegistry,
+        serials: &mut Serials,
+    ) -> Vec<Client> {
+        let mut deleted = vec![];
+        for id in 0..self.clients.len() as u32 {
+            if let Some(client) = self.get_mut(id) {
+                if let Err(e) = client.stream.flush() {
+                    log::debug!("Failed to flush events before disconnecting: {}", e);
+                }
+                deleted.extend(self.delete(id, registry, serials));
+            }
+        }
+        self.dirty.clear();
+        deleted
+    }
+
+    /// Ping every client that has no ping pending, which has to be answered before `deadline`.
+    pub fn ping_all(&mut self, registry: &ObjectRegistry, deadline: Instant) {
+        for id in 0..self.clients.len() as u32 {
+            if let Some(client) = self.get_mut(id) {
+                match client.ping(registry, deadline) {
+                    Ok(true) => self.mark_dirty(id),
+                    Ok(false) => (),
+                    Err(e) => log::error!("Failed to ping client {}: {}", client.info, e),
+                }
+            }
+        }
+    }
+
+    /// Send the frame callbacks of presented surfaces, with the time of the frame in
+    /// milliseconds.
+    pub fn send_frame_callbacks(
+        &mut self,
+        registry: &mut ObjectRegistry,
+        presented: &[GlobalObjectId],
+        time: u32,
+    ) {
+        for id in 0..self.clients.len() as u32 {
+            if let Some(client) = self.get_mut(id) {
+                match client.send_frame_callbacks(registry, presented, time) {
+                    Ok(true) => self.mark_dirty(id),
+                    Ok(false) => (),
+                    Err(e) => log::error!(
+                        "Failed to send frame callbacks to client {}: {}",
+                        client.info,
+                        e
+                    ),
+                }
+            }
         }
     }
 
+    /// Mark clients whose pending ping is past its deadline as unresponsive. Returns the
+    /// earliest deadline that has not passed yet and the clients that just became
+    /// unresponsive.
+    pub fn check_ping_deadlines(&mut self, now: Instant) -> (Option<Instant>, Vec<u32>) {
+        let mut earliest: Option<Instant> = None;
+        let mut unresponsive = vec![];
+        for (id, client) in self.clients.iter_mut().enumerate() {
+            let ping = match client {
+                Some(client) => &mut client.ping,
+                None => continue,
+            };
+            let was_unresponsive = ping.is_unresponsive();
+            if let Some(deadline) = ping.check_deadline(now) {
+                earliest = Some(earliest.map_or(deadline, |earliest| earliest.min(deadline)));
+            }
+            if !was_unresponsive && ping.is_unresponsive() {
+                unresponsive.push(id as u32);
+            }
+        }
+
+        (earliest, unresponsive)
+    }
+
+    /// Clients that did not answer a ping in time.
+    pub fn unresponsive(&self) -> impl Iterator<Item = (u32, &Client)> {
+        self.clients
+            .iter()
+            .enumerate()
+            .filter_map(|(id, client)| Some((id as u32, client.as_ref()?)))
+            .filter(|(_, client)| client.ping.is_unresponsive())
+    }
+
+    /// The client that owns a registry entry, with the id the client knows it by.
+    pub fn find_owner(&self, global_id: GlobalObjectId) -> Option<(u32, ObjectId)> {
+        self.clients
+            .iter()
+            .enumerate()
+            .find_map(|(client_id, client)| {
+                let (id, _) = client
+                    .as_ref()?
+                    .objects
+                    .iter()
+                    .find(|&(_, owned)| owned == global_id)?;
+                Some((client_id as u32, id))
+            })
+    }
+
     pub fn get_mut(&mut self, id: u32) -> Option<&mut Client> {
         self.clients.get_mut(id as usize).and_then(Option::as_mut)
     }
 
-    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Client> {
-        self.clients.iter_mut().filter_map(Option::as_mut)
+    /// Call `f` with the send buffer, id and version of every object clients bound to the
+    /// global `global_id`.
+    pub fn for_each_bound<F>(&mut self, global_id: GlobalObjectId, mut f: F)
+    where
+        F: FnMut(&mut MessageBuf<Write>, ObjectId, u32) -> Result<(), MessageError>,
+    {
+        for (client_id, client) in self.clients.iter_mut().enumerate() {
+            let client = match client {
+                Some(client) => client,
+                None => continue,
+            };
+            let bound: Vec<_> = client
+                .objects
+                .iter()
+                .filter(|&(_, id)| id == global_id)
+                .filter_map(|(id, _)| Some((id, client.objects.get_with_version(id)?.1)))
+                .collect();
+
+            for (id, version) in bound {
+                if let Err(e) = f(client.stream.send_buf_mut(), id, version) {
+                    log::warn!("Failed to send event to client {}: {}", client.info, e);
+                }
+                mark_dirty(&mut self.dirty, client_id as u32);
+            }
+        }
     }
 
     pub fn find_interface_in_clients<'a, F>(
@@ -77,12 +455,28 @@ impl Clients {
     where
         F: FnMut(&Interface) -> bool + 'a,
     {
-        self.iter_mut().filter_map(move |client| {
-            let res = client
-                .objects
-                .iter()
-                .find(|&(_, global_id)| registry.get(global_id).map_or(false, &mut filter));
-            res.map(|(id, _)| (client, id))
-        })
+        let dirty = &mut self.dirty;
+        self.clients
+            .iter_mut()
+            .enumerate()
+            .filter_map(move |(client_id, client)| {
+                let client = client.as_mut()?;
+                let res = client
+                    .objects
+                    .iter()
+                    .find(|&(_, global_id)| registry.get(global_id).is_some_and(&mut filter));
+                res.map(|(id, _)| {
+                    // Assume the caller is going to send an event to the object.
+                    mark_dirty(dirty, client_id as u32);
+                    (client, id)
+                })
+            })
+    }
+}
+
+#[inline]
+fn mark_dirty(dirty: &mut Vec<u32>, id: u32) {
+    if !dirty.contains(&id) {
+        dirty.push(id);
     }
 }
@@ -0,0 +1,281 @@
+use crate::{backend::Backend, gateway::Gateway};
+
+use nix::{
+    errno::Errno,
+    sys::{
+        epoll::*,
+        time::TimeSpec,
+        timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags},
+    },
+    unistd::close,
+};
+use slotmap::{new_key_type, Key, KeyData, SlotMap};
+
+use std::{
+    io,
+    os::unix::io::{AsRawFd, RawFd},
+    time::Duration,
+};
+
+new_key_type! {
+    /// Handle to an event source registered with the gateway.
+    pub struct SourceId;
+}
+
+/// Called with the id of the source and the readiness flags reported by epoll.
+pub type SourceCallback<B> = Box<dyn FnMut(&mut Gateway<B>, SourceId, EpollFlags)>;
+/// Called once when the event loop has dispatched all pending events.
+pub type IdleCallback<B> = Box<dyn FnOnce(&mut Gateway<B>)>;
+
+struct Source<B: Backend> {
+    fd: RawFd,
+    /// Owned timer if the source was created with `add_timer`.
+    timer: Option<TimerFd>,
+    /// Taken out while the callback is running.
+    callback: Option<SourceCallback<B>>,
+}
+
+pub(super) struct EventLoop<B: Backend> {
+    epoll_fd: RawFd,
+    sources: SlotMap<SourceId, Source<B>>,
+    idle: Vec<IdleCallback<B>>,
+}
+
+impl<B: Backend> Drop for EventLoop<B> {
+    fn drop(&mut self) {
+        let _ = close(self.epoll_fd);
+    }
+}
+
+impl<B: Backend> EventLoop<B> {
+    pub fn new() -> Self {
+        let epoll_fd = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC)
+            .expect("Failed to create epoll instance");
+
+        Self {
+            epoll_fd,
+            sources: SlotMap::with_key(),
+            idle: vec![],
+        }
+    }
+
+    /// Wait for events for at most `timeout`, or indefinitely if `None`, without blocking if
+    /// there are idle callbacks to run.
+    pub fn wait(&self, events: &mut [EpollEvent], timeout: Option<Duration>) -> nix::Result<usize> {
+        let timeout = match timeout {
+            _ if !self.idle.is_empty() => 0,
+            // Round up so a short timeout does not turn into a busy loop.
+            Some(timeout) => timeout
+                .as_nanos()
+                .div_ceil(1_000_000)
+                .try_into()
+                .unwrap_or(isize::MAX),
+            None => -1,
+        };
+        epoll_wait(self.epoll_fd, events, timeout)
+    }
+}
+
+impl<B: Backend> Gateway<B> {
+    /// Call `callback` whenever `fd` becomes ready for any of the events in `flags`.
+    ///
+    /// The fd must stay open until the source is removed with `remove_source`.
+    pub fn add_fd(
+        &mut self,
+        fd: RawFd,
+        flags: EpollFlags,
+        callback: SourceCallback<B>,
+    ) -> io::Result<SourceId> {
+        self.insert_source(fd, None, flags, callback)
+    }
+
+    /// Change the events an fd source is interested in.
+    pub fn modify_fd(&mut self, id: SourceId, flags: EpollFlags) -> io::Result<()> {
+        let fd = self
+            .event_loop
+            .sources
+            .get(id)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?
+            .fd;
+        let mut event = EpollEvent::new(flags, id.data().as_ffi());
+        epoll_ctl(
+            self.event_loop.epoll_fd,
+            EpollOp::EpollCtlMod,
+            fd,
+            Some(&mut event),
+        )?;
+
+        Ok(())
+    }
+
+    /// Create a disarmed timer that calls `callback` when it expires.
+    pub fn add_timer(&mut self, callback: SourceCallback<B>) -> io::Result<SourceId> {
+        let timer = TimerFd::new(
+            ClockId::CLOCK_MONOTONIC,
+            TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+        )?;
+        self.insert_source(
+            timer.as_raw_fd(),
+            Some(timer),
+            EpollFlags::EPOLLIN,
+            callback,
+        )
+    }
+
+    /// Let a timer expire after `delay`, and then every `interval` if given.
+    ///
+    /// Any previous expiration of the timer is replaced.
+    pub fn arm_timer(
+        &mut self,
+        id: SourceId,
+        delay: Duration,
+        interval: Option<Duration>,
+    ) -> io::Result<()> {
+        let timer = self.timer(id)?;
+        // A zero expiration would disarm the timer.
+        let delay = TimeSpec::from_duration(delay.max(Duration::from_nanos(1)));
+        let expiration = match interval {
+            Some(interval) => Expiration::IntervalDelayed(delay, TimeSpec::from_duration(interval)),
+            None => Expiration::OneShot(delay),
+        };
+        timer.set(expiration, TimerSetTimeFlags::empty())?;
+
+        Ok(())
+    }
+
+    /// Stop a timer, an expiration that has not been dispatched yet is dropped.
+    pub fn disarm_timer(&mut self, id: SourceId) -> io::Result<()> {
+        self.timer(id)?.unset()?;
+        Ok(())
+    }
+
+    /// Run `callback` once after the pending events of the current iteration are dispatched.
+    pub fn add_idle(&mut self, callback: IdleCallback<B>) {
+        self.event_loop.idle.push(callback);
+    }
+
+    /// Unregister a source, does nothing if it was already removed.
+    pub fn remove_source(&mut self, id: SourceId) {
+        if let Some(source) = self.event_loop.sources.remove(id) {
+            if let Err(e) = epoll_ctl(
+                self.event_loop.epoll_fd,
+                EpollOp::EpollCtlDel,
+                source.fd,
+                None,
+            ) {
+                log::debug!("Failed to remove event source from epoll: {}", e);
+            }
+        }
+    }
+
+    fn insert_source(
+        &mut self,
+        fd: RawFd,
+        timer: Option<TimerFd>,
+        flags: EpollFlags,
+        callback: SourceCallback<B>,
+    ) -> io::Result<SourceId> {
+        let id = self.event_loop.sources.insert(Source {
+            fd,
+            timer,
+            callback: Some(callback),
+        });
+        let mut event = EpollEvent::new(flags, id.data().as_ffi());
+        if let Err(e) = epoll_ctl(
+            self.event_loop.epoll_fd,
+            EpollOp::EpollCtlAdd,
+            fd,
+            Some(&mut event),
+        ) {
+            self.event_loop.sources.remove(id);
+            return Err(e.into());
+        }
+
+        Ok(id)
+    }
+
+    fn timer(&self, id: SourceId) -> io::Result<&TimerFd> {
+        self.event_loop
+            .sources
+            .get(id)
+            .and_then(|source| source.timer.as_ref())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    pub(super) fn dispatch_event(&mut self, event: &EpollEvent) {
+        let id = SourceId::from(KeyData::from_ffi(event.data()));
+        // The source may have been removed by a callback earlier in this iteration.
+        let source = match self.event_loop.sources.get_mut(id) {
+            Some(source) => source,
+            None => return,
+        };
+
+        if let Some(timer) = &source.timer {
+            match timer.wait() {
+                Ok(()) => (),
+                // The timer was disarmed or re-armed after the event was queued.
+                Err(Errno::EAGAIN) => return,
+                Err(e) => {
+                    log::error!("Failed to read timer: {}", e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(mut callback) = source.callback.take() {
+            callback(self, id, event.events());
+            if let Some(source) = self.event_loop.sources.get_mut(id) {
+                source.callback = Some(callback);
+            }
+        }
+    }
+
+    pub(super) fn dispatch_idle(&mut self) {
+        for callback in std::mem::take(&mut self.event_loop.idle) {
+            callback(self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        backend::Headless,
+        gateway::{
+            signal::ShutdownSignals,
+            socket::{ListeningSocket, SocketConfig},
+            Gateway, PingConfig,
+        },
+        xkb::Rmlvo,
+    };
+
+    use std::{cell::Cell, num::NonZeroU32, process, rc::Rc, time::Duration};
+
+    #[test]
+    fn idle_callbacks_run_once() {
+        let dir = std::env::temp_dir().join(format!("carbon-idle-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut gateway = Gateway::new(
+            Headless::new(vec![]),
+            ListeningSocket::new(SocketConfig::Name(dir.join("wayland"))),
+            PingConfig::default(),
+            NonZeroU32::new(60_000).unwrap(),
+            Rmlvo::default(),
+            ShutdownSignals::new(),
+        );
+
+        let count = Rc::new(Cell::new(0));
+        let counter = count.clone();
+        gateway.add_idle(Box::new(move |_| counter.set(counter.get() + 1)));
+        assert_eq!(count.get(), 0);
+
+        // Pending idle callbacks make the iteration return without waiting.
+        gateway.dispatch(None).unwrap();
+        assert_eq!(count.get(), 1);
+        gateway.dispatch(Some(Duration::ZERO)).unwrap();
+        assert_eq!(count.get(), 1);
+
+        drop(gateway);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::{
+    backend::Backend,
+    gateway::{timestamp, Gateway},
+    output::{Output, OutputId},
+    protocol::wl_shm,
+};
+
+use nix::sys::epoll::EpollFlags;
+
+use std::{io, num::NonZeroU32, time::Instant};
+
+impl<B: Backend> Gateway<B> {
+    /// Create the outputs of the backend. Their frame clocks are driven by the backend if it
+    /// reports refreshes, and otherwise by a timer at `fallback_refresh` mHz unless the
+    /// backend knows the refresh rate.
+    pub(super) fn add_outputs(&mut self, fallback_refresh: NonZeroU32) {
+        let backend_driven = match self.backend.frame_fd() {
+            Some(fd) => {
+                self.add_fd(
+                    fd,
+                    EpollFlags::EPOLLIN | EpollFlags::EPOLLET,
+                    Box::new(|gateway, _, _| gateway.backend_frames()),
+                )
+                .expect("Failed to add backend frame fd to epoll");
+                true
+            }
+            None => false,
+        };
+
+        for mode in self.backend.outputs() {
+            let output = Output::new(mode, fallback_refresh);
+            log::info!(
+                "Adding {}x{} output refreshing every {:?}",
+                mode.width,
+                mode.height,
+                output.refresh_interval
+            );
+            let id = self.outputs.insert(output);
+
+            if !backend_driven {
+                let timer = self
+                    .add_timer(Box::new(move |gateway, _, _| gateway.tick_frame_clock(id)))
+                    .expect("Failed to create frame timer");
+                self.outputs.get_mut(id).unwrap().timer = Some(timer);
+            }
+        }
+    }
+
+    /// Apply modes the backend changed, outputs whose mode changed are redrawn.
+    pub(super) fn update_output_modes(&mut self) {
+        for (idx, mode) in self.backend.outputs().into_iter().enumerate() {
+            let output = match self
+                .outputs
+                .by_index(idx)
+                .and_then(|id| self.outputs.get_mut(id))
+            {
+                Some(output) => output,
+                None => continue,
+            };
+            if output.mode != mode {
+                log::info!("Output {} changed to {}x{}", idx, mode.width, mode.height);
+                output.set_mode(mode);
+            }
+        }
+    }
+
+    /// Start the timers of outputs a frame was requested on, in phase with their last frame.
+    pub(super) fn start_frame_clocks(&mut self) {
+        let now = Instant::now();
+        let mut start = vec![];
+        for (_, output) in self.outputs.iter_mut() {
+            if !output.frame_requested || output.ticking {
+                continue;
+            }
+            if let Some(timer) = output.timer {
+                output.ticking = true;
+                start.push((
+                    timer,
+                    output.until_next_refresh(now),
+                    output.refresh_interval,
+                ));
+            }
+        }
+
+        for (timer, delay, interval) in start {
+            if let Err(e) = self.arm_timer(timer, delay, Some(interval)) {
+                log::error!("Failed to start frame clock: {}", e);
+            }
+        }
+    }
+
+    fn tick_frame_clock(&mut self, id: OutputId) {
+        let output = match self.outputs.get_mut(id) {
+            Some(output) => output,
+            None => return,
+        };
+
+        if output.frame_requested {
+            self.present_frame(id);
+        } else if let Some(timer) = output.timer {
+            // Nothing changed since the last frame, stop until a frame is requested.
+            output.ticking = false;
+            if let Err(e) = self.disarm_timer(timer) {
+                log::error!("Failed to stop frame clock: {}", e);
+            }
+        }
+    }
+
+    fn backend_frames(&mut self) {
+        let indices = match self.backend.drain_frames() {
+            Ok(indices) => indices,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) => {
+                log::error!("Backend failed to report frames: {}", e);
+                return;
+            }
+        };
+
+        for idx in indices {
+            let id = match self.outputs.by_index(idx) {
+                Some(id) => id,
+                None => continue,
+            };
+            if self
+                .outputs
+                .get_mut(id)
+                .is_some_and(|output| output.frame_requested)
+            {
+                self.present_frame(id);
+            }
+        }
+    }
+
+    /// Finish a frame on an output, letting the clients whose surfaces were presented know
+    /// that they can draw the next one.
+    fn present_frame(&mut self, id: OutputId) {
+        let (idx, output) = match (self.outputs.index(id), self.outputs.get_mut(id)) {
+            (Some(idx), Some(output)) => (idx, output),
+            _ => return,
+        };
+        output.frame_requested = false;
+        output.last_frame = Some(Instant::now());
+
+        let frame = self.renderer.render(id, output.mode, &mut self.registry);
+        if !frame.damage.is_empty() {
+            self.backend.present(idx, frame.framebuffer, &frame.damage);
+        }
+
+        self.clients
+            .send_frame_callbacks(&mut self.registry, &frame.presented, timestamp());
+
+        for buffer in frame.truncated {
+            if let Some((client, buffer)) = self.clients.find_owner(buffer) {
+                let error = wl_shm::Error::InvalidFd
+                    .into_message_error(buffer, "error accessing SHM buffer".to_owned());
+                self.kill_client(client, error);
+            }
+        }
+    }
+}
@@ -1,9 +1,9 @@
-use crate::gateway::registry::ObjectId;
+use crate::{gateway::registry::ObjectId, protocol::wl_display};
 
+use bytemuck::{cast_slice, cast_slice_mut};
 use nix::{
     errno::Errno,
     sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
-    unistd::close,
 };
 
 use std::{
@@ -14,6 +14,16 @@ use std::{
 };
 
 const MAX_FDS_OUT: usize = 28;
+/// Maximum number of fds that can be queued for sending.
+const MAX_FDS_QUEUED: usize = 1024;
+/// The message size is encoded in 16 bits and must be a multiple of 4.
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize & !3;
+
+const INITIAL_BUF_SIZE: usize = 4096;
+/// Any partial message fits in the receive buffer at this size.
+const MAX_RECEIVE_BUF_SIZE: usize = 64 * 1024;
+/// Clients that let this many bytes of events pile up are disconnected.
+const MAX_SEND_BUF_SIZE: usize = 1024 * 1024;
 
 #[derive(Debug, thiserror::Error)]
 pub enum MessageError {
@@ -27,28 +37,82 @@ pub enum MessageError {
     InvalidObject,
     #[error("bad request opcode")]
     InvalidOpcode,
+    #[error("protocol error on object {}: {message}", object_id.raw())]
+    Protocol {
+        object_id: ObjectId,
+        code: u32,
+        message: String,
+    },
     #[error("io error: {0}")]
     Io(#[from] io::Error),
 }
 
+impl MessageError {
+    /// Attribute an error that occurred while handling a request to `object_id`.
+    pub fn for_object(self, object_id: ObjectId) -> Self {
+        let (object_id, code, message) = match self {
+            Self::InvalidObject => (
+                ObjectId::display(),
+                wl_display::Error::InvalidObject,
+                format!("invalid object id in request to {}", object_id.raw()),
+            ),
+            Self::InvalidOpcode => (
+                object_id,
+                wl_display::Error::InvalidMethod,
+                "invalid request opcode".to_owned(),
+            ),
+            Self::BadFormat(message) | Self::BadRequest(message) => {
+                (object_id, wl_display::Error::InvalidMethod, message)
+            }
+            e => return e,
+        };
+
+        Self::Protocol {
+            object_id,
+            code: code.into(),
+            message,
+        }
+    }
+
+    /// Returns the object, code and message that should be sent to the client in a
+    /// `wl_display.error` event, or `None` if the error cannot be reported.
+    fn to_protocol_error(&self) -> Option<(ObjectId, u32, String)> {
+        let (code, message) = match self {
+            Self::Protocol {
+                object_id,
+                code,
+                message,
+            } => return Some((*object_id, *code, message.clone())),
+            Self::OutOfMemory => (wl_display::Error::NoMemory, self.to_string()),
+            Self::InvalidObject => (wl_display::Error::InvalidObject, self.to_string()),
+            Self::InvalidOpcode | Self::BadFormat(_) | Self::BadRequest(_) => {
+                (wl_display::Error::InvalidMethod, self.to_string())
+            }
+            Self::Io(_) => return None,
+        };
+
+        Some((ObjectId::display(), code.into(), message))
+    }
+}
+
 pub struct MessageStream {
-    stream_fd: RawFd,
+    stream_fd: OwnedFd,
     receive_buf: MessageBuf<Read>,
     send_buf: MessageBuf<Write>,
 }
 
-impl Drop for MessageStream {
-    fn drop(&mut self) {
-        let _ = close(self.stream_fd);
+impl AsRawFd for MessageStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream_fd.as_raw_fd()
     }
 }
 
 impl MessageStream {
-    pub fn new(stream_fd: RawFd) -> Self {
+    pub fn new(stream_fd: OwnedFd, client_id: u32) -> Self {
         Self {
             stream_fd,
-            receive_buf: MessageBuf::new(),
-            send_buf: MessageBuf::new(),
+            receive_buf: MessageBuf::new(MAX_RECEIVE_BUF_SIZE, client_id),
+            send_buf: MessageBuf::new(MAX_SEND_BUF_SIZE, client_id),
         }
     }
 
@@ -57,6 +121,19 @@ impl MessageStream {
         &mut self.send_buf
     }
 
+    /// Whether there are events in the send buffer that have not been flushed yet.
+    #[inline]
+    pub fn has_pending(&self) -> bool {
+        self.send_buf.ring.len() != 0
+    }
+
+    /// Whether an event could not be written because the send buffer reached its maximum size,
+    /// in which case the client must be disconnected.
+    #[inline]
+    pub fn is_overflowed(&self) -> bool {
+        self.send_buf.overflowed
+    }
+
     pub fn receive<D>(&mut self, mut dispatcher: D) -> Result<usize, MessageError>
     where
         D: FnMut(
@@ -70,13 +147,15 @@ impl MessageStream {
         let mut count = 0;
         let mut cmsg_buf = nix::cmsg_space!([RawFd; MAX_FDS_OUT]);
         loop {
-            if self.receive_buf.is_full() {
+            let ring = &mut self.receive_buf.ring;
+            if ring.is_full() && !ring.reserve(ring.capacity()) {
                 return Err(MessageError::OutOfMemory);
             }
 
+            let (a, b) = ring.free_slices_mut();
             match recvmsg::<()>(
-                self.stream_fd,
-                &mut [self.receive_buf.io_slice_mut()],
+                self.stream_fd.as_raw_fd(),
+                &mut [IoSliceMut::new(a), IoSliceMut::new(b)],
                 Some(&mut cmsg_buf),
                 MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL,
             ) {
@@ -92,10 +171,14 @@ impl MessageStream {
                         return Ok(0);
                     }
 
-                    self.receive_buf.grow(msg.bytes);
+                    self.receive_buf.ring.commit(msg.bytes);
                     for cmsg in msg.cmsgs() {
                         if let ControlMessageOwned::ScmRights(fds) = cmsg {
-                            self.receive_buf.fds.extend(fds.into_iter());
+                            // Safety: the fds were just received, nothing else owns them.
+                            let fds = fds
+                                .into_iter()
+                                .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+                            self.receive_buf.fds.extend(fds);
                         }
                     }
                 }
@@ -117,25 +200,51 @@ impl MessageStream {
 
     pub fn flush(&mut self) -> io::Result<usize> {
         let mut total_count = 0;
-        let res = loop {
-            if total_count == self.send_buf.len() {
+        let send_buf = &mut self.send_buf;
+        loop {
+            if send_buf.ring.len() == 0 {
                 break Ok(total_count);
             }
 
-            self.send_buf.fds.make_contiguous();
-            let control_messages = [ControlMessage::ScmRights(self.send_buf.fds.as_slices().0)];
-            let control_message_count = (!self.send_buf.fds.is_empty()) as usize;
+            let mut fds = [0; MAX_FDS_OUT];
+            let fd_count = send_buf.fds.len().min(MAX_FDS_OUT);
+            for (dst, (_, fd)) in fds.iter_mut().zip(&send_buf.fds) {
+                *dst = fd.as_raw_fd();
+            }
+            let control_messages = [ControlMessage::ScmRights(&fds[..fd_count])];
+            let control_message_count = (fd_count != 0) as usize;
+
+            // Fds that don't fit in this batch must arrive before the last byte of the
+            // message they belong to, so stop right before it.
+            let max_bytes = match send_buf.fds.get(fd_count) {
+                Some(&(end, _)) => (end - send_buf.consumed - 1) as usize,
+                None => send_buf.ring.len(),
+            };
+            let (a, b) = send_buf.ring.slices();
+            let a = &a[..a.len().min(max_bytes)];
+            let b = &b[..b.len().min(max_bytes - a.len())];
 
             match sendmsg::<()>(
-                self.stream_fd,
-                &[self.send_buf.io_slice(total_count)],
+                self.stream_fd.as_raw_fd(),
+                &[IoSlice::new(a), IoSlice::new(b)],
                 &control_messages[..control_message_count],
                 MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL,
                 None,
             ) {
+                // Fds sent without any data are discarded by the kernel, so they are kept for
+                // the next attempt.
+                Ok(0) => {
+                    break if total_count == 0 {
+                        Err(io::Error::from(io::ErrorKind::WouldBlock))
+                    } else {
+                        Ok(total_count)
+                    };
+                }
                 Ok(count) => {
                     total_count += count;
-                    self.send_buf.fds.clear();
+                    send_buf.consume(count);
+                    // The fds have been duplicated into the client, close our copies.
+                    send_buf.fds.drain(..fd_count);
                 }
                 Err(Errno::EWOULDBLOCK) => {
                     break if total_count == 0 {
@@ -148,80 +257,247 @@ impl MessageStream {
                 }
                 Err(e) => break Err(e.into()),
             }
-        };
-
-        // if total_count != 0 {
-        //     log::debug!("Flushed buffer:");
-        //     for chunk in &self.send_buf.buf[..total_count / 4] {
-        //         let bytes = chunk.to_ne_bytes();
-        //         log::debug!(
-        //             "{:08x} {}",
-        //             chunk.swap_bytes(),
-        //             String::from_utf8_lossy(&bytes)
-        //         );
-        //     }
-        // }
+        }
+    }
 
-        self.send_buf.shrink(total_count);
+    /// Notify the client of a fatal error with a `wl_display.error` event and try to flush it.
+    ///
+    /// The client should be disconnected afterwards regardless of whether this succeeded.
+    pub fn post_error(&mut self, error: &MessageError) {
+        let (object_id, code, message) = match error.to_protocol_error() {
+            Some(v) => v,
+            None => return,
+        };
 
-        res
+        let res = wl_display::emit_error(
+            &mut self.send_buf,
+            ObjectId::display(),
+            object_id,
+            code,
+            &message,
+        )
+        .map_err(|e| e.to_string())
+        .and_then(|_| self.flush().map_err(|e| e.to_string()));
+        if let Err(e) = res {
+            log::debug!("Failed to send error to client: {}", e);
+        }
     }
 }
 
-pub struct FdSource<'a>(&'a mut VecDeque<RawFd>);
+pub struct FdSource<'a>(&'a mut VecDeque<OwnedFd>);
 
 impl<'a> FdSource<'a> {
-    pub fn pop(&mut self) -> Option<RawFd> {
+    /// Take ownership of the next received fd, it is closed when dropped.
+    pub fn pop(&mut self) -> Option<OwnedFd> {
         self.0.pop_front()
     }
 }
 
-const BUF_SIZE: usize = 4096;
-
-struct Read;
-pub struct Write;
-pub struct MessageBuf<I> {
-    buf: [u32; BUF_SIZE / 4],
+/// A growable ring buffer of bytes, stored as words so whole messages can be accessed as `[u32]`.
+///
+/// The capacity is always a multiple of 4 bytes.
+struct RingBuf {
+    words: Vec<u32>,
+    /// Byte offset of the first stored byte.
+    head: usize,
+    /// Number of stored bytes.
     len: usize,
-    fds: VecDeque<RawFd>,
-    _phantom: PhantomData<I>,
+    max_size: usize,
 }
 
-impl<I> MessageBuf<I> {
-    #[inline]
-    fn new() -> Self {
+impl RingBuf {
+    fn new(max_size: usize) -> Self {
         Self {
-            buf: [0; BUF_SIZE / 4],
+            words: vec![0; INITIAL_BUF_SIZE / 4],
+            head: 0,
             len: 0,
-            fds: VecDeque::new(),
-            _phantom: PhantomData,
+            max_size,
         }
     }
 
     #[inline]
-    fn is_full(&self) -> bool {
-        self.len == BUF_SIZE
+    fn capacity(&self) -> usize {
+        self.words.len() * 4
     }
 
     #[inline]
     fn len(&self) -> usize {
         self.len
     }
-}
 
-impl MessageBuf<Read> {
     #[inline]
-    fn io_slice_mut(&mut self) -> IoSliceMut {
-        let a = &mut bytemuck::cast_slice_mut(&mut self.buf)[self.len..];
-        IoSliceMut::new(a)
+    fn is_full(&self) -> bool {
+        self.len == self.capacity()
+    }
+
+    /// The stored bytes, the second slice is non-empty if they wrap around.
+    fn slices(&self) -> (&[u8], &[u8]) {
+        let bytes: &[u8] = cast_slice(&self.words);
+        let end = self.head + self.len;
+        if end <= bytes.len() {
+            (&bytes[self.head..end], &[])
+        } else {
+            let (wrapped, rest) = bytes.split_at(end - bytes.len());
+            (&rest[self.head - wrapped.len()..], wrapped)
+        }
     }
 
-    /// `count` must be smaller than or equal to the length of the current `io_slice_mut`.
+    /// The unused space after the stored bytes, the second slice is non-empty if it wraps around.
+    fn free_slices_mut(&mut self) -> (&mut [u8], &mut [u8]) {
+        let capacity = self.capacity();
+        let head = self.head;
+        let end = head + self.len;
+        let bytes: &mut [u8] = cast_slice_mut(&mut self.words);
+        if end < capacity {
+            let (before, after) = bytes.split_at_mut(end);
+            (after, &mut before[..head])
+        } else {
+            (&mut bytes[end - capacity..head], &mut [])
+        }
+    }
+
+    /// Mark `count` bytes of the free space as stored.
     #[inline]
-    fn grow(&mut self, count: usize) {
+    fn commit(&mut self, count: usize) {
+        debug_assert!(self.len + count <= self.capacity());
         self.len += count;
     }
 
+    /// Remove `count` bytes from the front.
+    #[inline]
+    fn consume(&mut self, count: usize) {
+        debug_assert!(count <= self.len);
+        self.len -= count;
+        self.head = if self.len == 0 {
+            0
+        } else {
+            (self.head + count) % self.capacity()
+        };
+    }
+
+    /// Rotate the buffer so the stored bytes start in the first word.
+    fn make_contiguous(&mut self) {
+        self.words.rotate_left(self.head / 4);
+        self.head %= 4;
+    }
+
+    /// Make sure at least `additional` bytes of free space are available, growing the buffer if
+    /// needed. Returns `false` if that would exceed the maximum size.
+    fn reserve(&mut self, additional: usize) -> bool {
+        let required = self.len + additional;
+        if required <= self.capacity() {
+            return true;
+        } else if required > self.max_size {
+            return false;
+        }
+
+        let mut capacity = self.capacity();
+        while capacity < required {
+            capacity *= 2;
+        }
+        self.make_contiguous();
+        self.words.resize(capacity.min(self.max_size) / 4, 0);
+
+        true
+    }
+
+    /// Append `count` words and return them as one slice.
+    ///
+    /// The end of the stored bytes must be word aligned.
+    fn allocate_words(&mut self, count: usize) -> Option<&mut [u32]> {
+        let size = count * 4;
+        if !self.reserve(size) {
+            return None;
+        }
+
+        let capacity = self.capacity();
+        let mut end = self.head + self.len;
+        let contiguous_free = if end < capacity {
+            capacity - end
+        } else {
+            end -= capacity;
+            self.head - end
+        };
+        if contiguous_free < size {
+            self.make_contiguous();
+            end = self.head + self.len;
+        }
+        debug_assert_eq!(end % 4, 0);
+
+        self.len += size;
+        Some(&mut self.words[end / 4..end / 4 + count])
+    }
+
+    /// Read the word at `idx` words from the front, the front must be word aligned.
+    #[inline]
+    fn word(&self, idx: usize) -> u32 {
+        self.words[(self.head / 4 + idx) % self.words.len()]
+    }
+
+    /// Get the first `size` bytes as one slice, the front must be word aligned.
+    fn front_words(&mut self, size: usize) -> &[u32] {
+        if self.head + size > self.capacity() {
+            self.make_contiguous();
+        }
+
+        &self.words[self.head / 4..(self.head + size) / 4]
+    }
+}
+
+pub trait BufDirection {
+    type QueuedFd;
+}
+
+struct Read;
+impl BufDirection for Read {
+    type QueuedFd = OwnedFd;
+}
+
+pub struct Write;
+impl BufDirection for Write {
+    /// Outgoing fds are queued along with the stream position of the end of the message they
+    /// belong to.
+    type QueuedFd = (u64, OwnedFd);
+}
+
+pub struct MessageBuf<I: BufDirection> {
+    ring: RingBuf,
+    fds: VecDeque<I::QueuedFd>,
+    /// Total number of bytes that have been removed from the front of the buffer.
+    consumed: u64,
+    /// Set when a write failed because the buffer is at its maximum size.
+    overflowed: bool,
+    /// Id of the client the buffer belongs to, used to attribute protocol traces.
+    client_id: u32,
+    _phantom: PhantomData<I>,
+}
+
+impl<I: BufDirection> MessageBuf<I> {
+    #[inline]
+    fn new(max_size: usize, client_id: u32) -> Self {
+        Self {
+            ring: RingBuf::new(max_size),
+            fds: VecDeque::new(),
+            consumed: 0,
+            overflowed: false,
+            client_id,
+            _phantom: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn client_id(&self) -> u32 {
+        self.client_id
+    }
+
+    #[inline]
+    fn consume(&mut self, count: usize) {
+        self.ring.consume(count);
+        self.consumed += count as u64;
+    }
+}
+
+impl MessageBuf<Read> {
     fn deserialize_messages<D>(
         &mut self,
         dispatcher: &mut D,
@@ -236,20 +512,19 @@ impl MessageBuf<Read> {
             &mut MessageBuf<Write>,
         ) -> Result<(), MessageError>,
     {
-        let mut idx = 0;
         let mut msg_count = 0;
 
-        let res = loop {
+        loop {
             // While we have enough bytes for a message header
-            if self.len < 8 {
+            if self.ring.len() < 8 {
                 break Ok(msg_count);
             }
 
-            let object_id = match ObjectId::new(self.buf[idx]) {
+            let object_id = match ObjectId::new(self.ring.word(0)) {
                 Some(id) => id,
                 None => break Err(MessageError::InvalidObject),
             };
-            let header = self.buf[idx + 1];
+            let header = self.ring.word(1);
             let msg_size = (header >> 16) as usize;
             let opcode = header as u16;
 
@@ -259,89 +534,249 @@ impl MessageBuf<Read> {
                 ));
             }
 
-            if self.len >= msg_size {
-                let msg_end = idx + msg_size / 4;
-                let payload = &self.buf[idx + 2..msg_end];
-
-                // log::debug!("object : {:?}", object_id);
-                // log::debug!("size   : {:?}", msg_size);
-                // log::debug!("opcode : {:?}", opcode);
-                // for chunk in payload {
-                //     let bytes = chunk.to_ne_bytes();
-                //     log::debug!(
-                //         "payload: {:08x} {}",
-                //         chunk.swap_bytes(),
-                //         String::from_utf8_lossy(&bytes)
-                //     );
-                // }
-                // log::debug!("------------------------------");
-
-                if let Err(e) = dispatcher(
+            if self.ring.len() >= msg_size {
+                let payload = &self.ring.front_words(msg_size)[2..];
+
+                dispatcher(
                     object_id,
                     opcode,
                     payload,
                     FdSource(&mut self.fds),
                     send_buf,
-                ) {
-                    break Err(e);
-                }
+                )?;
                 msg_count += 1;
 
-                self.len -= msg_size;
-                idx = msg_end;
+                self.consume(msg_size);
             } else {
                 // We haven't received the full message yet
                 break Ok(msg_count);
             }
-        };
-
-        if self.len > 0 && idx != 0 {
-            // Copy remaining partial message to start of buffer.
-            // This is not very likely to happen, and most messages are also quite small.
-            //
-            // It's also possible a request failed in which case we should clean up the
-            // buffer by removing the requests we processed.
-            self.buf.copy_within(idx..idx + (self.len + 3) / 4, 0);
         }
-
-        res
     }
 }
 
 impl MessageBuf<Write> {
-    #[inline]
-    fn io_slice(&self, offset: usize) -> IoSlice {
-        let a = &bytemuck::cast_slice(&self.buf)[offset..self.len];
-        IoSlice::new(a)
-    }
-
     #[inline]
     pub fn allocate(&mut self, chunk_count: usize) -> Result<&mut [u32], MessageError> {
-        let new_len = self.len + chunk_count * 4;
-        (new_len <= BUF_SIZE)
-            .then(|| {
-                let res = &mut self.buf[self.len / 4..new_len / 4];
-                self.len = new_len;
-                res
-            })
-            .ok_or(MessageError::OutOfMemory)
+        if chunk_count * 4 > MAX_MESSAGE_SIZE {
+            return Err(MessageError::OutOfMemory);
+        }
+
+        match self.ring.allocate_words(chunk_count) {
+            Some(words) => Ok(words),
+            None => {
+                self.overflowed = true;
+                Err(MessageError::OutOfMemory)
+            }
+        }
     }
 
+    /// Queue `fd` to be sent along with the message that was last allocated.
+    ///
+    /// The fd is closed once it has been sent or the client is dropped.
     #[inline]
-    pub fn push_fd(&mut self, fd: RawFd) -> Result<(), MessageError> {
-        if self.fds.len() < MAX_FDS_OUT {
-            self.fds.push_back(fd);
+    pub fn push_fd(&mut self, fd: OwnedFd) -> Result<(), MessageError> {
+        let end = self.consumed + self.ring.len() as u64;
+        // All fds of a message are sent in one batch, so at least one byte of the message can
+        // be sent along with them.
+        let message_fds = self
+            .fds
+            .iter()
+            .rev()
+            .take_while(|&&(e, _)| e == end)
+            .count();
+        if self.fds.len() < MAX_FDS_QUEUED && message_fds < MAX_FDS_OUT {
+            self.fds.push_back((end, fd));
             Ok(())
         } else {
+            // The message has already been written, so the stream is unusable without the fd.
+            self.overflowed = true;
             Err(MessageError::OutOfMemory)
         }
     }
+}
 
-    fn shrink(&mut self, count: usize) {
-        if count < self.len {
-            let bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut self.buf);
-            bytes.copy_within(count..self.len, 0);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
+    use std::fs::File;
+
+    /// Store `count` bytes counting up from `start` in the free space of the ring.
+    fn push_bytes(ring: &mut RingBuf, start: u8, count: usize) {
+        let (a, b) = ring.free_slices_mut();
+        for (i, byte) in a.iter_mut().chain(b.iter_mut()).take(count).enumerate() {
+            *byte = start.wrapping_add(i as u8);
         }
-        self.len -= count;
+        ring.commit(count);
+    }
+
+    fn counting(start: u8, count: usize) -> Vec<u8> {
+        (0..count).map(|i| start.wrapping_add(i as u8)).collect()
+    }
+
+    fn stored(ring: &RingBuf) -> Vec<u8> {
+        let (a, b) = ring.slices();
+        [a, b].concat()
+    }
+
+    /// A ring whose 400 stored bytes wrap around the end of the buffer.
+    fn wrapped_ring() -> (RingBuf, Vec<u8>) {
+        let mut ring = RingBuf::new(INITIAL_BUF_SIZE * 4);
+        push_bytes(&mut ring, 0, INITIAL_BUF_SIZE - 100);
+        ring.consume(INITIAL_BUF_SIZE - 200);
+        push_bytes(&mut ring, 50, 300);
+
+        let mut expected = counting((INITIAL_BUF_SIZE - 200) as u8, 100);
+        expected.extend(counting(50, 300));
+        (ring, expected)
+    }
+
+    fn null_fd() -> OwnedFd {
+        File::open("/dev/null").unwrap().into()
+    }
+
+    #[test]
+    fn ring_wraps_around() {
+        let (mut ring, expected) = wrapped_ring();
+
+        let (a, b) = ring.slices();
+        assert_eq!((a.len(), b.len()), (200, 200));
+        assert_eq!(stored(&ring), expected);
+        for i in 0..expected.len() / 4 {
+            let bytes = expected[i * 4..i * 4 + 4].try_into().unwrap();
+            assert_eq!(ring.word(i), u32::from_ne_bytes(bytes));
+        }
+
+        let words = ring.front_words(expected.len()).to_vec();
+        assert_eq!(cast_slice::<u32, u8>(&words), &expected[..]);
+    }
+
+    #[test]
+    fn free_space_wraps_around() {
+        let (mut ring, _) = wrapped_ring();
+        let free = ring.capacity() - ring.len();
+
+        let (a, b) = ring.free_slices_mut();
+        assert_eq!(a.len() + b.len(), free);
+        push_bytes(&mut ring, 0, free);
+        assert!(ring.is_full());
+        assert_eq!(ring.free_slices_mut().0.len(), 0);
+    }
+
+    #[test]
+    fn make_contiguous_keeps_contents() {
+        let (mut ring, mut expected) = wrapped_ring();
+        // The front does not have to be word aligned.
+        ring.consume(3);
+        expected.drain(..3);
+
+        ring.make_contiguous();
+        assert!(ring.head < 4);
+        assert!(ring.slices().1.is_empty());
+        assert_eq!(stored(&ring), expected);
+    }
+
+    #[test]
+    fn reserve_grows_up_to_max_size() {
+        let (mut ring, expected) = wrapped_ring();
+        let max_size = INITIAL_BUF_SIZE * 4;
+
+        assert!(ring.reserve(INITIAL_BUF_SIZE));
+        assert_eq!(ring.capacity(), INITIAL_BUF_SIZE * 2);
+        assert_eq!(stored(&ring), expected);
+
+        assert!(ring.reserve(max_size - ring.len()));
+        assert_eq!(ring.capacity(), max_size);
+        assert!(!ring.reserve(max_size - ring.len() + 1));
+        assert_eq!(ring.capacity(), max_size);
+        assert_eq!(stored(&ring), expected);
+    }
+
+    #[test]
+    fn send_buf_overflows_at_max_size() {
+        let mut buf = MessageBuf::<Write>::new(INITIAL_BUF_SIZE * 2, 0);
+        for _ in 0..INITIAL_BUF_SIZE * 2 / 64 {
+            buf.allocate(16).unwrap();
+        }
+        assert!(!buf.overflowed);
+
+        assert!(matches!(buf.allocate(1), Err(MessageError::OutOfMemory)));
+        assert!(buf.overflowed);
+    }
+
+    #[test]
+    fn fds_of_a_message_fit_in_one_batch() {
+        let mut buf = MessageBuf::<Write>::new(INITIAL_BUF_SIZE, 0);
+        buf.allocate(2).unwrap();
+        for _ in 0..MAX_FDS_OUT {
+            buf.push_fd(null_fd()).unwrap();
+        }
+
+        assert!(buf.push_fd(null_fd()).is_err());
+        assert!(buf.overflowed);
+    }
+
+    /// Read everything queued on a socket, returning the bytes and fds of every read.
+    fn receive_all(fd: &OwnedFd) -> Vec<(usize, usize)> {
+        let mut reads = vec![];
+        let mut data = [0; 4096];
+        let mut cmsg_buf = nix::cmsg_space!([RawFd; MAX_FDS_OUT]);
+        loop {
+            let msg = match recvmsg::<()>(
+                fd.as_raw_fd(),
+                &mut [IoSliceMut::new(&mut data)],
+                Some(&mut cmsg_buf),
+                MsgFlags::MSG_DONTWAIT,
+            ) {
+                Ok(msg) if msg.bytes > 0 => msg,
+                _ => return reads,
+            };
+
+            let mut fd_count = 0;
+            for cmsg in msg.cmsgs() {
+                if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                    fd_count += fds.len();
+                    // Safety: the fds were just received, nothing else owns them.
+                    fds.into_iter()
+                        .for_each(|fd| drop(unsafe { OwnedFd::from_raw_fd(fd) }));
+                }
+            }
+            reads.push((msg.bytes, fd_count));
+        }
+    }
+
+    #[test]
+    fn fds_arrive_with_their_message() {
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )
+        .unwrap();
+        // Safety: the fds were just created, nothing else owns them.
+        let (a, peer) = unsafe { (OwnedFd::from_raw_fd(a), OwnedFd::from_raw_fd(b)) };
+        let mut stream = MessageStream::new(a, 0);
+
+        // More fds than fit in one batch, each on a message of 8 bytes.
+        let message_count = MAX_FDS_OUT * 2 + 3;
+        for i in 0..message_count {
+            let words = stream.send_buf_mut().allocate(2).unwrap();
+            words[0] = i as u32;
+            words[1] = 8 << 16;
+            stream.send_buf_mut().push_fd(null_fd()).unwrap();
+        }
+        assert_eq!(stream.flush().unwrap(), message_count * 8);
+        assert!(!stream.has_pending());
+
+        let (mut bytes, mut fds) = (0, 0);
+        for (read_bytes, read_fds) in receive_all(&peer) {
+            bytes += read_bytes;
+            fds += read_fds;
+            // The fd of a message must arrive before its last byte.
+            assert!(fds >= bytes / 8, "fd arrived after its message");
+        }
+        assert_eq!((bytes, fds), (message_count * 8, message_count));
     }
 }
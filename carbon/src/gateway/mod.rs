@@ -1,337 +1,488 @@
 use crate::{
     backend::Backend,
     gateway::{
-        client::{Client, Clients},
+        client::{Client, ClientInfo, Clients},
+        event_loop::{EventLoop, SourceId},
         message::{FdSource, MessageError, MessageStream},
-        registry::ObjectRegistry,
+        registry::{ObjectId, ObjectRegistry},
+        serial::Serials,
+        signal::ShutdownSignals,
+        socket::ListeningSocket,
     },
     input::{InputSink, InputState},
-    protocol::DispatchState,
+    output::Outputs,
+    protocol::{wl_display, DispatchState},
+    render::Renderer,
+    xkb::{Rmlvo, XkbError},
 };
 
 use nix::{
     errno::Errno,
-    fcntl::{flock, FlockArg},
     sys::{epoll::*, socket::*},
-    unistd::close,
+    time::{clock_gettime, ClockId},
 };
-use num_enum::{IntoPrimitive, TryFromPrimitive, TryFromPrimitiveError};
 
-use std::{env, fs, io, os::unix::prelude::*, path::PathBuf};
+use std::{
+    io,
+    num::NonZeroU32,
+    os::unix::prelude::*,
+    process::ExitCode,
+    time::{Duration, Instant},
+};
 
 pub mod client;
+pub mod event_loop;
+mod frame;
 pub mod message;
 pub mod registry;
+pub mod serial;
+pub mod signal;
+pub mod socket;
+
+/// How clients are checked for liveness.
+#[derive(Debug, Clone, Copy)]
+pub struct PingConfig {
+    /// Time between pings to a client.
+    pub interval: Duration,
+    /// Time after which a client that has not answered a ping is considered unresponsive.
+    pub timeout: Duration,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
 
 pub struct Gateway<B: Backend> {
-    _lock_file: fs::File,
-    listener_fd: RawFd,
-    epoll_fd: RawFd,
+    event_loop: EventLoop<B>,
+    listener: ListeningSocket,
+    signals: ShutdownSignals,
     clients: Clients,
     registry: ObjectRegistry,
+    serials: Serials,
     input_state: InputState,
+    outputs: Outputs,
+    renderer: Renderer,
     backend: B,
-}
-
-impl<B: Backend> Drop for Gateway<B> {
-    fn drop(&mut self) {
-        let _ = close(self.listener_fd);
-        let _ = close(self.epoll_fd);
-    }
+    ping_config: PingConfig,
+    /// Timer that expires at the earliest deadline of all pending pings.
+    ping_timeout_timer: SourceId,
+    /// Set when the event loop should stop, with the status `run` should return.
+    exit_status: Option<ExitCode>,
 }
 
 impl<B: Backend> Gateway<B> {
-    pub fn new(backend: B) -> Self {
-        let mut path: PathBuf = env::var("XDG_RUNTIME_DIR")
-            .ok()
-            .filter(|dir| dir.starts_with('/'))
-            .expect("XDG_RUNTIME_DIR not set or invalid")
-            .into();
-
-        for n in 0..32 {
-            if n != 0 {
-                path.pop();
-            }
-            path.push(format!("wayland-{}.lock", n));
-
-            let lock_file = match fs::OpenOptions::new()
-                .create(true)
-                .read(true)
-                .write(true)
-                .mode(0o660)
-                .open(&path)
-            {
-                Ok(f) => f,
-                Err(e) => {
-                    log::warn!(
-                        "Failed to open socket lock file at {}: {}",
-                        path.to_string_lossy(),
-                        e
-                    );
-                    continue;
-                }
-            };
-
-            if let Err(e) = flock(lock_file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
-                log::warn!(
-                    "Failed to acquire socket lock at {}: {}",
-                    path.to_string_lossy(),
-                    e.desc()
-                );
-                continue;
-            };
-
-            path.set_extension("");
-            if let Some(e) = fs::remove_file(&path)
-                .err()
-                .filter(|e| e.kind() != io::ErrorKind::NotFound)
-            {
-                log::warn!(
-                    "Failed to remove existing socket at {}: {}",
-                    path.to_string_lossy(),
-                    e
-                );
-                continue;
-            }
-
-            let sock_addr = UnixAddr::new(&path).unwrap();
-            let listener_fd = socket(
-                AddressFamily::Unix,
-                SockType::Stream,
-                SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
-                None,
-            )
-            .expect("Failed to create socket");
-            bind(listener_fd, &sock_addr)
-                .and_then(|_| listen(listener_fd, 256))
-                .map_err(|e| {
-                    let _ = close(listener_fd);
-                    e
-                })
-                .expect("Failed to bind to socket");
-
-            log::info!("Listening at {}", path.to_string_lossy());
-
-            let epoll_fd = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC)
-                .expect("Failed to create epoll instance");
-            let mut new_connection_event = EpollEvent::new(
-                EpollFlags::EPOLLIN | EpollFlags::EPOLLET,
-                EpollToken {
-                    kind: EpollTokenKind::NewConnection,
-                    id: 0,
-                }
-                .into(),
-            );
-            epoll_ctl(
-                epoll_fd,
-                EpollOp::EpollCtlAdd,
-                listener_fd,
-                Some(&mut new_connection_event),
+    pub fn new(
+        backend: B,
+        listener: ListeningSocket,
+        ping_config: PingConfig,
+        fallback_refresh: NonZeroU32,
+        keymap_names: Rmlvo,
+        signals: ShutdownSignals,
+    ) -> Self {
+        let mut gateway = Self {
+            event_loop: EventLoop::new(),
+            listener,
+            signals,
+            clients: Clients::new(),
+            registry: ObjectRegistry::new(),
+            serials: Serials::new(),
+            backend,
+            input_state: InputState::new(keymap_names),
+            outputs: Outputs::new(),
+            renderer: Renderer::new(),
+            ping_config,
+            ping_timeout_timer: SourceId::default(),
+            exit_status: None,
+        };
+
+        let edge_readable = EpollFlags::EPOLLIN | EpollFlags::EPOLLET;
+        gateway
+            .add_fd(
+                gateway.listener.as_raw_fd(),
+                edge_readable,
+                Box::new(|gateway, _, _| gateway.accept_clients()),
             )
             .expect("Failed to add socket fd to epoll");
-            let mut new_input_event = EpollEvent::new(
-                EpollFlags::EPOLLIN | EpollFlags::EPOLLET,
-                EpollToken {
-                    kind: EpollTokenKind::NewInput,
-                    id: 0,
-                }
-                .into(),
-            );
-            epoll_ctl(
-                epoll_fd,
-                EpollOp::EpollCtlAdd,
-                backend.input_fd(),
-                Some(&mut new_input_event),
+        gateway
+            .add_fd(
+                gateway.backend.input_fd(),
+                edge_readable,
+                Box::new(|gateway, _, _| gateway.drain_input()),
             )
             .expect("Failed to add backend input fd to epoll");
+        gateway
+            .add_fd(
+                gateway.signals.as_raw_fd(),
+                edge_readable,
+                Box::new(|gateway, _, _| gateway.read_signals()),
+            )
+            .expect("Failed to add signalfd to epoll");
+
+        let ping_timer = gateway
+            .add_timer(Box::new(|gateway, _, _| gateway.ping_clients()))
+            .expect("Failed to create ping timer");
+        gateway
+            .arm_timer(ping_timer, ping_config.interval, Some(ping_config.interval))
+            .expect("Failed to arm ping timer");
+        gateway.ping_timeout_timer = gateway
+            .add_timer(Box::new(|gateway, _, _| gateway.check_ping_deadlines()))
+            .expect("Failed to create ping timeout timer");
+        gateway.add_outputs(fallback_refresh);
+
+        gateway
+    }
 
-            return Self {
-                _lock_file: lock_file,
-                listener_fd,
-                epoll_fd,
-                clients: Clients::new(),
-                registry: ObjectRegistry::new(),
-                backend,
-                input_state: InputState::new(),
-            };
-        }
+    /// Clients that did not answer a ping in time, and have not answered it since.
+    pub fn unresponsive_clients(&self) -> impl Iterator<Item = (u32, &ClientInfo)> {
+        self.clients
+            .unresponsive()
+            .map(|(id, client)| (id, client.info()))
+    }
 
-        panic!("Could not find a socket to bind to");
+    /// Forcibly disconnect a client, for example when it is unresponsive.
+    pub fn disconnect_client(&mut self, id: u32) {
+        if let Some(client) = self
+            .clients
+            .delete(id, &mut self.registry, &mut self.serials)
+        {
+            self.remove_source(client.source_id());
+        }
     }
 
-    pub fn run(&mut self) {
-        let mut events = [EpollEvent::empty(); 256];
+    /// Send a protocol error to a client and disconnect it, for errors that are not caused by
+    /// a request it is sending.
+    fn kill_client(&mut self, id: u32, error: MessageError) {
+        if let Some(client) = self.clients.get_mut(id) {
+            log::error!("Error caused by {}: {}", client.info(), error);
+            log::error!("Dropping this client");
+            client.stream_mut().post_error(&error);
+            self.disconnect_client(id);
+        }
+    }
 
-        loop {
-            match epoll_wait(self.epoll_fd, &mut events, -1) {
-                Ok(count) => {
-                    for event in &events[..count] {
-                        let token: EpollToken = event
-                            .data()
-                            .try_into()
-                            .expect("Received invalid event data");
+    /// Run the event loop until a shutdown signal is received or a fatal error occurs.
+    ///
+    /// All clients are disconnected before returning.
+    pub fn run(&mut self) -> ExitCode {
+        let status = loop {
+            if let Some(status) = self.exit_status.take() {
+                break status;
+            }
 
-                        self.handle_epoll(token, event.events());
-                    }
-                }
-                Err(e) => panic!("Error waiting for epoll event: {}", e),
+            if let Err(e) = self.dispatch(None) {
+                log::error!("Error waiting for epoll event: {}", e);
+                break ExitCode::FAILURE;
             }
+        };
+
+        log::info!("Shutting down");
+        for client in self
+            .clients
+            .disconnect_all(&mut self.registry, &mut self.serials)
+        {
+            self.remove_source(client.source_id());
         }
+
+        status
     }
 
-    fn handle_epoll(&mut self, token: EpollToken, events: EpollFlags) {
-        use EpollTokenKind::*;
-
-        match token.kind {
-            NewConnection => {
-                let stream_fd = match accept4(
-                    self.listener_fd,
-                    SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
-                ) {
-                    Ok(fd) => fd,
-                    Err(Errno::EWOULDBLOCK) => return,
-                    Err(e) => {
-                        log::error!("Failed to accept socket connection: {}", e);
-                        return;
-                    }
-                };
+    /// Run one iteration of the event loop, waiting at most `timeout` for events, or
+    /// indefinitely if `None`.
+    ///
+    /// This is what `run` does until it is stopped, for embedders that drive the gateway
+    /// themselves, like tests of the headless backend.
+    pub fn dispatch(&mut self, timeout: Option<Duration>) -> nix::Result<()> {
+        let mut events = [EpollEvent::empty(); 256];
+        let count = match self.event_loop.wait(&mut events, timeout) {
+            Ok(count) => count,
+            Err(Errno::EINTR) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        for event in &events[..count] {
+            self.dispatch_event(event);
+        }
+        self.dispatch_idle();
+        self.start_frame_clocks();
+
+        for client in self
+            .clients
+            .delete_overflowed(&mut self.registry, &mut self.serials)
+        {
+            self.remove_source(client.source_id());
+        }
+        for id in self.clients.take_dirty() {
+            self.flush_client(id);
+        }
 
-                let client_id = self.clients.next_id();
-                let mut client_data_event = EpollEvent::new(
-                    EpollFlags::EPOLLIN | EpollFlags::EPOLLOUT | EpollFlags::EPOLLET,
-                    EpollToken {
-                        kind: EpollTokenKind::ClientData,
-                        id: client_id,
-                    }
-                    .into(),
-                );
-                if let Err(e) = epoll_ctl(
-                    self.epoll_fd,
-                    EpollOp::EpollCtlAdd,
-                    stream_fd,
-                    Some(&mut client_data_event),
-                ) {
-                    log::error!("Failed to register stream with epoll: {}", e);
-                    let _ = close(stream_fd);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    #[inline]
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Switch the keyboards of all seats to the keymap compiled from `names`, for example to
+    /// change the layout. The keymap is sent to the clients on the next iteration.
+    pub fn set_keymap(&mut self, names: Rmlvo) -> Result<(), XkbError> {
+        let mut sink = InputSink {
+            state: &mut self.input_state,
+            registry: &mut self.registry,
+            clients: &mut self.clients,
+            serials: &mut self.serials,
+        };
+        sink.set_keymaps(names)
+    }
+
+    fn accept_clients(&mut self) {
+        loop {
+            let stream_fd = match accept4(
+                self.listener.as_raw_fd(),
+                SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+            ) {
+                // Safety: the fd was just accepted, nothing else owns it.
+                Ok(fd) => unsafe { OwnedFd::from_raw_fd(fd) },
+                Err(Errno::EWOULDBLOCK) => return,
+                Err(e) => {
+                    log::error!("Failed to accept socket connection: {}", e);
                     return;
                 }
+            };
+
+            let info = match ClientInfo::from_stream(stream_fd.as_raw_fd()) {
+                Ok(info) => info,
+                Err(e) => {
+                    log::error!("Failed to get client credentials: {}", e);
+                    continue;
+                }
+            };
+            log::info!("New client connected: {}", info);
+
+            let client_id = self.clients.next_id();
+            let source_id = match self.add_fd(
+                stream_fd.as_raw_fd(),
+                client_data_flags(false),
+                Box::new(move |gateway, _, events| gateway.handle_client_data(client_id, events)),
+            ) {
+                Ok(source_id) => source_id,
+                Err(e) => {
+                    log::error!("Failed to register stream with epoll: {}", e);
+                    continue;
+                }
+            };
+
+            let client = Client::new(
+                MessageStream::new(stream_fd, client_id),
+                self.registry.display_id(),
+                info,
+                source_id,
+            );
+            self.clients.insert_or_push(client_id, client);
+        }
+    }
 
-                let client = Client::new(MessageStream::new(stream_fd), self.registry.display_id());
-                self.clients.insert_or_push(client_id, client);
+    fn handle_client_data(&mut self, id: u32, events: EpollFlags) {
+        let (stream, objects, info, ping) = match self.clients.get_mut(id) {
+            Some(client) => client.parts_mut(),
+            None => {
+                log::error!("Received ready event for non-existing client");
+                return;
             }
-            ClientData => {
-                let (stream, objects) = match self.clients.get_mut(token.id) {
-                    Some(client) => client.stream_and_objects_mut(),
+        };
+
+        if events.contains(EpollFlags::EPOLLIN) {
+            let dispatcher = |object_id, opcode, args: &_, fds: FdSource<'_>, send_buf: &mut _| {
+                let (global_id, version) = match objects.get_with_version(object_id) {
+                    Some(v) => v,
+                    None if objects.is_deleted(object_id) => {
+                        // Can happen if object has been deleted but the client has
+                        // not yet acknowledged it.
+                        log::debug!("Attempt to dispatch request for deleted object");
+                        return Ok(());
+                    }
                     None => {
-                        log::error!("Received ready event for non-existing client");
-                        return;
+                        return Err(MessageError::Protocol {
+                            object_id: ObjectId::display(),
+                            code: wl_display::Error::InvalidObject.into(),
+                            message: format!("invalid object {}", object_id.raw()),
+                        })
                     }
                 };
 
-                if events.contains(EpollFlags::EPOLLIN) {
-                    let dispatcher =
-                        |object_id, opcode, args: &_, fds: FdSource<'_>, send_buf: &mut _| {
-                            let global_id =
-                                objects.get(object_id).ok_or(MessageError::InvalidObject)?;
-
-                            if let Some(mut object) = self.registry.take(global_id) {
-                                let mut state = DispatchState {
-                                    fds,
-                                    send_buf,
-                                    registry: &mut self.registry,
-                                    objects,
-                                };
-                                let res = object.dispatch(opcode, args, &mut state);
-                                self.registry.restore(global_id, object);
-                                res?;
-                            } else {
-                                // Can happen if object has been deleted but the client has not
-                                // yet acknowledged it.
-                                log::debug!("Attempt to dispatch request for deleted object");
-                            }
-
-                            Ok(())
-                        };
-
-                    match stream.receive(dispatcher) {
-                        Ok(0) => {
-                            log::debug!("Client disconnected");
-                            self.clients.delete(token.id);
-                            return;
-                        }
-                        Ok(count) => {
-                            log::debug!("Processed {} requests", count);
-                        }
-                        Err(MessageError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => (),
-                        Err(e) => {
-                            log::error!("Error while receiving message: {}", e);
-                            log::error!("Dropping this client");
-                            self.clients.delete(token.id);
-                            return;
-                        }
-                    }
+                if let Some(mut object) = self.registry.take(global_id) {
+                    let mut state = DispatchState {
+                        self_id: object_id,
+                        version,
+                        fds,
+                        send_buf,
+                        registry: &mut self.registry,
+                        serials: &mut self.serials,
+                        input: &mut self.input_state,
+                        outputs: &mut self.outputs,
+                        objects,
+                        client: info,
+                        ping,
+                    };
+                    let res = object.dispatch(opcode, args, &mut state);
+                    self.registry.restore(global_id, object);
+                    res.map_err(|e| e.for_object(object_id))?;
+                } else {
+                    // Can happen if a global has been removed from the registry
+                    // while the client still has it bound.
+                    log::debug!("Attempt to dispatch request for removed object");
                 }
-                if events.contains(EpollFlags::EPOLLOUT) {
-                    match stream.flush() {
-                        Ok(0) => (),
-                        Ok(count) => {
-                            log::debug!("Flushed {} bytes", count);
-                        }
-                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
-                        Err(e) => {
-                            log::error!("Error while flushing messages: {}", e);
-                            log::error!("Dropping this client");
-                            self.clients.delete(token.id);
-                        }
-                    }
+
+                Ok(())
+            };
+
+            match stream.receive(dispatcher) {
+                Ok(0) => {
+                    log::info!("Client disconnected: {}", info);
+                    self.disconnect_client(id);
+                    return;
+                }
+                Ok(count) => {
+                    log::debug!("Processed {} requests", count);
+                    self.clients.mark_dirty(id);
+                }
+                Err(MessageError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => {
+                    log::error!("Error while receiving message from {}: {}", info, e);
+                    log::error!("Dropping this client");
+                    stream.post_error(&e);
+                    self.disconnect_client(id);
+                    return;
                 }
             }
-            NewInput => {
-                let mut sink = InputSink {
-                    state: &mut self.input_state,
-                    registry: &mut self.registry,
-                    clients: &mut self.clients,
-                };
-                match self.backend.drain_input(&mut sink) {
-                    Ok(_) => (),
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
-                    Err(e) => {
-                        log::error!("Backend failed to drain input: {}", e);
-                    }
+        }
+        if events.contains(EpollFlags::EPOLLOUT) {
+            self.flush_client(id);
+        }
+    }
+
+    fn drain_input(&mut self) {
+        let mut sink = InputSink {
+            state: &mut self.input_state,
+            registry: &mut self.registry,
+            clients: &mut self.clients,
+            serials: &mut self.serials,
+        };
+        match self.backend.drain_input(&mut sink) {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => {
+                log::error!("Backend failed to drain input: {}", e);
+            }
+        }
+        self.update_output_modes();
+    }
+
+    fn read_signals(&mut self) {
+        loop {
+            match self.signals.read() {
+                Ok(Some(signal)) => {
+                    log::info!("Received {}", signal);
+                    self.exit_status = Some(ExitCode::SUCCESS);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("Failed to read signalfd: {}", e);
+                    self.exit_status = Some(ExitCode::FAILURE);
+                    break;
                 }
             }
         }
     }
-}
 
-#[derive(Debug)]
-struct EpollToken {
-    kind: EpollTokenKind,
-    id: u32,
-}
+    fn ping_clients(&mut self) {
+        let deadline = Instant::now() + self.ping_config.timeout;
+        self.clients.ping_all(&self.registry, deadline);
+        self.check_ping_deadlines();
+    }
 
-#[derive(Debug, TryFromPrimitive, IntoPrimitive)]
-#[repr(u32)]
-enum EpollTokenKind {
-    NewConnection,
-    ClientData,
-    NewInput,
-}
+    fn check_ping_deadlines(&mut self) {
+        let now = Instant::now();
+        let (deadline, unresponsive) = self.clients.check_ping_deadlines(now);
+        if !unresponsive.is_empty() {
+            let count = self.unresponsive_clients().count();
+            for (_, info) in self
+                .unresponsive_clients()
+                .filter(|(id, _)| unresponsive.contains(id))
+            {
+                log::warn!(
+                    "Client {} is not responding, {} clients are unresponsive",
+                    info,
+                    count
+                );
+            }
+        }
 
-impl From<EpollToken> for u64 {
-    fn from(token: EpollToken) -> Self {
-        let kind: u32 = token.kind.into();
-        u64::from(kind) | (u64::from(token.id) << 32)
+        let res = match deadline {
+            Some(deadline) => self.arm_timer(self.ping_timeout_timer, deadline - now, None),
+            None => self.disarm_timer(self.ping_timeout_timer),
+        };
+        if let Err(e) = res {
+            log::error!("Failed to set ping timeout timer: {}", e);
+        }
     }
-}
 
-impl TryFrom<u64> for EpollToken {
-    type Error = TryFromPrimitiveError<EpollTokenKind>;
+    /// Flush the send buffer of a client, and listen for the stream becoming writable if not
+    /// everything could be written.
+    fn flush_client(&mut self, id: u32) {
+        let client = match self.clients.get_mut(id) {
+            Some(client) => client,
+            None => return,
+        };
+
+        match client.stream_mut().flush() {
+            Ok(0) => (),
+            Ok(count) => {
+                log::debug!("Flushed {} bytes", count);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => {
+                log::error!("Error while flushing messages: {}", e);
+                log::error!("Dropping this client");
+                self.disconnect_client(id);
+                return;
+            }
+        }
 
-    fn try_from(raw: u64) -> Result<Self, Self::Error> {
-        let kind = (raw as u32).try_into()?;
-        let id = (raw >> 32) as u32;
+        let wants_writable = client.stream_mut().has_pending();
+        if wants_writable != client.wants_writable {
+            client.wants_writable = wants_writable;
+            let source_id = client.source_id();
+            if let Err(e) = self.modify_fd(source_id, client_data_flags(wants_writable)) {
+                log::error!("Failed to modify stream epoll interest: {}", e);
+                log::error!("Dropping this client");
+                self.disconnect_client(id);
+            }
+        }
+    }
+}
 
-        Ok(Self { kind, id })
+fn client_data_flags(writable: bool) -> EpollFlags {
+    let mut flags = EpollFlags::EPOLLIN | EpollFlags::EPOLLET;
+    if writable {
+        flags |= EpollFlags::EPOLLOUT;
     }
+    flags
+}
+
+/// Milliseconds on `CLOCK_MONOTONIC`, which frame callbacks and input events are timestamped
+/// with.
+pub fn timestamp() -> u32 {
+    let now = clock_gettime(ClockId::CLOCK_MONOTONIC).expect("Failed to read monotonic clock");
+    (now.tv_sec() as u64 * 1000 + now.tv_nsec() as u64 / 1_000_000) as u32
 }
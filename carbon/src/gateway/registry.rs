@@ -9,13 +9,28 @@ use std::{fmt::Debug, num::NonZeroU32};
 
 slotmap::new_key_type! { pub struct GlobalObjectId; }
 
+/// A global object advertised to clients through `wl_registry`.
+#[derive(Debug, Clone, Copy)]
+struct Global {
+    /// The name clients use to bind the global, unique for the lifetime of the compositor.
+    name: u32,
+    id: GlobalObjectId,
+}
+
 pub struct ObjectRegistry {
     display_id: GlobalObjectId,
     registry_id: GlobalObjectId,
-    globals: Vec<GlobalObjectId>,
+    globals: Vec<Global>,
+    next_global_name: u32,
     objects: SlotMap<GlobalObjectId, Option<Interface>>,
 }
 
+impl Default for ObjectRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ObjectRegistry {
     pub fn new() -> Self {
         let mut objects = SlotMap::with_key();
@@ -23,20 +38,26 @@ impl ObjectRegistry {
         let display_id = objects.insert(Some(Interface::WlDisplay(WlDisplay)));
         let registry_id = objects.insert(Some(Interface::WlRegistry(WlRegistry)));
 
-        let globals = vec![
-            objects.insert(Some(Interface::WlCompositor(WlCompositor))),
-            objects.insert(Some(Interface::WlShm(WlShm))),
-            objects.insert(Some(Interface::WlDataDeviceManager(WlDataDeviceManager))),
-            objects.insert(Some(Interface::WlSubcompositor(WlSubcompositor))),
-            objects.insert(Some(Interface::XdgWmBase(XdgWmBase))),
-        ];
-
-        Self {
+        let mut res = Self {
             display_id,
             registry_id,
-            globals,
+            globals: vec![],
+            next_global_name: 1,
             objects,
+        };
+
+        for interface in [
+            Interface::WlCompositor(WlCompositor),
+            Interface::WlShm(WlShm),
+            Interface::WlDataDeviceManager(WlDataDeviceManager),
+            Interface::WlSubcompositor(WlSubcompositor),
+            Interface::XdgWmBase(XdgWmBase),
+        ] {
+            let id = res.insert(interface);
+            res.push_global(id);
         }
+
+        res
     }
 
     #[inline]
@@ -49,64 +70,75 @@ impl ObjectRegistry {
         self.registry_id
     }
 
+    /// Iterate over the current globals along with their names.
     #[inline]
-    pub fn globals(&self) -> impl Iterator<Item = (GlobalObjectId, &Interface)> {
+    pub fn globals(&self) -> impl Iterator<Item = (u32, GlobalObjectId, &Interface)> {
         self.globals
             .iter()
-            .copied()
-            .map(|id| (id, self.get(id).unwrap()))
+            .map(|g| (g.name, g.id, self.get(g.id).unwrap()))
     }
 
-    pub fn make_global(
-        &mut self,
-        id: GlobalObjectId,
-        clients: &mut Clients,
-    ) -> Result<(), MessageError> {
-        if self.globals.contains(&id) {
-            return Ok(());
-        }
+    /// Look up a global by the name it was advertised with.
+    #[inline]
+    pub fn global_by_name(&self, name: u32) -> Option<(GlobalObjectId, &Interface)> {
+        self.globals
+            .iter()
+            .find(|g| g.name == name)
+            .and_then(|g| self.get(g.id).map(|interface| (g.id, interface)))
+    }
 
-        if let Some(new_global) = self.get(id) {
-            for (client, id) in clients.find_interface_in_clients(self, |interface| {
-                matches!(interface, Interface::WlRegistry(_))
-            }) {
-                wl_registry::emit_global(
-                    client.stream_mut().send_buf_mut(),
-                    id,
-                    new_global.id(),
-                    new_global.name(),
-                    new_global.version(),
-                )?;
-            }
+    fn push_global(&mut self, id: GlobalObjectId) -> u32 {
+        let name = self.next_global_name;
+        self.next_global_name = name.checked_add(1).expect("Ran out of global names");
+        self.globals.push(Global { name, id });
 
-            self.globals.push(id);
+        name
+    }
+
+    /// Advertise the object as a global to all clients.
+    ///
+    /// Clients whose send buffer is full are marked as overflowed and should be disconnected.
+    pub fn make_global(&mut self, id: GlobalObjectId, clients: &mut Clients) {
+        if self.globals.iter().any(|g| g.id == id) || self.get(id).is_none() {
+            return;
         }
 
-        Ok(())
+        let name = self.push_global(id);
+        let new_global = self.get(id).unwrap();
+        for (client, id) in clients.find_interface_in_clients(self, |interface| {
+            matches!(interface, Interface::WlRegistry(_))
+        }) {
+            if let Err(e) = wl_registry::emit_global(
+                client.stream_mut().send_buf_mut(),
+                id,
+                name,
+                new_global.name(),
+                new_global.version(),
+            ) {
+                log::warn!("Failed to announce global to client: {}", e);
+            }
+        }
     }
 
+    /// Stop advertising the object as a global.
+    ///
+    /// Clients whose send buffer is full are marked as overflowed and should be disconnected.
     #[inline]
-    pub fn remove_global(
-        &mut self,
-        id: GlobalObjectId,
-        clients: &mut Clients,
-    ) -> Result<(), MessageError> {
-        if let Some(idx) = self.globals.iter().position(|&g| g == id) {
-            let global = self.get(id).unwrap();
+    pub fn remove_global(&mut self, id: GlobalObjectId, clients: &mut Clients) {
+        if let Some(idx) = self.globals.iter().position(|g| g.id == id) {
+            let global = self.globals.swap_remove(idx);
             for (client, id) in clients.find_interface_in_clients(self, |interface| {
                 matches!(interface, Interface::WlRegistry(_))
             }) {
-                wl_registry::emit_global_remove(
+                if let Err(e) = wl_registry::emit_global_remove(
                     client.stream_mut().send_buf_mut(),
                     id,
-                    global.id(),
-                )?;
+                    global.name,
+                ) {
+                    log::warn!("Failed to announce global removal to client: {}", e);
+                }
             }
-
-            self.globals.swap_remove(idx);
         }
-
-        Ok(())
     }
 
     #[inline]
@@ -114,19 +146,32 @@ impl ObjectRegistry {
         self.objects.get(id).and_then(Option::as_ref)
     }
 
+    #[inline]
+    pub fn get_mut(&mut self, id: GlobalObjectId) -> Option<&mut Interface> {
+        self.objects.get_mut(id).and_then(Option::as_mut)
+    }
+
+    /// All objects that are not temporarily taken.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = (GlobalObjectId, &Interface)> {
+        self.objects
+            .iter()
+            .filter_map(|(id, object)| Some((id, object.as_ref()?)))
+    }
+
     #[inline]
     pub fn take(&mut self, id: GlobalObjectId) -> Option<Interface> {
         self.objects.get_mut(id).and_then(|b| b.take())
     }
 
+    /// Put back an object that was taken with `take`.
+    ///
+    /// If the object was removed while it was taken, it is dropped instead.
     #[inline]
     pub fn restore(&mut self, id: GlobalObjectId, object: Interface) {
-        let entry = self
-            .objects
-            .get_mut(id)
-            .expect("Tried to restore non-existent object");
-
-        *entry = Some(object);
+        if let Some(entry) = self.objects.get_mut(id) {
+            *entry = Some(object);
+        }
     }
 
     #[inline]
@@ -134,14 +179,19 @@ impl ObjectRegistry {
         self.objects.insert(Some(object))
     }
 
+    /// Remove an object from the registry.
+    ///
+    /// Returns `None` if the object does not exist or is temporarily taken, in the latter case
+    /// it will be dropped when it is restored.
     #[inline]
     pub fn remove(&mut self, id: GlobalObjectId) -> Option<Interface> {
-        self.objects
-            .remove(id)
-            .map(|entry| entry.expect("Tried to remove object that was temporarily taken"))
+        self.objects.remove(id).flatten()
     }
 }
 
+/// Object ids starting from this value are allocated by the server.
+pub const SERVER_ID_START: u32 = 0xff00_0000;
+
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ObjectId(NonZeroU32);
@@ -152,46 +202,152 @@ impl ObjectId {
         NonZeroU32::new(raw).map(Self)
     }
 
+    /// The id of the `wl_display` singleton, which is the same for every client.
+    #[inline]
+    pub fn display() -> Self {
+        Self::new(1).unwrap()
+    }
+
     #[inline]
     pub fn raw(self) -> u32 {
         self.0.get()
     }
+
+    #[inline]
+    pub fn is_server_allocated(self) -> bool {
+        self.raw() >= SERVER_ID_START
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ClientObject {
+    Vacant,
+    /// An object created by the client or created by the server on its behalf, it is removed
+    /// from the registry when destroyed.
+    Owned {
+        global_id: GlobalObjectId,
+        version: u32,
+    },
+    /// A global the client has bound, which may be shared with other clients.
+    Bound {
+        global_id: GlobalObjectId,
+        version: u32,
+    },
+    /// An object that has been destroyed, but the client may not have received the
+    /// `wl_display.delete_id` event yet. Requests to it are ignored until the client
+    /// reuses the id.
+    Deleted,
 }
 
 pub struct ClientObjects {
-    objects: Vec<Option<GlobalObjectId>>,
+    /// Objects with client allocated ids, indexed by id.
+    objects: Vec<ClientObject>,
+    /// Objects with server allocated ids, indexed by id minus `SERVER_ID_START`.
+    server_objects: Vec<ClientObject>,
 }
 
 impl ClientObjects {
     #[inline]
     pub fn new(display_id: GlobalObjectId) -> Self {
         Self {
-            objects: vec![None, Some(display_id)],
+            objects: vec![
+                ClientObject::Vacant,
+                ClientObject::Bound {
+                    global_id: display_id,
+                    version: 1,
+                },
+            ],
+            server_objects: vec![],
+        }
+    }
+
+    #[inline]
+    fn entry(&self, id: ObjectId) -> Option<&ClientObject> {
+        if id.is_server_allocated() {
+            self.server_objects
+                .get((id.raw() - SERVER_ID_START) as usize)
+        } else {
+            self.objects.get(id.raw() as usize)
+        }
+    }
+
+    #[inline]
+    fn entry_mut(&mut self, id: ObjectId) -> Option<&mut ClientObject> {
+        if id.is_server_allocated() {
+            self.server_objects
+                .get_mut((id.raw() - SERVER_ID_START) as usize)
+        } else {
+            self.objects.get_mut(id.raw() as usize)
         }
     }
 
     #[inline]
     pub fn get(&self, id: ObjectId) -> Option<GlobalObjectId> {
-        self.objects.get(id.0.get() as usize).and_then(|id| *id)
+        self.get_with_version(id).map(|(global_id, _)| global_id)
+    }
+
+    /// Returns the registry entry along with the version the client object has.
+    #[inline]
+    pub fn get_with_version(&self, id: ObjectId) -> Option<(GlobalObjectId, u32)> {
+        match self.entry(id) {
+            Some(
+                ClientObject::Owned { global_id, version }
+                | ClientObject::Bound { global_id, version },
+            ) => Some((*global_id, *version)),
+            _ => None,
+        }
     }
 
+    #[inline]
+    pub fn is_deleted(&self, id: ObjectId) -> bool {
+        matches!(self.entry(id), Some(ClientObject::Deleted))
+    }
+
+    /// Register an object created by the client, transferring ownership of the registry entry
+    /// to the client.
+    ///
+    /// `version` should be the version of the object the `new_id` request was sent to.
     #[inline]
     pub fn register(
         &mut self,
         id: ObjectId,
-        global_id: Option<GlobalObjectId>,
+        global_id: GlobalObjectId,
+        version: u32,
+    ) -> Result<(), MessageError> {
+        self.insert(id, ClientObject::Owned { global_id, version })
+    }
+
+    /// Register a global the client has bound with the given version. The registry entry is
+    /// not removed when the client object is destroyed.
+    #[inline]
+    pub fn register_global(
+        &mut self,
+        id: ObjectId,
+        global_id: GlobalObjectId,
+        version: u32,
     ) -> Result<(), MessageError> {
-        let idx = id.0.get() as usize;
+        self.insert(id, ClientObject::Bound { global_id, version })
+    }
+
+    fn insert(&mut self, id: ObjectId, object: ClientObject) -> Result<(), MessageError> {
+        if id.is_server_allocated() {
+            return Err(MessageError::BadRequest(format!(
+                "client tried to create object with server allocated id {}",
+                id.raw()
+            )));
+        }
+
+        let idx = id.raw() as usize;
         if idx == self.objects.len() {
-            self.objects.push(global_id);
+            self.objects.push(object);
             Ok(())
         } else {
             let entry = self
                 .objects
                 .get_mut(idx)
                 .ok_or(MessageError::InvalidObject)?;
-            if entry.is_none() {
-                *entry = global_id;
+            if matches!(entry, ClientObject::Vacant | ClientObject::Deleted) {
+                *entry = object;
                 Ok(())
             } else {
                 Err(MessageError::InvalidObject)
@@ -199,11 +355,173 @@ impl ClientObjects {
         }
     }
 
+    /// Allocate an id in the server range for an object the server creates on behalf of the
+    /// client, for example the `new_id` argument of `wl_data_device.data_offer`.
+    ///
+    /// Ownership of the registry entry is transferred to the client.
+    pub fn allocate(
+        &mut self,
+        global_id: GlobalObjectId,
+        version: u32,
+    ) -> Result<ObjectId, MessageError> {
+        let object = ClientObject::Owned { global_id, version };
+        let idx = match self
+            .server_objects
+            .iter()
+            .position(|o| matches!(o, ClientObject::Vacant))
+        {
+            Some(idx) => {
+                self.server_objects[idx] = object;
+                idx
+            }
+            None => {
+                self.server_objects.push(object);
+                self.server_objects.len() - 1
+            }
+        };
+
+        u32::try_from(idx)
+            .ok()
+            .and_then(|idx| idx.checked_add(SERVER_ID_START))
+            .and_then(ObjectId::new)
+            .ok_or(MessageError::OutOfMemory)
+    }
+
+    /// Mark the object as deleted, returning the registry entry if it was owned by the client
+    /// and should be removed.
+    ///
+    /// Server allocated ids are released immediately since no acknowledgement from the client
+    /// is required before they can be reused.
+    #[inline]
+    pub fn unregister(&mut self, id: ObjectId) -> Result<Option<GlobalObjectId>, MessageError> {
+        let released = if id.is_server_allocated() {
+            ClientObject::Vacant
+        } else {
+            ClientObject::Deleted
+        };
+        let entry = self.entry_mut(id).ok_or(MessageError::InvalidObject)?;
+        match *entry {
+            ClientObject::Owned { global_id, .. } => {
+                *entry = released;
+                Ok(Some(global_id))
+            }
+            ClientObject::Bound { .. } => {
+                *entry = released;
+                Ok(None)
+            }
+            ClientObject::Vacant | ClientObject::Deleted => Err(MessageError::InvalidObject),
+        }
+    }
+
     #[inline]
     pub fn iter(&self) -> impl Iterator<Item = (ObjectId, GlobalObjectId)> + '_ {
-        self.objects
+        let client = self.objects.iter().enumerate().map(|(i, o)| (i as u32, o));
+        let server = self
+            .server_objects
             .iter()
             .enumerate()
-            .filter_map(|(i, o)| o.map(|o| (ObjectId::new(i as u32).unwrap(), o)))
+            .map(|(i, o)| (i as u32 + SERVER_ID_START, o));
+
+        client.chain(server).filter_map(|(i, o)| match o {
+            ClientObject::Owned { global_id, .. } | ClientObject::Bound { global_id, .. } => {
+                Some((ObjectId::new(i).unwrap(), *global_id))
+            }
+            _ => None,
+        })
+    }
+
+    /// Iterate over the registry entries owned by the client.
+    #[inline]
+    pub fn owned(&self) -> impl Iterator<Item = GlobalObjectId> + '_ {
+        self.objects
+            .iter()
+            .chain(&self.server_objects)
+            .filter_map(|o| match o {
+                ClientObject::Owned { global_id, .. } => Some(*global_id),
+                _ => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::WlCallback;
+
+    fn id(raw: u32) -> ObjectId {
+        ObjectId::new(raw).unwrap()
+    }
+
+    fn objects() -> (ObjectRegistry, ClientObjects) {
+        let registry = ObjectRegistry::new();
+        let objects = ClientObjects::new(registry.display_id());
+        (registry, objects)
+    }
+
+    #[test]
+    fn allocate_starts_at_server_range() {
+        let (mut registry, mut objects) = objects();
+        let first = registry.insert(Interface::WlCallback(WlCallback));
+        let second = registry.insert(Interface::WlCallback(WlCallback));
+
+        let a = objects.allocate(first, 3).unwrap();
+        let b = objects.allocate(second, 1).unwrap();
+        assert_eq!(a.raw(), SERVER_ID_START);
+        assert_eq!(b.raw(), SERVER_ID_START + 1);
+        assert!(a.is_server_allocated());
+        assert_eq!(objects.get_with_version(a), Some((first, 3)));
+        assert_eq!(objects.get_with_version(b), Some((second, 1)));
+        assert!(objects.owned().any(|global_id| global_id == first));
+    }
+
+    #[test]
+    fn allocate_reuses_released_ids() {
+        let (mut registry, mut objects) = objects();
+        let first = registry.insert(Interface::WlCallback(WlCallback));
+        let second = registry.insert(Interface::WlCallback(WlCallback));
+        let third = registry.insert(Interface::WlCallback(WlCallback));
+
+        let a = objects.allocate(first, 1).unwrap();
+        let b = objects.allocate(second, 1).unwrap();
+        assert_eq!(objects.unregister(a).unwrap(), Some(first));
+        // Server ids need no acknowledgement, so they are free again right away.
+        assert!(!objects.is_deleted(a));
+        assert_eq!(objects.get(a), None);
+
+        assert_eq!(objects.allocate(third, 1).unwrap(), a);
+        assert_eq!(objects.get(b), Some(second));
+    }
+
+    #[test]
+    fn clients_cannot_create_server_ids() {
+        let (mut registry, mut objects) = objects();
+        let global_id = registry.insert(Interface::WlCallback(WlCallback));
+
+        assert!(matches!(
+            objects.register(id(SERVER_ID_START), global_id, 1),
+            Err(MessageError::BadRequest(_))
+        ));
+        assert!(matches!(
+            objects.register_global(id(u32::MAX), global_id, 1),
+            Err(MessageError::BadRequest(_))
+        ));
+        assert_eq!(objects.iter().count(), 1);
+    }
+
+    #[test]
+    fn client_ids_are_deleted_until_reused() {
+        let (mut registry, mut objects) = objects();
+        let global_id = registry.insert(Interface::WlCallback(WlCallback));
+
+        objects.register(id(2), global_id, 1).unwrap();
+        assert_eq!(objects.unregister(id(2)).unwrap(), Some(global_id));
+        assert!(objects.is_deleted(id(2)));
+        assert!(matches!(
+            objects.unregister(id(2)),
+            Err(MessageError::InvalidObject)
+        ));
+
+        objects.register(id(2), global_id, 1).unwrap();
+        assert_eq!(objects.get(id(2)), Some(global_id));
     }
 }
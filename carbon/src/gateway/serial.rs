@@ -0,0 +1,156 @@
+use crate::{gateway::registry::GlobalObjectId, input::SeatId};
+
+use std::collections::VecDeque;
+
+/// Number of serials that are remembered, older serials are considered stale.
+const MAX_SERIAL_HISTORY: usize = 256;
+
+/// The input device an event carrying a serial came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputDevice {
+    Pointer,
+    Keyboard,
+    Touch,
+}
+
+/// What a serial was issued for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialEvent {
+    pub seat: Option<SeatId>,
+    pub device: Option<InputDevice>,
+    /// The client the event was sent to.
+    pub client_id: u32,
+    /// The surface the event was about, if any.
+    pub surface: Option<GlobalObjectId>,
+}
+
+/// Compositor-wide serial allocator that remembers what recent serials were issued for, so
+/// serials sent back by clients can be validated.
+pub struct Serials {
+    last: u32,
+    /// The event is `None` once its client disconnected, as client ids are reused.
+    history: VecDeque<(u32, Option<SerialEvent>)>,
+}
+
+impl Default for Serials {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serials {
+    pub fn new() -> Self {
+        Self {
+            last: 0,
+            history: VecDeque::with_capacity(MAX_SERIAL_HISTORY),
+        }
+    }
+
+    /// Allocate a serial for an event and record it in the history.
+    pub fn next(&mut self, event: SerialEvent) -> u32 {
+        self.last = self.last.wrapping_add(1);
+        if self.history.len() == MAX_SERIAL_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((self.last, Some(event)));
+
+        self.last
+    }
+
+    /// Look up what a serial was issued for, `None` if it was never issued or is too old.
+    pub fn get(&self, serial: u32) -> Option<&SerialEvent> {
+        // Serials are allocated in order, so the offset from the last one gives the position,
+        // also when they wrapped around.
+        let age = self.last.wrapping_sub(serial) as usize;
+        let idx = self.history.len().checked_sub(age + 1)?;
+        self.history
+            .get(idx)
+            .filter(|(s, _)| *s == serial)
+            .and_then(|(_, event)| event.as_ref())
+    }
+
+    /// Forget the serials issued to a client that disconnected, so a new client that gets the
+    /// same id can not use them.
+    pub fn forget_client(&mut self, client_id: u32) {
+        for (_, event) in &mut self.history {
+            if event.is_some_and(|event| event.client_id == client_id) {
+                *event = None;
+            }
+        }
+    }
+
+    /// Check that `serial` was sent to `client_id` with an event from a device matching
+    /// `device`, which should be `None` to accept any device.
+    pub fn validate(
+        &self,
+        serial: u32,
+        client_id: u32,
+        device: Option<InputDevice>,
+    ) -> Option<&SerialEvent> {
+        self.get(serial).filter(|event| {
+            event.client_id == client_id && (device.is_none() || event.device == device)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(client_id: u32, device: InputDevice) -> SerialEvent {
+        SerialEvent {
+            seat: None,
+            device: Some(device),
+            client_id,
+            surface: None,
+        }
+    }
+
+    #[test]
+    fn validate_checks_client_and_device() {
+        let mut serials = Serials::new();
+        let serial = serials.next(event(1, InputDevice::Pointer));
+
+        assert!(serials.validate(serial, 1, None).is_some());
+        assert!(serials
+            .validate(serial, 1, Some(InputDevice::Pointer))
+            .is_some());
+        assert!(serials
+            .validate(serial, 1, Some(InputDevice::Keyboard))
+            .is_none());
+        assert!(serials.validate(serial, 2, None).is_none());
+        assert!(serials.validate(serial.wrapping_add(1), 1, None).is_none());
+    }
+
+    #[test]
+    fn old_serials_are_stale() {
+        let mut serials = Serials::new();
+        // Start right before the serials wrap around.
+        serials.last = u32::MAX - 10;
+        let first = serials.next(event(1, InputDevice::Pointer));
+        for _ in 1..MAX_SERIAL_HISTORY {
+            serials.next(event(1, InputDevice::Pointer));
+        }
+        assert!(serials.get(first).is_some());
+
+        let last = serials.next(event(1, InputDevice::Pointer));
+        assert!(last < first);
+        assert!(serials.get(first).is_none());
+        assert!(serials.get(last).is_some());
+    }
+
+    #[test]
+    fn serials_of_disconnected_clients_are_forgotten() {
+        let mut serials = Serials::new();
+        let old = serials.next(event(1, InputDevice::Pointer));
+        let other = serials.next(event(2, InputDevice::Pointer));
+
+        // A new client with the same id must not be able to use the serials of the old one.
+        serials.forget_client(1);
+        assert!(serials.validate(old, 1, None).is_none());
+        assert!(serials.validate(other, 2, None).is_some());
+
+        let new = serials.next(event(1, InputDevice::Pointer));
+        assert!(serials.validate(new, 1, None).is_some());
+    }
+}
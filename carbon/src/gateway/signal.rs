@@ -0,0 +1,49 @@
+use nix::sys::{
+    signal::{SigSet, Signal},
+    signalfd::{SfdFlags, SignalFd},
+};
+
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// Signals that make the gateway shut down gracefully.
+const SHUTDOWN_SIGNALS: [Signal; 3] = [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP];
+
+/// Receives the shutdown signals through a signalfd so they can be handled in the event loop.
+pub struct ShutdownSignals {
+    fd: SignalFd,
+}
+
+impl AsRawFd for ShutdownSignals {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl ShutdownSignals {
+    /// Block the shutdown signals for the calling thread and create a signalfd for them.
+    ///
+    /// This must be called before any other threads are spawned, since they inherit the signal
+    /// mask and would otherwise receive the signals with their default action.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let mut mask = SigSet::empty();
+        for signal in SHUTDOWN_SIGNALS {
+            mask.add(signal);
+        }
+        mask.thread_block()
+            .expect("Failed to block shutdown signals");
+        let fd = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)
+            .expect("Failed to create signalfd");
+
+        Self { fd }
+    }
+
+    /// Read the next pending signal, if any.
+    pub fn read(&mut self) -> nix::Result<Option<Signal>> {
+        match self.fd.read_signal()? {
+            Some(info) => Ok(Signal::try_from(info.ssi_signo as i32).ok()),
+            None => Ok(None),
+        }
+    }
+}
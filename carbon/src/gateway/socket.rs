@@ -0,0 +1,234 @@
+use nix::{
+    fcntl::{fcntl, flock, FcntlArg, FdFlag, FlockArg, OFlag},
+    sys::socket::*,
+};
+
+use std::{
+    env,
+    ffi::OsString,
+    fs, io,
+    os::unix::prelude::*,
+    path::{Path, PathBuf},
+};
+
+/// First file descriptor passed by the systemd socket activation protocol.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Where the gateway should listen for client connections.
+pub enum SocketConfig {
+    /// Bind to the first free `wayland-N` socket in `XDG_RUNTIME_DIR`.
+    Auto,
+    /// Bind to a socket name relative to `XDG_RUNTIME_DIR`, or to an absolute path.
+    Name(PathBuf),
+    /// Use an inherited socket that is already bound and listening.
+    Fd(OwnedFd),
+}
+
+impl SocketConfig {
+    /// Takes the listening socket passed with systemd style `LISTEN_FDS` socket activation,
+    /// if there is one for this process.
+    ///
+    /// The activation variables are removed from the environment so they are not
+    /// inherited by child processes.
+    pub fn from_listen_fds() -> Option<Self> {
+        let pid = env::var("LISTEN_PID").ok()?;
+        let count = env::var("LISTEN_FDS").ok()?;
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+        let count = count.parse::<u32>().ok()?;
+        if count == 0 {
+            return None;
+        } else if count > 1 {
+            log::warn!(
+                "Got {} sockets from LISTEN_FDS, only using the first one",
+                count
+            );
+        }
+
+        // Safety: the socket activation protocol hands ownership of this fd to us.
+        Some(Self::Fd(unsafe {
+            OwnedFd::from_raw_fd(SD_LISTEN_FDS_START)
+        }))
+    }
+}
+
+/// The listening socket of the gateway.
+///
+/// Sockets bound by the gateway itself are unlinked together with their lock file on drop.
+pub struct ListeningSocket {
+    fd: OwnedFd,
+    display_name: Option<OsString>,
+    _bound: Option<BoundPaths>,
+}
+
+struct BoundPaths {
+    socket_path: PathBuf,
+    lock_path: PathBuf,
+    _lock_file: fs::File,
+}
+
+impl Drop for BoundPaths {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.socket_path);
+        let _ = fs::remove_file(&self.lock_path);
+    }
+}
+
+impl AsRawFd for ListeningSocket {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl ListeningSocket {
+    pub fn new(config: SocketConfig) -> Self {
+        match config {
+            SocketConfig::Auto => {
+                let dir = runtime_dir();
+                for n in 0..32 {
+                    let name = format!("wayland-{}", n);
+                    match bind_at(dir.join(&name)) {
+                        Ok((fd, bound)) => {
+                            return Self {
+                                fd,
+                                display_name: Some(name.into()),
+                                _bound: Some(bound),
+                            }
+                        }
+                        Err(e) => log::warn!("{}", e),
+                    }
+                }
+
+                panic!("Could not find a socket to bind to");
+            }
+            SocketConfig::Name(name) => {
+                let path = if name.is_absolute() {
+                    name.clone()
+                } else {
+                    runtime_dir().join(&name)
+                };
+                let (fd, bound) = bind_at(path).unwrap_or_else(|e| panic!("{}", e));
+
+                Self {
+                    fd,
+                    display_name: Some(name.into()),
+                    _bound: Some(bound),
+                }
+            }
+            SocketConfig::Fd(fd) => {
+                // The gateway expects a nonblocking listener and inherited fds may be either.
+                let raw_fd = fd.as_raw_fd();
+                let flags = fcntl(raw_fd, FcntlArg::F_GETFL).expect("Invalid listening socket fd");
+                fcntl(
+                    raw_fd,
+                    FcntlArg::F_SETFL(OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK),
+                )
+                .and_then(|_| fcntl(raw_fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)))
+                .expect("Failed to set flags on listening socket fd");
+
+                log::info!("Listening on inherited socket fd {}", raw_fd);
+
+                Self {
+                    fd,
+                    display_name: None,
+                    _bound: None,
+                }
+            }
+        }
+    }
+
+    /// The value clients should use for `WAYLAND_DISPLAY`, if known.
+    #[inline]
+    pub fn display_name(&self) -> Option<&OsString> {
+        self.display_name.as_ref()
+    }
+}
+
+fn runtime_dir() -> PathBuf {
+    env::var("XDG_RUNTIME_DIR")
+        .ok()
+        .filter(|dir| dir.starts_with('/'))
+        .expect("XDG_RUNTIME_DIR not set or invalid")
+        .into()
+}
+
+fn bind_at(socket_path: PathBuf) -> Result<(OwnedFd, BoundPaths), String> {
+    let mut lock_path = OsString::from(&socket_path);
+    lock_path.push(".lock");
+    let lock_path = PathBuf::from(lock_path);
+
+    let lock_file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .mode(0o660)
+        .open(&lock_path)
+        .map_err(|e| {
+            format!(
+                "Failed to open socket lock file at {}: {}",
+                lock_path.to_string_lossy(),
+                e
+            )
+        })?;
+
+    flock(lock_file.as_raw_fd(), FlockArg::LockExclusiveNonblock).map_err(|e| {
+        format!(
+            "Failed to acquire socket lock at {}: {}",
+            lock_path.to_string_lossy(),
+            e.desc()
+        )
+    })?;
+
+    if let Some(e) = fs::remove_file(&socket_path)
+        .err()
+        .filter(|e| e.kind() != io::ErrorKind::NotFound)
+    {
+        return Err(format!(
+            "Failed to remove existing socket at {}: {}",
+            socket_path.to_string_lossy(),
+            e
+        ));
+    }
+
+    let fd = bind_listener(&socket_path).map_err(|e| {
+        format!(
+            "Failed to bind to socket at {}: {}",
+            socket_path.to_string_lossy(),
+            e
+        )
+    })?;
+
+    log::info!("Listening at {}", socket_path.to_string_lossy());
+
+    Ok((
+        fd,
+        BoundPaths {
+            socket_path,
+            lock_path,
+            _lock_file: lock_file,
+        },
+    ))
+}
+
+fn bind_listener(path: &Path) -> nix::Result<OwnedFd> {
+    let sock_addr = UnixAddr::new(path)?;
+    let fd = socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    // Safety: the fd was just created, nothing else owns it.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    bind(fd.as_raw_fd(), &sock_addr)?;
+    listen(fd.as_raw_fd(), 256)?;
+
+    Ok(fd)
+}
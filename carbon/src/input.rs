@@ -1,61 +1,954 @@
 use crate::{
     gateway::{
         client::Clients,
-        registry::{GlobalObjectId, ObjectRegistry},
+        message::{MessageBuf, MessageError, Write},
+        registry::{GlobalObjectId, ObjectId, ObjectRegistry},
+        serial::{InputDevice, SerialEvent, Serials},
+        timestamp,
     },
-    protocol::{wl_seat::Capability, Interface, WlSeat},
+    protocol::{
+        wl_keyboard::{self, KeyState, KeymapFormat},
+        wl_pointer::{self, Axis, ButtonState},
+        wl_seat::{self, Capability},
+        wl_touch, Interface, WlSeat,
+    },
+    render, surface,
+    xkb::{KeyboardState, Keymap, Modifiers, Rmlvo, Xkb, XkbError},
 };
 
+use fixed::types::I24F8;
 use slotmap::{new_key_type, SlotMap};
 
+use std::{fs::File, os::unix::io::OwnedFd};
+
+/// Keys repeat 25 times per second after being held for 600 ms.
+const REPEAT_RATE: i32 = 25;
+const REPEAT_DELAY: i32 = 600;
+
 new_key_type! { pub struct SeatId; }
 
+/// Input reported by a backend. Positions are in the coordinates of the output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEvent {
+    PointerMotion {
+        dx: f64,
+        dy: f64,
+    },
+    PointerMotionAbsolute {
+        x: f64,
+        y: f64,
+    },
+    /// Motion of the pointing device, which does not move the pointer. The winit backend reports
+    /// this next to absolute positions while the cursor is over its window.
+    RawPointerMotion {
+        dx: f64,
+        dy: f64,
+    },
+    /// `button` is a Linux evdev code, like `BTN_LEFT`.
+    PointerButton {
+        button: u32,
+        state: ButtonState,
+    },
+    /// Scroll by `value`, `discrete` is the number of steps if the scroll is from a wheel.
+    PointerAxis {
+        axis: Axis,
+        value: f64,
+        discrete: Option<i32>,
+    },
+    /// `key` is a Linux evdev scancode, like `KEY_A`.
+    Key {
+        key: u32,
+        state: KeyState,
+    },
+    TouchDown {
+        id: i32,
+        x: f64,
+        y: f64,
+    },
+    TouchMotion {
+        id: i32,
+        x: f64,
+        y: f64,
+    },
+    TouchUp {
+        id: i32,
+    },
+    /// Ends a group of touch events that belong together.
+    TouchFrame,
+    TouchCancel,
+    /// The seat gained or lost the focus of the host, keys released while it is not focused
+    /// are not reported.
+    Focus {
+        focused: bool,
+    },
+}
+
+/// A `wl_pointer`, `wl_keyboard` or `wl_touch` created by a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceObject {
+    /// Id of the client that created the object.
+    pub client: u32,
+    pub id: ObjectId,
+    pub global_id: GlobalObjectId,
+    pub version: u32,
+}
+
 pub struct Seat {
+    name: String,
     capabilities: Capability,
+    /// All capabilities the seat ever had, clients may create devices for these.
+    past_capabilities: Capability,
     object_id: GlobalObjectId,
+    pointers: Vec<DeviceObject>,
+    keyboards: Vec<DeviceObject>,
+    touches: Vec<DeviceObject>,
+    pointer_position: (f64, f64),
+    /// Buttons that are held down, in the order they were pressed. The pointer focus does not
+    /// change while any are held.
+    pressed_buttons: Vec<u32>,
+    pointer_focus: Option<SurfaceFocus>,
+    /// Keys that are held down, in the order they were pressed.
+    pressed_keys: Vec<u32>,
+    /// Moves to the surface that is clicked.
+    keyboard_focus: Option<KeyboardFocus>,
+    /// Touch points that went down on a surface, which receives all of their events.
+    touch_points: Vec<TouchPoint>,
+    /// Clients that were sent touch events since the last `wl_touch.frame`.
+    touch_clients: Vec<u32>,
+    /// `None` if no keymap could be compiled.
+    keyboard: Option<Keyboard>,
+}
+
+/// The surface under the pointer or a touch point, which receives its events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SurfaceFocus {
+    surface: GlobalObjectId,
+    /// The client that owns the surface, and the id of the surface for it.
+    client: u32,
+    surface_id: ObjectId,
+    /// Position of the surface in global coordinates.
+    origin: (i32, i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TouchPoint {
+    id: i32,
+    focus: SurfaceFocus,
+}
+
+/// The surface that receives the key events of a seat, which is the root of a sub-surface tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyboardFocus {
+    pub surface: GlobalObjectId,
+    /// The client that owns the surface, and the id of the surface for it.
+    pub client: u32,
+    pub surface_id: ObjectId,
+}
+
+/// The keymap of a seat and the modifiers and layout of its keys.
+struct Keyboard {
+    keymap: Keymap,
+    state: KeyboardState,
+    modifiers: Modifiers,
+}
+
+impl Keyboard {
+    /// Start from the keys that are already held down.
+    fn new(keymap: Keymap, pressed_keys: &[u32]) -> Self {
+        let mut state = keymap.state();
+        for &key in pressed_keys {
+            state.update_key(key, KeyState::Pressed);
+        }
+        let modifiers = state.modifiers();
+
+        Self {
+            keymap,
+            state,
+            modifiers,
+        }
+    }
+
+    /// Update the state with a key, returning whether the modifiers or layout changed.
+    fn update_key(&mut self, key: u32, state: KeyState) -> bool {
+        self.state.update_key(key, state);
+        let modifiers = self.state.modifiers();
+        let changed = modifiers != self.modifiers;
+        self.modifiers = modifiers;
+        changed
+    }
+}
+
+impl Seat {
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn capabilities(&self) -> Capability {
+        self.capabilities
+    }
+
+    #[inline]
+    pub fn had_capability(&self, capability: Capability) -> bool {
+        self.past_capabilities.contains(capability)
+    }
+
+    #[inline]
+    pub fn keymap(&self) -> Option<&Keymap> {
+        self.keyboard.as_ref().map(|keyboard| &keyboard.keymap)
+    }
+
+    #[inline]
+    pub fn modifiers(&self) -> Option<Modifiers> {
+        self.keyboard.as_ref().map(|keyboard| keyboard.modifiers)
+    }
+
+    #[inline]
+    pub fn pressed_keys(&self) -> &[u32] {
+        &self.pressed_keys
+    }
+
+    /// The keyboard focus, unless the surface was destroyed.
+    pub fn keyboard_focus(&self, registry: &ObjectRegistry) -> Option<KeyboardFocus> {
+        self.keyboard_focus
+            .filter(|focus| registry.get(focus.surface).is_some())
+    }
+
+    /// The device objects of one of the `POINTER`, `KEYBOARD` or `TOUCH` capabilities.
+    pub fn devices(&self, capability: Capability) -> &[DeviceObject] {
+        match capability {
+            Capability::POINTER => &self.pointers,
+            Capability::KEYBOARD => &self.keyboards,
+            Capability::TOUCH => &self.touches,
+            _ => &[],
+        }
+    }
+
+    fn devices_mut(&mut self, capability: Capability) -> Option<&mut Vec<DeviceObject>> {
+        match capability {
+            Capability::POINTER => Some(&mut self.pointers),
+            Capability::KEYBOARD => Some(&mut self.keyboards),
+            Capability::TOUCH => Some(&mut self.touches),
+            _ => None,
+        }
+    }
+
+    /// Track a device object, forgetting objects that were removed with their client.
+    pub fn add_device(
+        &mut self,
+        capability: Capability,
+        device: DeviceObject,
+        registry: &ObjectRegistry,
+    ) {
+        if let Some(devices) = self.devices_mut(capability) {
+            devices.retain(|d| registry.get(d.global_id).is_some());
+            devices.push(device);
+        }
+    }
+
+    pub fn remove_device(&mut self, capability: Capability, client: u32, id: ObjectId) {
+        if let Some(devices) = self.devices_mut(capability) {
+            devices.retain(|d| d.client != client || d.id != id);
+        }
+    }
 }
 
 pub struct InputState {
     seats: SlotMap<SeatId, Seat>,
+    /// `None` if libxkbcommon is not available.
+    xkb: Option<Xkb>,
+    /// Names the keymap of new seats is compiled from.
+    keymap_names: Rmlvo,
 }
 
 impl InputState {
-    pub fn new() -> Self {
+    pub fn new(keymap_names: Rmlvo) -> Self {
+        let xkb = match Xkb::new() {
+            Ok(xkb) => Some(xkb),
+            Err(e) => {
+                log::error!("{}, keyboards will have no keymap", e);
+                None
+            }
+        };
+
         Self {
             seats: SlotMap::with_key(),
+            xkb,
+            keymap_names,
+        }
+    }
+
+    fn compile_keymap(&self, names: &Rmlvo) -> Result<Keymap, XkbError> {
+        match &self.xkb {
+            Some(xkb) => xkb.compile(names),
+            None => Err(XkbError::Compile(names.clone())),
         }
     }
+
+    #[inline]
+    pub fn seat(&self, id: SeatId) -> Option<&Seat> {
+        self.seats.get(id)
+    }
+
+    #[inline]
+    pub fn seat_mut(&mut self, id: SeatId) -> Option<&mut Seat> {
+        self.seats.get_mut(id)
+    }
 }
 
 pub struct InputSink<'a> {
     pub state: &'a mut InputState,
     pub registry: &'a mut ObjectRegistry,
     pub clients: &'a mut Clients,
+    /// Serials for input events must be allocated here so requests can be validated against them.
+    pub serials: &'a mut Serials,
 }
 
 impl<'a> InputSink<'a> {
-    pub fn create_seat(&mut self, capabilities: Capability) -> SeatId {
+    pub fn create_seat(&mut self, name: &str, capabilities: Capability) -> SeatId {
+        let keyboard = match self.state.compile_keymap(&self.state.keymap_names) {
+            Ok(keymap) => Some(Keyboard::new(keymap, &[])),
+            Err(e) => {
+                log::error!("Seat {} has no keymap: {}", name, e);
+                None
+            }
+        };
         let seat = Seat {
+            name: name.to_owned(),
             capabilities,
+            past_capabilities: capabilities,
             object_id: GlobalObjectId::default(),
+            pointers: vec![],
+            keyboards: vec![],
+            touches: vec![],
+            pointer_position: (0.0, 0.0),
+            pressed_buttons: vec![],
+            pointer_focus: None,
+            pressed_keys: vec![],
+            keyboard_focus: None,
+            touch_points: vec![],
+            touch_clients: vec![],
+            keyboard,
         };
         let seat_id = self.state.seats.insert(seat);
 
         let wl_seat = WlSeat { id: seat_id };
         let object_id = self.registry.insert(Interface::WlSeat(wl_seat));
-        self.registry
-            .make_global(object_id, self.clients)
-            .expect("send buffer full");
+        self.registry.make_global(object_id, self.clients);
         self.state.seats.get_mut(seat_id).unwrap().object_id = object_id;
 
         seat_id
     }
 
+    /// Change the capabilities of a seat and announce them to the clients that bound it.
+    pub fn set_capabilities(&mut self, id: SeatId, capabilities: Capability) {
+        let seat = match self.state.seats.get_mut(id) {
+            Some(seat) if seat.capabilities != capabilities => seat,
+            _ => return,
+        };
+        seat.capabilities = capabilities;
+        seat.past_capabilities |= capabilities;
+
+        self.clients
+            .for_each_bound(seat.object_id, |send_buf, object_id, _| {
+                wl_seat::emit_capabilities(send_buf, object_id, capabilities)
+            });
+    }
+
+    /// Switch the keymap of a seat and send it to all of its keyboards.
+    pub fn set_keymap(&mut self, id: SeatId, names: &Rmlvo) -> Result<(), XkbError> {
+        let keymap = self.state.compile_keymap(names)?;
+        let seat = match self.state.seats.get_mut(id) {
+            Some(seat) => seat,
+            None => return Ok(()),
+        };
+        seat.keyboard = Some(Keyboard::new(keymap, &seat.pressed_keys));
+        log::info!("Switched keymap of seat {} to {}", seat.name, names);
+
+        let keymap = seat.keymap();
+        for device in live_devices(seat, Capability::KEYBOARD, self.registry) {
+            if let Some(client) = self.clients.get_mut(device.client) {
+                let send_buf = client.stream_mut().send_buf_mut();
+                if let Err(e) = send_keymap(send_buf, device.id, device.version, keymap) {
+                    log::warn!("Failed to send keymap to client {}: {}", client.info(), e);
+                }
+                self.clients.mark_dirty(device.client);
+            }
+        }
+        self.send_modifiers(id);
+
+        Ok(())
+    }
+
+    /// Switch all seats to the keymap compiled from `names`, which new seats use as well.
+    /// Nothing changes if the keymap fails to compile.
+    pub fn set_keymaps(&mut self, names: Rmlvo) -> Result<(), XkbError> {
+        self.state.compile_keymap(&names)?;
+        let ids: Vec<_> = self.state.seats.keys().collect();
+        for id in ids {
+            self.set_keymap(id, &names)?;
+        }
+        self.state.keymap_names = names;
+
+        Ok(())
+    }
+
+    /// Send a key to the keyboards of the focused client.
+    fn send_key(&mut self, id: SeatId, key: u32, state: KeyState) {
+        let focus = match self
+            .state
+            .seats
+            .get(id)
+            .and_then(|seat| seat.keyboard_focus(self.registry))
+        {
+            Some(focus) => focus,
+            None => return,
+        };
+
+        let serial = self.serials.next(SerialEvent {
+            seat: Some(id),
+            device: Some(InputDevice::Keyboard),
+            client_id: focus.client,
+            surface: Some(focus.surface),
+        });
+        let time = timestamp();
+        self.send_to_devices(
+            id,
+            Capability::KEYBOARD,
+            focus.client,
+            |send_buf, device| wl_keyboard::emit_key(send_buf, device.id, serial, time, key, state),
+        );
+    }
+
+    /// Send the modifiers of a seat to the keyboards of the focused client.
+    fn send_modifiers(&mut self, id: SeatId) {
+        let (focus, modifiers) = match self.state.seats.get(id) {
+            Some(seat) => match (seat.keyboard_focus(self.registry), &seat.keyboard) {
+                (Some(focus), Some(keyboard)) => (focus, keyboard.modifiers),
+                _ => return,
+            },
+            None => return,
+        };
+
+        let serial = self.serials.next(SerialEvent {
+            seat: Some(id),
+            device: Some(InputDevice::Keyboard),
+            client_id: focus.client,
+            surface: Some(focus.surface),
+        });
+        self.send_to_devices(
+            id,
+            Capability::KEYBOARD,
+            focus.client,
+            |send_buf, device| {
+                wl_keyboard::emit_modifiers(
+                    send_buf,
+                    device.id,
+                    serial,
+                    modifiers.depressed,
+                    modifiers.latched,
+                    modifiers.locked,
+                    modifiers.group,
+                )
+            },
+        );
+    }
+
+    /// Move the keyboard focus, sending the new surface the keys that are held and the
+    /// modifiers.
+    fn set_keyboard_focus(&mut self, id: SeatId, new: Option<KeyboardFocus>) {
+        let seat = match self.state.seats.get_mut(id) {
+            Some(seat) => seat,
+            None => return,
+        };
+        let old = seat.keyboard_focus(self.registry);
+        seat.keyboard_focus = new;
+        if old == new {
+            return;
+        }
+        let keys = seat.pressed_keys.clone();
+
+        if let Some(old) = old {
+            let serial = self.serials.next(SerialEvent {
+                seat: Some(id),
+                device: Some(InputDevice::Keyboard),
+                client_id: old.client,
+                surface: Some(old.surface),
+            });
+            self.send_to_devices(id, Capability::KEYBOARD, old.client, |send_buf, device| {
+                wl_keyboard::emit_leave(send_buf, device.id, serial, old.surface_id)
+            });
+        }
+        if let Some(new) = new {
+            let serial = self.serials.next(SerialEvent {
+                seat: Some(id),
+                device: Some(InputDevice::Keyboard),
+                client_id: new.client,
+                surface: Some(new.surface),
+            });
+            self.send_to_devices(id, Capability::KEYBOARD, new.client, |send_buf, device| {
+                let keys = bytemuck::cast_slice(&keys);
+                wl_keyboard::emit_enter(send_buf, device.id, serial, new.surface_id, keys)
+            });
+            self.send_modifiers(id);
+        }
+    }
+
     pub fn destroy_seat(&mut self, id: SeatId) {
         if let Some(seat) = self.state.seats.remove(id) {
-            self.registry
-                .remove_global(seat.object_id, self.clients)
-                .expect("send buffer full");
+            self.registry.remove_global(seat.object_id, self.clients);
+        }
+    }
+
+    pub fn handle_event(&mut self, id: SeatId, event: InputEvent) {
+        let seat = match self.state.seats.get_mut(id) {
+            Some(seat) => seat,
+            None => return,
+        };
+        log::trace!("Input event: {:?}", event);
+
+        match event {
+            InputEvent::PointerMotion { dx, dy } => {
+                let (x, y) = seat.pointer_position;
+                seat.pointer_position = (x + dx, y + dy);
+                self.pointer_motion(id);
+            }
+            InputEvent::PointerMotionAbsolute { x, y } => {
+                seat.pointer_position = (x, y);
+                self.pointer_motion(id);
+            }
+            // Only the relative pointer protocol reports raw motion, which is not implemented.
+            InputEvent::RawPointerMotion { .. } => (),
+            InputEvent::PointerButton { button, state } => {
+                let was_pressed = seat.pressed_buttons.contains(&button);
+                seat.pressed_buttons.retain(|&pressed| pressed != button);
+                if state == ButtonState::Pressed {
+                    seat.pressed_buttons.push(button);
+                }
+
+                if was_pressed != (state == ButtonState::Pressed) {
+                    self.pointer_button(id, button, state);
+                }
+            }
+            InputEvent::PointerAxis {
+                axis,
+                value,
+                discrete,
+            } => self.pointer_axis(id, axis, value, discrete),
+            InputEvent::Key { key, state } => {
+                let was_pressed = seat.pressed_keys.contains(&key);
+                seat.pressed_keys.retain(|&pressed| pressed != key);
+                if state == KeyState::Pressed {
+                    seat.pressed_keys.push(key);
+                }
+
+                // Repeated presses and releases of keys that are not held would unbalance the
+                // key counts of the xkb state, and clients repeat keys themselves.
+                if was_pressed == (state == KeyState::Pressed) {
+                    return;
+                }
+                let changed = seat
+                    .keyboard
+                    .as_mut()
+                    .is_some_and(|keyboard| keyboard.update_key(key, state));
+                self.send_key(id, key, state);
+                if changed {
+                    self.send_modifiers(id);
+                }
+            }
+            InputEvent::Focus { focused: false } => {
+                let keys = std::mem::take(&mut seat.pressed_keys);
+                let mut changed = false;
+                for &key in &keys {
+                    if let Some(keyboard) = &mut seat.keyboard {
+                        changed |= keyboard.update_key(key, KeyState::Released);
+                    }
+                }
+                for key in keys {
+                    self.send_key(id, key, KeyState::Released);
+                }
+                if changed {
+                    self.send_modifiers(id);
+                }
+            }
+            InputEvent::Focus { focused: true } => (),
+            InputEvent::TouchDown { id: point, x, y } => self.touch_down(id, point, (x, y)),
+            InputEvent::TouchMotion { id: point, x, y } => self.touch_motion(id, point, (x, y)),
+            InputEvent::TouchUp { id: point } => self.touch_up(id, point),
+            InputEvent::TouchFrame => self.touch_frame(id),
+            InputEvent::TouchCancel => self.touch_cancel(id),
+        }
+    }
+
+    /// Move the pointer focus to the surface under the pointer and send it the motion.
+    fn pointer_motion(&mut self, id: SeatId) {
+        if self.update_pointer_focus(id) {
+            // The new surface was told about the position on enter.
+            return;
+        }
+
+        let (focus, position) = match self.state.seats.get(id) {
+            Some(seat) => match seat.pointer_focus {
+                Some(focus) => (focus, seat.pointer_position),
+                None => return,
+            },
+            None => return,
+        };
+        let (x, y) = surface_position(position, focus.origin);
+        let time = timestamp();
+        self.send_to_pointers(id, focus, |send_buf, device| {
+            wl_pointer::emit_motion(send_buf, device.id, time, x, y)
+        });
+    }
+
+    fn pointer_button(&mut self, id: SeatId, button: u32, state: ButtonState) {
+        let pointer_focus = self.live_pointer_focus(id);
+        if let Some(focus) = pointer_focus.filter(|_| state == ButtonState::Pressed) {
+            let root = surface::root(self.registry, focus.surface);
+            if let Some((client, surface_id)) = self.clients.find_owner(root) {
+                let focus = KeyboardFocus {
+                    surface: root,
+                    client,
+                    surface_id,
+                };
+                self.set_keyboard_focus(id, Some(focus));
+            }
         }
+
+        if let Some(focus) = pointer_focus {
+            let serial = self.serials.next(SerialEvent {
+                seat: Some(id),
+                device: Some(InputDevice::Pointer),
+                client_id: focus.client,
+                surface: Some(focus.surface),
+            });
+            let time = timestamp();
+            self.send_to_pointers(id, focus, |send_buf, device| {
+                wl_pointer::emit_button(send_buf, device.id, serial, time, button, state)
+            });
+        }
+
+        // Releasing the last button ends the grab of the focused surface.
+        if state == ButtonState::Released {
+            self.update_pointer_focus(id);
+        }
+    }
+
+    fn pointer_axis(&mut self, id: SeatId, axis: Axis, value: f64, discrete: Option<i32>) {
+        let focus = match self.live_pointer_focus(id) {
+            Some(focus) => focus,
+            None => return,
+        };
+        let time = timestamp();
+        let value = I24F8::saturating_from_num(value);
+        self.send_to_pointers(id, focus, |send_buf, device| {
+            if let Some(discrete) = discrete {
+                wl_pointer::emit_axis_discrete(
+                    send_buf,
+                    device.id,
+                    device.version,
+                    axis,
+                    discrete,
+                )?;
+            }
+            wl_pointer::emit_axis(send_buf, device.id, time, axis, value)
+        });
+    }
+
+    /// The pointer focus of a seat, unless the surface was destroyed.
+    fn live_pointer_focus(&self, id: SeatId) -> Option<SurfaceFocus> {
+        self.state
+            .seats
+            .get(id)?
+            .pointer_focus
+            .filter(|focus| self.registry.get(focus.surface).is_some())
     }
+
+    /// Focus the topmost surface under the pointer, unless a button is held while a surface
+    /// is focused. Returns whether the focused surface changed.
+    ///
+    /// The focus only follows changes of the scene when the pointer moves or a button is
+    /// released.
+    fn update_pointer_focus(&mut self, id: SeatId) -> bool {
+        let old = self.live_pointer_focus(id);
+        let seat = match self.state.seats.get_mut(id) {
+            Some(seat) => seat,
+            None => return false,
+        };
+        if old.is_some() && !seat.pressed_buttons.is_empty() {
+            return false;
+        }
+
+        let target = render::surface_at(self.registry, seat.pointer_position);
+        if let (Some(old), Some((surface, origin))) = (old, target) {
+            if old.surface == surface {
+                // Sub-surfaces move with their parent.
+                seat.pointer_focus = Some(SurfaceFocus { origin, ..old });
+                return false;
+            }
+        }
+        let new = target.and_then(|(surface, origin)| {
+            let (client, surface_id) = self.clients.find_owner(surface)?;
+            Some(SurfaceFocus {
+                surface,
+                client,
+                surface_id,
+                origin,
+            })
+        });
+        if old.is_none() && new.is_none() {
+            seat.pointer_focus = None;
+            return false;
+        }
+        let position = seat.pointer_position;
+        seat.pointer_focus = new;
+
+        if let Some(old) = old {
+            let serial = self.serials.next(SerialEvent {
+                seat: Some(id),
+                device: Some(InputDevice::Pointer),
+                client_id: old.client,
+                surface: Some(old.surface),
+            });
+            self.send_to_pointers(id, old, |send_buf, device| {
+                wl_pointer::emit_leave(send_buf, device.id, serial, old.surface_id)
+            });
+        }
+        if let Some(new) = new {
+            let serial = self.serials.next(SerialEvent {
+                seat: Some(id),
+                device: Some(InputDevice::Pointer),
+                client_id: new.client,
+                surface: Some(new.surface),
+            });
+            let (x, y) = surface_position(position, new.origin);
+            self.send_to_pointers(id, new, |send_buf, device| {
+                wl_pointer::emit_enter(send_buf, device.id, serial, new.surface_id, x, y)
+            });
+        }
+
+        true
+    }
+
+    /// Send a touch point to the topmost surface under it, which receives the rest of its
+    /// events.
+    fn touch_down(&mut self, id: SeatId, point: i32, position: (f64, f64)) {
+        let target = render::surface_at(self.registry, position).and_then(|(surface, origin)| {
+            let (client, surface_id) = self.clients.find_owner(surface)?;
+            Some(SurfaceFocus {
+                surface,
+                client,
+                surface_id,
+                origin,
+            })
+        });
+        let seat = match self.state.seats.get_mut(id) {
+            Some(seat) => seat,
+            None => return,
+        };
+        // A point that goes down again without going up first starts over.
+        seat.touch_points.retain(|touch| touch.id != point);
+        let focus = match target {
+            Some(focus) => focus,
+            None => return,
+        };
+        seat.touch_points.push(TouchPoint { id: point, focus });
+
+        let serial = self.serials.next(SerialEvent {
+            seat: Some(id),
+            device: Some(InputDevice::Touch),
+            client_id: focus.client,
+            surface: Some(focus.surface),
+        });
+        let time = timestamp();
+        let (x, y) = surface_position(position, focus.origin);
+        self.send_to_touches(id, focus.client, |send_buf, device| {
+            wl_touch::emit_down(
+                send_buf,
+                device.id,
+                serial,
+                time,
+                focus.surface_id,
+                point,
+                x,
+                y,
+            )
+        });
+    }
+
+    fn touch_motion(&mut self, id: SeatId, point: i32, position: (f64, f64)) {
+        let focus = match self.touch_focus(id, point) {
+            Some(focus) => focus,
+            None => return,
+        };
+        let time = timestamp();
+        let (x, y) = surface_position(position, focus.origin);
+        self.send_to_touches(id, focus.client, |send_buf, device| {
+            wl_touch::emit_motion(send_buf, device.id, time, point, x, y)
+        });
+    }
+
+    fn touch_up(&mut self, id: SeatId, point: i32) {
+        let focus = self.touch_focus(id, point);
+        if let Some(seat) = self.state.seats.get_mut(id) {
+            seat.touch_points.retain(|touch| touch.id != point);
+        }
+        let focus = match focus {
+            Some(focus) => focus,
+            None => return,
+        };
+
+        let serial = self.serials.next(SerialEvent {
+            seat: Some(id),
+            device: Some(InputDevice::Touch),
+            client_id: focus.client,
+            surface: Some(focus.surface),
+        });
+        let time = timestamp();
+        self.send_to_touches(id, focus.client, |send_buf, device| {
+            wl_touch::emit_up(send_buf, device.id, serial, time, point)
+        });
+    }
+
+    /// End the group of touch events that the clients were sent since the last frame.
+    fn touch_frame(&mut self, id: SeatId) {
+        let clients = match self.state.seats.get_mut(id) {
+            Some(seat) => std::mem::take(&mut seat.touch_clients),
+            None => return,
+        };
+        for client in clients {
+            self.send_to_devices(id, Capability::TOUCH, client, |send_buf, device| {
+                wl_touch::emit_frame(send_buf, device.id)
+            });
+        }
+    }
+
+    /// Drop all touch points, the clients that were sent any must forget them.
+    fn touch_cancel(&mut self, id: SeatId) {
+        let mut clients = match self.state.seats.get_mut(id) {
+            Some(seat) => {
+                let mut clients = std::mem::take(&mut seat.touch_clients);
+                clients.extend(seat.touch_points.drain(..).map(|touch| touch.focus.client));
+                clients
+            }
+            None => return,
+        };
+        clients.sort_unstable();
+        clients.dedup();
+        for client in clients {
+            self.send_to_devices(id, Capability::TOUCH, client, |send_buf, device| {
+                wl_touch::emit_cancel(send_buf, device.id)
+            });
+        }
+    }
+
+    /// The surface a touch point went down on, unless it was destroyed.
+    fn touch_focus(&self, id: SeatId, point: i32) -> Option<SurfaceFocus> {
+        self.state
+            .seats
+            .get(id)?
+            .touch_points
+            .iter()
+            .find(|touch| touch.id == point)
+            .map(|touch| touch.focus)
+            .filter(|focus| self.registry.get(focus.surface).is_some())
+    }
+
+    /// Send events to the touch devices that `client` created, which are followed by a
+    /// `wl_touch.frame` with the next frame of the seat.
+    fn send_to_touches(
+        &mut self,
+        id: SeatId,
+        client: u32,
+        emit: impl FnMut(&mut MessageBuf<Write>, &DeviceObject) -> Result<(), MessageError>,
+    ) {
+        if let Some(seat) = self.state.seats.get_mut(id) {
+            if !seat.touch_clients.contains(&client) {
+                seat.touch_clients.push(client);
+            }
+        }
+        self.send_to_devices(id, Capability::TOUCH, client, emit);
+    }
+
+    /// Send events to the pointers that the client of `focus` created, each followed by a
+    /// `wl_pointer.frame`.
+    fn send_to_pointers(
+        &mut self,
+        id: SeatId,
+        focus: SurfaceFocus,
+        mut emit: impl FnMut(&mut MessageBuf<Write>, &DeviceObject) -> Result<(), MessageError>,
+    ) {
+        self.send_to_devices(id, Capability::POINTER, focus.client, |send_buf, device| {
+            emit(send_buf, device)?;
+            wl_pointer::emit_frame(send_buf, device.id, device.version)
+        });
+    }
+
+    /// Send events to the devices of one capability that `client` created.
+    fn send_to_devices(
+        &mut self,
+        id: SeatId,
+        capability: Capability,
+        client_id: u32,
+        mut emit: impl FnMut(&mut MessageBuf<Write>, &DeviceObject) -> Result<(), MessageError>,
+    ) {
+        let (seat, client) = match (self.state.seats.get(id), self.clients.get_mut(client_id)) {
+            (Some(seat), Some(client)) => (seat, client),
+            _ => return,
+        };
+
+        let send_buf = client.stream_mut().send_buf_mut();
+        let res = live_devices(seat, capability, self.registry)
+            .filter(|device| device.client == client_id)
+            .try_for_each(|device| emit(send_buf, device));
+        if let Err(e) = res {
+            log::warn!(
+                "Failed to send {:?} event to client {}: {}",
+                capability,
+                client.info(),
+                e
+            );
+        }
+        self.clients.mark_dirty(client_id);
+    }
+}
+
+/// Position of a point in global coordinates relative to a surface at `origin`.
+fn surface_position((x, y): (f64, f64), (sx, sy): (i32, i32)) -> (I24F8, I24F8) {
+    (
+        I24F8::saturating_from_num(x - f64::from(sx)),
+        I24F8::saturating_from_num(y - f64::from(sy)),
+    )
+}
+
+/// The device objects of a seat whose client is still connected.
+fn live_devices<'a>(
+    seat: &'a Seat,
+    capability: Capability,
+    registry: &'a ObjectRegistry,
+) -> impl Iterator<Item = &'a DeviceObject> + 'a {
+    seat.devices(capability)
+        .iter()
+        .filter(move |device| registry.get(device.global_id).is_some())
+}
+
+/// Send the keymap and repeat rate of a seat to a keyboard. Without a keymap, clients are told
+/// to interpret the raw keycodes themselves.
+pub fn send_keymap(
+    send_buf: &mut MessageBuf<Write>,
+    id: ObjectId,
+    version: u32,
+    keymap: Option<&Keymap>,
+) -> Result<(), MessageError> {
+    let (format, fd, size) = match keymap {
+        Some(keymap) => (KeymapFormat::XkbV1, keymap.fd(), keymap.size()),
+        None => (
+            KeymapFormat::NoKeymap,
+            File::open("/dev/null").map(OwnedFd::from),
+            0,
+        ),
+    };
+    wl_keyboard::emit_keymap(send_buf, id, format, fd?, size)?;
+    wl_keyboard::emit_repeat_info(send_buf, id, version, REPEAT_RATE, REPEAT_DELAY)
 }
@@ -0,0 +1,14 @@
+//! A Wayland compositor. The binary runs a `Gateway` on the backend chosen on the command
+//! line, embedders and tests can drive one themselves, e.g. on the `Headless` backend.
+#![feature(core_intrinsics)]
+
+pub mod backend;
+pub mod gateway;
+pub mod input;
+mod output;
+pub mod protocol;
+pub mod region;
+pub mod render;
+mod shm;
+mod surface;
+pub mod xkb;
@@ -1,17 +1,188 @@
-#![feature(core_intrinsics)]
+use carbon::{
+    backend::{self, Backend, OutputMode},
+    gateway::{
+        self,
+        signal::ShutdownSignals,
+        socket::{ListeningSocket, SocketConfig},
+        PingConfig,
+    },
+    protocol,
+    xkb::Rmlvo,
+};
 
-mod backend;
-mod gateway;
-mod input;
-mod protocol;
+use std::{
+    env,
+    num::NonZeroU32,
+    os::unix::prelude::*,
+    process::{self, ExitCode},
+    time::Duration,
+};
 
-fn main() {
+/// Refresh rate in mHz of outputs whose backend does not know it.
+const DEFAULT_REFRESH: NonZeroU32 = NonZeroU32::new(60_000).unwrap();
+
+fn main() -> ExitCode {
     env_logger::init();
+    protocol::trace::init_from_env();
 
     log::info!("Starting carbon...");
 
-    let backend = backend::Winit::new();
+    let (socket_config, ping_config, refresh, headless_outputs, keymap_names) = parse_args();
+    // Block the signals and export the socket before the backend spawns any threads, the
+    // environment must not be modified afterwards.
+    let signals = ShutdownSignals::new();
+    let listener = ListeningSocket::new(socket_config);
+    if let Some(name) = listener.display_name() {
+        env::set_var("WAYLAND_DISPLAY", name);
+    }
+
+    if headless_outputs.is_empty() {
+        let backend = backend::Winit::new();
+        run(
+            backend,
+            listener,
+            ping_config,
+            refresh,
+            keymap_names,
+            signals,
+        )
+    } else {
+        let backend = backend::Headless::new(headless_outputs);
+        run(
+            backend,
+            listener,
+            ping_config,
+            refresh,
+            keymap_names,
+            signals,
+        )
+    }
+}
+
+fn run<B: Backend>(
+    backend: B,
+    listener: ListeningSocket,
+    ping_config: PingConfig,
+    refresh: NonZeroU32,
+    keymap_names: Rmlvo,
+    signals: ShutdownSignals,
+) -> ExitCode {
+    let mut gateway = gateway::Gateway::new(
+        backend,
+        listener,
+        ping_config,
+        refresh,
+        keymap_names,
+        signals,
+    );
+    gateway.run()
+}
+
+fn parse_args() -> (SocketConfig, PingConfig, NonZeroU32, Vec<OutputMode>, Rmlvo) {
+    let mut args = env::args().skip(1);
+    let mut config = None;
+    let mut ping_config = PingConfig::default();
+    let mut refresh = DEFAULT_REFRESH;
+    let mut headless_outputs = vec![];
+    let mut keymap_names = Rmlvo::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" => match args.next() {
+                Some(name) if !name.is_empty() => config = Some(SocketConfig::Name(name.into())),
+                _ => usage_error("--socket requires a socket name or path"),
+            },
+            "--listen-fd" => match args.next().and_then(|fd| fd.parse::<RawFd>().ok()) {
+                // Safety: the fd was passed to us by the parent process to take ownership of.
+                Some(fd) if fd >= 0 => {
+                    config = Some(SocketConfig::Fd(unsafe { OwnedFd::from_raw_fd(fd) }))
+                }
+                _ => usage_error("--listen-fd requires a file descriptor number"),
+            },
+            "--ping-interval" => match args.next().and_then(|ms| ms.parse::<u64>().ok()) {
+                Some(ms) if ms > 0 => ping_config.interval = Duration::from_millis(ms),
+                _ => usage_error("--ping-interval requires a positive number of milliseconds"),
+            },
+            "--ping-timeout" => match args.next().and_then(|ms| ms.parse::<u64>().ok()) {
+                Some(ms) if ms > 0 => ping_config.timeout = Duration::from_millis(ms),
+                _ => usage_error("--ping-timeout requires a positive number of milliseconds"),
+            },
+            "--refresh" => match args
+                .next()
+                .and_then(|hz| hz.parse::<f64>().ok())
+                .filter(|hz| (1.0..=1000.0).contains(hz))
+                .and_then(|hz| NonZeroU32::new((hz * 1000.0).round() as u32))
+            {
+                Some(hz) => refresh = hz,
+                _ => usage_error("--refresh requires a refresh rate between 1 and 1000 Hz"),
+            },
+            "--headless" => match args.next().as_deref().and_then(parse_mode) {
+                Some(mode) => headless_outputs.push(mode),
+                _ => {
+                    usage_error("--headless requires an output mode like 1920x1080 or 1920x1080@60")
+                }
+            },
+            "--xkb-rules" => keymap_names.rules = xkb_name(&arg, args.next()),
+            "--xkb-model" => keymap_names.model = xkb_name(&arg, args.next()),
+            "--xkb-layout" => keymap_names.layout = xkb_name(&arg, args.next()),
+            "--xkb-variant" => keymap_names.variant = xkb_name(&arg, args.next()),
+            "--xkb-options" => keymap_names.options = xkb_name(&arg, args.next()),
+            _ => usage_error(&format!("Unknown argument {}", arg)),
+        }
+    }
+
+    let socket_config = config
+        .or_else(SocketConfig::from_listen_fds)
+        .unwrap_or(SocketConfig::Auto);
+
+    (
+        socket_config,
+        ping_config,
+        refresh,
+        headless_outputs,
+        keymap_names,
+    )
+}
+
+/// The value of an `--xkb-*` argument, which may be empty to use the default.
+fn xkb_name(arg: &str, value: Option<String>) -> String {
+    match value {
+        Some(value) => value,
+        None => usage_error(&format!("{} requires a name", arg)),
+    }
+}
+
+/// Parse `<width>x<height>[@<hz>]`, a missing refresh rate is left unknown.
+fn parse_mode(mode: &str) -> Option<OutputMode> {
+    let (size, hz) = match mode.split_once('@') {
+        Some((size, hz)) => (size, Some(hz.parse::<f64>().ok()?)),
+        None => (mode, None),
+    };
+    let (width, height) = size.split_once('x')?;
+    let (width, height) = (width.parse::<i32>().ok()?, height.parse::<i32>().ok()?);
+    if !(1..=16384).contains(&width) || !(1..=16384).contains(&height) {
+        return None;
+    }
+
+    let refresh = match hz {
+        Some(hz) if (1.0..=1000.0).contains(&hz) => (hz * 1000.0).round() as u32,
+        Some(_) => return None,
+        None => 0,
+    };
+    Some(OutputMode {
+        width,
+        height,
+        refresh,
+    })
+}
 
-    let mut gateway = gateway::Gateway::new(backend);
-    gateway.run();
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!(
+        "Usage: carbon [--socket <name or path>] [--listen-fd <fd>] [--ping-interval <ms>] \
+         [--ping-timeout <ms>] [--refresh <hz>] [--headless <width>x<height>[@<hz>]]... \
+         [--xkb-rules <rules>] [--xkb-model <model>] [--xkb-layout <layouts>] \
+         [--xkb-variant <variants>] [--xkb-options <options>]"
+    );
+    process::exit(2);
 }
@@ -0,0 +1,121 @@
+//! Outputs frames are presented on, each with a clock that times its frames.
+
+use crate::{backend::OutputMode, gateway::event_loop::SourceId};
+
+use slotmap::{new_key_type, SlotMap};
+
+use std::{
+    num::NonZeroU32,
+    time::{Duration, Instant},
+};
+
+new_key_type! { pub struct OutputId; }
+
+pub struct Output {
+    pub mode: OutputMode,
+    /// Time between two refreshes of the output.
+    pub refresh_interval: Duration,
+    /// Timer that drives the frame clock, `None` if the backend reports refreshes.
+    pub timer: Option<SourceId>,
+    /// Whether the timer is armed.
+    pub ticking: bool,
+    /// A client committed a surface since the last frame.
+    pub frame_requested: bool,
+    /// When the last frame was presented, the timer keeps in phase with it.
+    pub last_frame: Option<Instant>,
+}
+
+impl Output {
+    /// Create an output, refreshing at `fallback_refresh` mHz if the backend does not know
+    /// the refresh rate.
+    pub fn new(mode: OutputMode, fallback_refresh: NonZeroU32) -> Self {
+        let refresh = NonZeroU32::new(mode.refresh).unwrap_or(fallback_refresh);
+
+        Self {
+            mode,
+            refresh_interval: Duration::from_nanos(1_000_000_000_000 / u64::from(refresh.get())),
+            timer: None,
+            ticking: false,
+            frame_requested: false,
+            last_frame: None,
+        }
+    }
+
+    /// Switch to a new mode, keeping the refresh interval if the refresh rate is unknown.
+    pub fn set_mode(&mut self, mode: OutputMode) {
+        if mode.refresh != 0 {
+            self.refresh_interval =
+                Duration::from_nanos(1_000_000_000_000 / u64::from(mode.refresh));
+        }
+        self.mode = mode;
+        self.frame_requested = true;
+    }
+
+    /// Time from `now` until the next refresh in phase with the last frame.
+    pub fn until_next_refresh(&self, now: Instant) -> Duration {
+        let last = match self.last_frame {
+            Some(last) => last,
+            None => return Duration::ZERO,
+        };
+
+        let interval = self.refresh_interval.as_nanos();
+        let elapsed = now.saturating_duration_since(last).as_nanos();
+        if elapsed >= interval * 2 {
+            // The clock was idle, no need to wait for a refresh that is not shown anyway.
+            Duration::ZERO
+        } else {
+            Duration::from_nanos((interval - elapsed % interval) as u64)
+        }
+    }
+}
+
+pub struct Outputs {
+    outputs: SlotMap<OutputId, Output>,
+    /// In the order the backend reported them.
+    order: Vec<OutputId>,
+}
+
+impl Outputs {
+    pub fn new() -> Self {
+        Self {
+            outputs: SlotMap::with_key(),
+            order: vec![],
+        }
+    }
+
+    pub fn insert(&mut self, output: Output) -> OutputId {
+        let id = self.outputs.insert(output);
+        self.order.push(id);
+        id
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, id: OutputId) -> Option<&mut Output> {
+        self.outputs.get_mut(id)
+    }
+
+    /// The output at an index reported by the backend.
+    #[inline]
+    pub fn by_index(&self, idx: usize) -> Option<OutputId> {
+        self.order.get(idx).copied()
+    }
+
+    /// The index the backend knows an output by.
+    #[inline]
+    pub fn index(&self, id: OutputId) -> Option<usize> {
+        self.order.iter().position(|&other| other == id)
+    }
+
+    #[inline]
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (OutputId, &mut Output)> {
+        self.outputs.iter_mut()
+    }
+
+    /// Ask for a new frame on all outputs, since surfaces are not placed on outputs yet.
+    #[inline]
+    pub fn request_frame(&mut self) {
+        for output in self.outputs.values_mut() {
+            output.frame_requested = true;
+        }
+    }
+}
@@ -1,11 +1,18 @@
-use crate::gateway::{
-    message::{FdSource, MessageBuf, MessageError, Write},
-    registry::{ClientObjects, ObjectRegistry},
+use crate::{
+    gateway::{
+        client::{ClientInfo, PingState},
+        message::{FdSource, MessageBuf, MessageError, Write},
+        registry::{ClientObjects, GlobalObjectId, ObjectId, ObjectRegistry},
+        serial::{InputDevice, SerialEvent, Serials},
+    },
+    input::InputState,
+    output::Outputs,
 };
 
 use std::intrinsics::discriminant_value;
 
 mod generated;
+pub mod trace;
 pub use generated::Interface;
 
 mod wayland;
@@ -37,11 +44,6 @@ impl Interface {
             .and_then(|f| f(self, args, state))
     }
 
-    #[inline]
-    pub fn id(&self) -> u32 {
-        discriminant_value(self) as u32
-    }
-
     #[inline]
     pub fn name(&self) -> &'static str {
         generated::INTERFACE_NAMES[discriminant_value(self) as usize]
@@ -54,8 +56,174 @@ impl Interface {
 }
 
 pub struct DispatchState<'a> {
+    /// The object the request being dispatched was sent to.
+    pub self_id: ObjectId,
+    /// The version of `self_id`, which is inherited by objects created by the request.
+    pub version: u32,
     pub fds: FdSource<'a>,
     pub send_buf: &'a mut MessageBuf<Write>,
     pub registry: &'a mut ObjectRegistry,
+    pub serials: &'a mut Serials,
+    pub input: &'a mut InputState,
+    /// Outputs to request frames on when surfaces change.
+    pub outputs: &'a mut Outputs,
     pub objects: &'a mut ClientObjects,
+    /// Credentials of the client that sent the request.
+    pub client: &'a ClientInfo,
+    pub ping: &'a mut PingState,
+}
+
+impl<'a> DispatchState<'a> {
+    /// Insert a new object into the registry and register it under the client allocated `id`,
+    /// with the same version as the object the request was sent to.
+    pub fn create_object(
+        &mut self,
+        id: ObjectId,
+        object: Interface,
+    ) -> Result<GlobalObjectId, MessageError> {
+        let global_id = self.registry.insert(object);
+        if let Err(e) = self.objects.register(id, global_id, self.version) {
+            self.registry.remove(global_id);
+            return Err(e);
+        }
+
+        Ok(global_id)
+    }
+
+    /// Insert a new object into the registry and give it a server allocated id, which can be
+    /// passed as `new_id` argument to an event.
+    pub fn create_server_object(&mut self, object: Interface) -> Result<ObjectId, MessageError> {
+        let global_id = self.registry.insert(object);
+        let res = self.objects.allocate(global_id, self.version);
+        if res.is_err() {
+            self.registry.remove(global_id);
+        }
+
+        res
+    }
+
+    /// Look up an object passed as argument by the client, a nonexistent object is reported
+    /// as `wl_display.invalid_object` on the object the request was sent to.
+    pub fn object(&self, id: ObjectId) -> Result<(GlobalObjectId, &Interface), MessageError> {
+        self.objects
+            .get(id)
+            .and_then(|global_id| self.registry.get(global_id).map(|o| (global_id, o)))
+            .ok_or_else(|| {
+                wl_display::Error::InvalidObject
+                    .into_message_error(self.self_id, format!("invalid object {}", id.raw()))
+            })
+    }
+
+    /// Check that a serial sent by the client was issued to it, for an event from `device` if
+    /// given. Returns `None` for spoofed or stale serials.
+    pub fn validate_serial(
+        &self,
+        serial: u32,
+        device: Option<InputDevice>,
+    ) -> Option<&SerialEvent> {
+        self.serials
+            .validate(serial, self.send_buf.client_id(), device)
+    }
+
+    /// Destroy the client object `id` and, if the id was allocated by the client, acknowledge
+    /// it with `wl_display.delete_id`.
+    ///
+    /// The registry entry is removed if the object was owned by the client.
+    pub fn destroy_object(&mut self, id: ObjectId) -> Result<(), MessageError> {
+        if let Some(global_id) = self.objects.unregister(id)? {
+            self.registry.remove(global_id);
+        }
+
+        if id.is_server_allocated() {
+            Ok(())
+        } else {
+            wl_display::emit_delete_id(self.send_buf, ObjectId::display(), id.raw())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        gateway::{message::MessageStream, registry::SERVER_ID_START},
+        xkb::Rmlvo,
+    };
+    use nix::{
+        sys::socket::{socketpair, AddressFamily, SockFlag, SockType},
+        unistd::{read, write},
+    };
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+    #[test]
+    fn server_objects_are_sent_as_new_id() {
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )
+        .unwrap();
+        // Safety: the fds were just created, nothing else owns them.
+        let (a, peer) = unsafe { (OwnedFd::from_raw_fd(a), OwnedFd::from_raw_fd(b)) };
+        let mut stream = MessageStream::new(a, 1);
+
+        let mut registry = ObjectRegistry::new();
+        let display_id = registry.insert(Interface::WlDisplay(WlDisplay));
+        let mut objects = ClientObjects::new(display_id);
+        let device = ObjectId::new(2).unwrap();
+        let device_id = registry.insert(Interface::WlDataDevice(WlDataDevice));
+        objects.register(device, device_id, 3).unwrap();
+        let mut serials = Serials::new();
+        let mut input = InputState::new(Rmlvo::default());
+        let mut outputs = Outputs::new();
+        let client = ClientInfo {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+            exe: None,
+        };
+        let mut ping = PingState::default();
+
+        // Any request gives access to the dispatch state, the offer is created while handling
+        // a `wl_data_device.release`.
+        let request: [u32; 2] = [device.raw(), 8 << 16 | 2];
+        write(peer.as_raw_fd(), bytemuck::cast_slice(&request)).unwrap();
+        let mut offer = None;
+        stream
+            .receive(|self_id, _, _, fds, send_buf: &mut _| {
+                let mut state = DispatchState {
+                    self_id,
+                    version: 3,
+                    fds,
+                    send_buf,
+                    registry: &mut registry,
+                    serials: &mut serials,
+                    input: &mut input,
+                    outputs: &mut outputs,
+                    objects: &mut objects,
+                    client: &client,
+                    ping: &mut ping,
+                };
+                let id = state.create_server_object(Interface::WlDataOffer(WlDataOffer))?;
+                offer = Some(id);
+                wl_data_device::emit_data_offer(state.send_buf, self_id, id)
+            })
+            .unwrap();
+        stream.flush().unwrap();
+
+        let offer = offer.unwrap();
+        assert_eq!(offer.raw(), SERVER_ID_START);
+        let (global_id, version) = objects.get_with_version(offer).unwrap();
+        assert_eq!(version, 3);
+        assert!(matches!(
+            registry.get(global_id),
+            Some(Interface::WlDataOffer(_))
+        ));
+
+        let mut event = [0u32; 3];
+        let len = read(peer.as_raw_fd(), bytemuck::cast_slice_mut(&mut event)).unwrap();
+        assert_eq!(len, 12);
+        assert_eq!(event, [device.raw(), 12 << 16, offer.raw()]);
+    }
 }
@@ -0,0 +1,90 @@
+//! Human readable tracing of all requests and events, in the style of `WAYLAND_DEBUG`.
+//!
+//! The trace lines are generated by protocol-scanner for every request demarshaller and
+//! `emit_*` function and are written to stderr when tracing is enabled.
+
+use crate::gateway::registry::ObjectId;
+
+use std::{
+    env, fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enable tracing if `WAYLAND_DEBUG` is set to `1` or `server`, like libwayland does.
+pub fn init_from_env() {
+    let enabled = env::var("WAYLAND_DEBUG").is_ok_and(|v| v == "1" || v == "server");
+    set_enabled(enabled);
+}
+
+#[inline]
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Trace a request received from a client.
+pub fn request(client_id: u32, message: fmt::Arguments) {
+    eprintln!("[{}] [client {}] {}", Timestamp, client_id, message);
+}
+
+/// Trace an event sent to a client.
+pub fn event(client_id: u32, message: fmt::Arguments) {
+    eprintln!("[{}] [client {}]  -> {}", Timestamp, client_id, message);
+}
+
+/// Wall clock milliseconds, truncated the same way as libwayland does.
+struct Timestamp;
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let millis = now.as_millis() % 1_000_000_000;
+        write!(f, "{:7}.{:03}", millis / 1000, millis % 1000)
+    }
+}
+
+/// An object argument, formatted as `interface@id`.
+pub struct Object(pub Option<&'static str>, pub Option<ObjectId>);
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self(_, None) => f.write_str("nil"),
+            Self(Some(interface), Some(id)) => write!(f, "{}@{}", interface, id.raw()),
+            Self(None, Some(id)) => write!(f, "[unknown]@{}", id.raw()),
+        }
+    }
+}
+
+/// A possibly null string argument.
+pub struct Str<'a>(pub Option<&'a str>);
+
+impl fmt::Display for Str<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(s) => write!(f, "{:?}", s),
+            None => f.write_str("nil"),
+        }
+    }
+}
+
+/// A possibly null array argument, only its length is shown.
+pub struct Array<'a>(pub Option<&'a [u8]>);
+
+impl fmt::Display for Array<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(a) => write!(f, "array[{}]", a.len()),
+            None => f.write_str("nil"),
+        }
+    }
+}
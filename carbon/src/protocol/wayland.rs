@@ -1,10 +1,17 @@
 use crate::{
-    gateway::{message::MessageError, registry::ObjectId},
-    input::SeatId,
-    protocol::{generated::*, DispatchState, Interface},
+    gateway::{
+        message::MessageError,
+        registry::{GlobalObjectId, ObjectId},
+        serial::{InputDevice, SerialEvent},
+    },
+    input::{self, DeviceObject, Seat, SeatId},
+    protocol::{generated::*, wl_seat::Capability, DispatchState, Interface},
+    region::{Rect, Region},
+    shm::{ShmBuffer, ShmPool, SUPPORTED_FORMATS},
+    surface::{self, AttachedBuffer, Role, StackEntry, SubsurfaceRole, Surface},
 };
 
-use std::os::unix::io::RawFd;
+use std::{cell::RefCell, os::unix::io::OwnedFd, rc::Rc};
 
 pub struct WlDisplay;
 impl WlDisplay {
@@ -13,10 +20,9 @@ impl WlDisplay {
         state: &mut DispatchState,
         callback: ObjectId,
     ) -> Result<(), MessageError> {
-        state.objects.register(callback, None)?;
+        state.create_object(callback, Interface::WlCallback(WlCallback))?;
         wl_callback::emit_done(state.send_buf, callback, 0)?;
-
-        Ok(())
+        state.destroy_object(callback)
     }
 
     pub fn handle_get_registry(
@@ -26,13 +32,13 @@ impl WlDisplay {
     ) -> Result<(), MessageError> {
         state
             .objects
-            .register(registry, Some(state.registry.registry_id()))?;
+            .register_global(registry, state.registry.registry_id(), 1)?;
 
-        for (_, interface) in state.registry.globals() {
+        for (name, _, interface) in state.registry.globals() {
             wl_registry::emit_global(
                 state.send_buf,
                 registry,
-                interface.id(),
+                name,
                 interface.name(),
                 interface.version(),
             )?;
@@ -52,15 +58,48 @@ impl WlRegistry {
         version: u32,
         id: ObjectId,
     ) -> Result<(), MessageError> {
-        let (global_id, _) = state
-            .registry
-            .globals()
-            .find(|(_, i)| i.id() == name && i.name() == interface && i.version() >= version)
-            .ok_or_else(|| {
-                MessageError::BadRequest("attempt to bind non-existent global".to_owned())
-            })?;
+        let (global_id, global) = state.registry.global_by_name(name).ok_or_else(|| {
+            wl_display::Error::InvalidObject.into_message_error(
+                state.self_id,
+                format!("invalid global {} ({})", interface, name),
+            )
+        })?;
+        if global.name() != interface {
+            return Err(wl_display::Error::InvalidObject.into_message_error(
+                state.self_id,
+                format!(
+                    "invalid interface for global {}: have {}, wanted {}",
+                    name,
+                    global.name(),
+                    interface
+                ),
+            ));
+        }
+        if version == 0 || version > global.version() {
+            return Err(wl_display::Error::InvalidObject.into_message_error(
+                state.self_id,
+                format!(
+                    "invalid version for global {} ({}): have {}, wanted {}",
+                    interface,
+                    name,
+                    global.version(),
+                    version
+                ),
+            ));
+        }
 
-        state.objects.register(id, Some(global_id))?;
+        state.objects.register_global(id, global_id, version)?;
+        match *global {
+            Interface::WlShm(_) => WlShm::send_formats(state, id)?,
+            Interface::WlSeat(WlSeat { id: seat }) => WlSeat::send_info(state, seat, id, version)?,
+            _ => (),
+        }
+        log::debug!(
+            "Client {} bound {} version {}",
+            state.client,
+            interface,
+            version
+        );
 
         Ok(())
     }
@@ -75,55 +114,98 @@ impl WlCompositor {
         state: &mut DispatchState,
         id: ObjectId,
     ) -> Result<(), MessageError> {
-        let surface = WlSurface;
-        let global_id = state.registry.insert(Interface::WlSurface(surface));
-        let res = state.objects.register(id, Some(global_id));
-        if res.is_err() {
-            let _surface = state.registry.remove(global_id).unwrap();
-        }
+        let surface = WlSurface {
+            surface: Surface::default(),
+        };
+        state.create_object(id, Interface::WlSurface(surface))?;
 
-        res
+        Ok(())
     }
 
     pub fn handle_create_region(
         &mut self,
-        _state: &mut DispatchState,
-        _id: ObjectId,
+        state: &mut DispatchState,
+        id: ObjectId,
     ) -> Result<(), MessageError> {
-        todo!(
-            "{}::{} not yet implemented",
-            "WlCompositor",
-            "create_region"
-        )
+        let region = WlRegion {
+            region: Region::new(),
+        };
+        state.create_object(id, Interface::WlRegion(region))?;
+
+        Ok(())
     }
 }
 
-pub struct WlShmPool;
+pub struct WlShmPool {
+    pool: Rc<RefCell<ShmPool>>,
+}
 impl WlShmPool {
     #[allow(clippy::too_many_arguments)]
     pub fn handle_create_buffer(
         &mut self,
-        _state: &mut DispatchState,
-        _id: ObjectId,
-        _offset: i32,
-        _width: i32,
-        _height: i32,
-        _stride: i32,
-        _format: wl_shm::Format,
+        state: &mut DispatchState,
+        id: ObjectId,
+        offset: i32,
+        width: i32,
+        height: i32,
+        stride: i32,
+        format: wl_shm::Format,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlShmPool", "create_buffer")
+        if !SUPPORTED_FORMATS.contains(&format) {
+            return Err(wl_shm::Error::InvalidFormat
+                .into_message_error(state.self_id, format!("invalid format {:?}", format)));
+        }
+
+        let pool_size = self.pool.borrow().size();
+        // All supported formats use 4 bytes per pixel.
+        let fits = offset >= 0
+            && width > 0
+            && height > 0
+            && width.checked_mul(4).is_some_and(|min| stride >= min)
+            && stride
+                .checked_mul(height)
+                .and_then(|size| size.checked_add(offset))
+                .is_some_and(|end| end as usize <= pool_size);
+        if !fits {
+            return Err(wl_shm::Error::InvalidStride.into_message_error(
+                state.self_id,
+                format!(
+                    "invalid width, height or stride ({}x{}, {}) at offset {} in pool of size {}",
+                    width, height, stride, offset, pool_size
+                ),
+            ));
+        }
+
+        let buffer = ShmBuffer::new(
+            Rc::clone(&self.pool),
+            offset as usize,
+            width as u32,
+            height as u32,
+            stride as u32,
+            format,
+        );
+        state.create_object(id, Interface::WlBuffer(WlBuffer { buffer }))?;
+
+        Ok(())
     }
 
     pub fn handle_destroy(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlShmPool", "destroy")
+        Ok(())
     }
 
     pub fn handle_resize(
         &mut self,
-        _state: &mut DispatchState,
-        _size: i32,
+        state: &mut DispatchState,
+        size: i32,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlShmPool", "resize")
+        let mut pool = self.pool.borrow_mut();
+        if size < 0 || (size as usize) < pool.size() {
+            return Err(wl_shm::Error::InvalidFd
+                .into_message_error(state.self_id, "shrinking pool invalid".to_owned()));
+        }
+
+        pool.grow(size as usize)
+            .map_err(|e| wl_shm::Error::InvalidFd.into_message_error(state.self_id, e.to_string()))
     }
 }
 
@@ -131,19 +213,43 @@ pub struct WlShm;
 impl WlShm {
     pub fn handle_create_pool(
         &mut self,
-        _state: &mut DispatchState,
-        _id: ObjectId,
-        _fd: RawFd,
-        _size: i32,
+        state: &mut DispatchState,
+        id: ObjectId,
+        fd: OwnedFd,
+        size: i32,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlShm", "create_pool")
+        if size <= 0 {
+            return Err(wl_shm::Error::InvalidStride
+                .into_message_error(state.self_id, format!("invalid pool size {}", size)));
+        }
+
+        let pool = ShmPool::new(fd, size as usize).map_err(|e| {
+            wl_shm::Error::InvalidFd.into_message_error(state.self_id, e.to_string())
+        })?;
+        let pool = WlShmPool {
+            pool: Rc::new(RefCell::new(pool)),
+        };
+        state.create_object(id, Interface::WlShmPool(pool))?;
+
+        Ok(())
+    }
+
+    /// Advertise the supported formats to a newly bound `wl_shm`.
+    fn send_formats(state: &mut DispatchState, id: ObjectId) -> Result<(), MessageError> {
+        for format in SUPPORTED_FORMATS {
+            wl_shm::emit_format(state.send_buf, id, format)?;
+        }
+
+        Ok(())
     }
 }
 
-pub struct WlBuffer;
+pub struct WlBuffer {
+    pub buffer: ShmBuffer,
+}
 impl WlBuffer {
     pub fn handle_destroy(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlBuffer", "destroy")
+        Ok(())
     }
 }
 
@@ -162,13 +268,13 @@ impl WlDataOffer {
         &mut self,
         _state: &mut DispatchState,
         _mime_type: &str,
-        _fd: RawFd,
+        _fd: OwnedFd,
     ) -> Result<(), MessageError> {
         todo!("{}::{} not yet implemented", "WlDataOffer", "receive")
     }
 
     pub fn handle_destroy(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlDataOffer", "destroy")
+        Ok(())
     }
 
     pub fn handle_finish(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
@@ -196,7 +302,7 @@ impl WlDataSource {
     }
 
     pub fn handle_destroy(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlDataSource", "destroy")
+        Ok(())
     }
 
     pub fn handle_set_actions(
@@ -235,7 +341,7 @@ impl WlDataDevice {
     }
 
     pub fn handle_release(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlDataDevice", "release")
+        Ok(())
     }
 }
 
@@ -283,10 +389,11 @@ pub struct WlShellSurface;
 impl WlShellSurface {
     pub fn handle_pong(
         &mut self,
-        _state: &mut DispatchState,
-        _serial: u32,
+        state: &mut DispatchState,
+        serial: u32,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlShellSurface", "pong")
+        state.ping.pong(serial, state.client);
+        Ok(())
     }
 
     pub fn handle_move(
@@ -388,111 +495,245 @@ impl WlShellSurface {
     }
 }
 
-pub struct WlSurface;
+pub struct WlSurface {
+    pub surface: Surface,
+}
 impl WlSurface {
-    pub fn handle_destroy(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSurface", "destroy")
+    pub fn handle_destroy(&mut self, state: &mut DispatchState) -> Result<(), MessageError> {
+        if let Some(id) = state.objects.get(state.self_id) {
+            self.detach_from_tree(state, id);
+        }
+
+        let cached = self.surface.cached.take().unwrap_or_default();
+        let current = self.surface.current.buffer;
+        if let Some(buffer) = current {
+            release_buffer(state, buffer)?;
+        }
+        if let Some(Some(buffer)) = cached.buffer.filter(|&b| b != current) {
+            release_buffer(state, buffer)?;
+        }
+
+        // Frame callbacks of the surface will never be done.
+        let callbacks = self
+            .surface
+            .pending
+            .frame_callbacks
+            .drain(..)
+            .chain(cached.frame_callbacks)
+            .chain(self.surface.current.frame_callbacks.drain(..));
+        for callback in callbacks {
+            state.destroy_object(callback)?;
+        }
+
+        Ok(())
     }
 
     pub fn handle_attach(
         &mut self,
-        _state: &mut DispatchState,
-        _buffer: Option<ObjectId>,
-        _x: i32,
-        _y: i32,
+        state: &mut DispatchState,
+        buffer: Option<ObjectId>,
+        x: i32,
+        y: i32,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSurface", "attach")
+        if state.version >= 5 && (x, y) != (0, 0) {
+            return Err(wl_surface::Error::InvalidOffset.into_message_error(
+                state.self_id,
+                format!("attach with offset {},{}, use wl_surface.offset", x, y),
+            ));
+        }
+
+        let buffer = match buffer {
+            Some(id) => match state.object(id)? {
+                (global_id, Interface::WlBuffer(WlBuffer { buffer })) => Some(AttachedBuffer {
+                    id,
+                    global_id,
+                    width: buffer.width as i32,
+                    height: buffer.height as i32,
+                }),
+                (_, object) => {
+                    return Err(wl_display::Error::InvalidObject.into_message_error(
+                        state.self_id,
+                        format!("cannot attach {}@{} as buffer", object.name(), id.raw()),
+                    ))
+                }
+            },
+            None => None,
+        };
+
+        let pending = &mut self.surface.pending;
+        pending.buffer = Some(buffer);
+        if state.version < 5 {
+            pending.offset = (x, y);
+        }
+
+        Ok(())
     }
 
     pub fn handle_damage(
         &mut self,
         _state: &mut DispatchState,
-        _x: i32,
-        _y: i32,
-        _width: i32,
-        _height: i32,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSurface", "damage")
+        self.surface
+            .pending
+            .surface_damage
+            .add(Rect::new(x, y, width, height));
+        Ok(())
     }
 
     pub fn handle_frame(
         &mut self,
-        _state: &mut DispatchState,
-        _callback: ObjectId,
+        state: &mut DispatchState,
+        callback: ObjectId,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSurface", "frame")
+        state.create_object(callback, Interface::WlCallback(WlCallback))?;
+        self.surface.pending.frame_callbacks.push(callback);
+
+        Ok(())
     }
 
     pub fn handle_set_opaque_region(
         &mut self,
-        _state: &mut DispatchState,
-        _region: Option<ObjectId>,
+        state: &mut DispatchState,
+        region: Option<ObjectId>,
     ) -> Result<(), MessageError> {
-        todo!(
-            "{}::{} not yet implemented",
-            "WlSurface",
-            "set_opaque_region"
-        )
+        self.surface.pending.opaque_region = Some(region_arg(state, region)?);
+        Ok(())
     }
 
     pub fn handle_set_input_region(
         &mut self,
-        _state: &mut DispatchState,
-        _region: Option<ObjectId>,
+        state: &mut DispatchState,
+        region: Option<ObjectId>,
     ) -> Result<(), MessageError> {
-        todo!(
-            "{}::{} not yet implemented",
-            "WlSurface",
-            "set_input_region"
-        )
+        self.surface.pending.input_region = Some(region_arg(state, region)?);
+        Ok(())
     }
 
-    pub fn handle_commit(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSurface", "commit")
+    pub fn handle_commit(&mut self, state: &mut DispatchState) -> Result<(), MessageError> {
+        if let (Some(buffer), scale) = self.surface.pending_buffer_and_scale() {
+            if buffer.width % scale != 0 || buffer.height % scale != 0 {
+                return Err(wl_surface::Error::InvalidSize.into_message_error(
+                    state.self_id,
+                    format!(
+                        "buffer size {}x{} is not a multiple of scale {}",
+                        buffer.width, buffer.height, scale
+                    ),
+                ));
+            }
+        }
+
+        let mut released = vec![];
+        self.surface.cache(&mut released);
+        let synchronized = self
+            .surface
+            .subsurface()
+            .is_some_and(|role| surface::is_synchronized(state.registry, role));
+        if !synchronized {
+            surface::apply_tree(&mut self.surface, state.registry, false, &mut released);
+            state.outputs.request_frame();
+        }
+
+        for buffer in released {
+            release_buffer(state, buffer)?;
+        }
+
+        Ok(())
     }
 
     pub fn handle_set_buffer_transform(
         &mut self,
         _state: &mut DispatchState,
-        _transform: wl_output::Transform,
+        transform: wl_output::Transform,
     ) -> Result<(), MessageError> {
-        todo!(
-            "{}::{} not yet implemented",
-            "WlSurface",
-            "set_buffer_transform"
-        )
+        self.surface.pending.transform = Some(transform);
+        Ok(())
     }
 
     pub fn handle_set_buffer_scale(
         &mut self,
-        _state: &mut DispatchState,
-        _scale: i32,
+        state: &mut DispatchState,
+        scale: i32,
     ) -> Result<(), MessageError> {
-        todo!(
-            "{}::{} not yet implemented",
-            "WlSurface",
-            "set_buffer_scale"
-        )
+        if scale <= 0 {
+            return Err(wl_surface::Error::InvalidScale
+                .into_message_error(state.self_id, format!("invalid scale {}", scale)));
+        }
+
+        self.surface.pending.scale = Some(scale);
+        Ok(())
     }
 
     pub fn handle_damage_buffer(
         &mut self,
         _state: &mut DispatchState,
-        _x: i32,
-        _y: i32,
-        _width: i32,
-        _height: i32,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSurface", "damage_buffer")
+        self.surface
+            .pending
+            .buffer_damage
+            .add(Rect::new(x, y, width, height));
+        Ok(())
     }
 
     pub fn handle_offset(
         &mut self,
         _state: &mut DispatchState,
-        _x: i32,
-        _y: i32,
+        x: i32,
+        y: i32,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSurface", "offset")
+        self.surface.pending.offset = (x, y);
+        Ok(())
+    }
+
+    /// Unmap the sub-surfaces of the surface and remove it from the stack of its parent.
+    fn detach_from_tree(&mut self, state: &mut DispatchState, id: GlobalObjectId) {
+        for entry in self.surface.pending_stack.iter().chain(&self.surface.stack) {
+            if let StackEntry::Child(child) = *entry {
+                if let Some(role) = surface::get_surface_mut(state.registry, child)
+                    .and_then(Surface::subsurface_mut)
+                {
+                    role.parent = None;
+                }
+            }
+        }
+
+        let parent = self.surface.subsurface().and_then(|role| role.parent);
+        if let Some(parent) = parent.and_then(|p| surface::get_surface_mut(state.registry, p)) {
+            remove_from_stack(parent, id);
+        }
+    }
+}
+
+/// Tell the client a buffer is no longer used, unless it was destroyed already.
+fn release_buffer(state: &mut DispatchState, buffer: AttachedBuffer) -> Result<(), MessageError> {
+    if state.objects.get(buffer.id) == Some(buffer.global_id) {
+        wl_buffer::emit_release(state.send_buf, buffer.id)?;
+    }
+
+    Ok(())
+}
+
+/// Copy the region passed to `set_opaque_region` or `set_input_region`.
+fn region_arg(
+    state: &DispatchState,
+    region: Option<ObjectId>,
+) -> Result<Option<Region>, MessageError> {
+    match region {
+        Some(id) => match state.object(id)? {
+            (_, Interface::WlRegion(WlRegion { region })) => Ok(Some(region.clone())),
+            (_, object) => Err(wl_display::Error::InvalidObject.into_message_error(
+                state.self_id,
+                format!("{}@{} is not a region", object.name(), id.raw()),
+            )),
+        },
+        None => Ok(None),
     }
 }
 
@@ -503,158 +744,476 @@ pub struct WlSeat {
 impl WlSeat {
     pub fn handle_get_pointer(
         &mut self,
-        _state: &mut DispatchState,
-        _id: ObjectId,
+        state: &mut DispatchState,
+        id: ObjectId,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSeat", "get_pointer")
+        let pointer = Interface::WlPointer(WlPointer { seat: self.id });
+        self.get_device(state, id, Capability::POINTER, pointer)
     }
 
     pub fn handle_get_keyboard(
         &mut self,
-        _state: &mut DispatchState,
-        _id: ObjectId,
+        state: &mut DispatchState,
+        id: ObjectId,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSeat", "get_keyboard")
+        let keyboard = Interface::WlKeyboard(WlKeyboard { seat: self.id });
+        self.get_device(state, id, Capability::KEYBOARD, keyboard)?;
+
+        let seat = state.input.seat(self.id);
+        input::send_keymap(
+            state.send_buf,
+            id,
+            state.version,
+            seat.and_then(Seat::keymap),
+        )?;
+
+        // A keyboard created while the client has the focus enters the focused surface right
+        // away.
+        let client_id = state.send_buf.client_id();
+        let seat = match seat {
+            Some(seat) => seat,
+            None => return Ok(()),
+        };
+        let focus = match seat.keyboard_focus(state.registry) {
+            Some(focus) if focus.client == client_id => focus,
+            _ => return Ok(()),
+        };
+        let serial = state.serials.next(SerialEvent {
+            seat: Some(self.id),
+            device: Some(InputDevice::Keyboard),
+            client_id,
+            surface: Some(focus.surface),
+        });
+        let keys = bytemuck::cast_slice(seat.pressed_keys());
+        wl_keyboard::emit_enter(state.send_buf, id, serial, focus.surface_id, keys)?;
+        if let Some(modifiers) = seat.modifiers() {
+            let serial = state.serials.next(SerialEvent {
+                seat: Some(self.id),
+                device: Some(InputDevice::Keyboard),
+                client_id,
+                surface: Some(focus.surface),
+            });
+            wl_keyboard::emit_modifiers(
+                state.send_buf,
+                id,
+                serial,
+                modifiers.depressed,
+                modifiers.latched,
+                modifiers.locked,
+                modifiers.group,
+            )?;
+        }
+
+        Ok(())
     }
 
     pub fn handle_get_touch(
         &mut self,
-        _state: &mut DispatchState,
-        _id: ObjectId,
+        state: &mut DispatchState,
+        id: ObjectId,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSeat", "get_touch")
+        let touch = Interface::WlTouch(WlTouch { seat: self.id });
+        self.get_device(state, id, Capability::TOUCH, touch)
     }
 
     pub fn handle_release(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSeat", "release")
+        Ok(())
+    }
+
+    /// Create a device object and track it on the seat. Devices of a seat that was removed by
+    /// its backend are never tracked, so they stay inert.
+    fn get_device(
+        &self,
+        state: &mut DispatchState,
+        id: ObjectId,
+        capability: Capability,
+        object: Interface,
+    ) -> Result<(), MessageError> {
+        if state
+            .input
+            .seat(self.id)
+            .is_some_and(|seat| !seat.had_capability(capability))
+        {
+            return Err(wl_seat::Error::MissingCapability.into_message_error(
+                state.self_id,
+                format!("seat never had the {:?} capability", capability),
+            ));
+        }
+
+        let global_id = state.create_object(id, object)?;
+        let device = DeviceObject {
+            client: state.send_buf.client_id(),
+            id,
+            global_id,
+            version: state.version,
+        };
+        if let Some(seat) = state.input.seat_mut(self.id) {
+            seat.add_device(capability, device, state.registry);
+        }
+
+        Ok(())
+    }
+
+    /// Send the capabilities and name of the seat to a newly bound `wl_seat`.
+    fn send_info(
+        state: &mut DispatchState,
+        seat: SeatId,
+        id: ObjectId,
+        version: u32,
+    ) -> Result<(), MessageError> {
+        // The seat can be removed by its backend right after clients see the global.
+        let (capabilities, name) = match state.input.seat(seat) {
+            Some(seat) => (seat.capabilities(), seat.name()),
+            None => (Capability::empty(), ""),
+        };
+        wl_seat::emit_capabilities(state.send_buf, id, capabilities)?;
+        wl_seat::emit_name(state.send_buf, id, version, name)
+    }
+}
+
+/// Stop tracking a device object on its seat when it is released.
+fn release_device(state: &mut DispatchState, seat: SeatId, capability: Capability) {
+    let client = state.send_buf.client_id();
+    if let Some(seat) = state.input.seat_mut(seat) {
+        seat.remove_device(capability, client, state.self_id);
     }
 }
 
-pub struct WlPointer;
+pub struct WlPointer {
+    pub seat: SeatId,
+}
+
 impl WlPointer {
+    /// Give a surface the cursor role. The hotspot is not used, as cursor surfaces are not
+    /// drawn.
     pub fn handle_set_cursor(
         &mut self,
-        _state: &mut DispatchState,
-        _serial: u32,
-        _surface: Option<ObjectId>,
+        state: &mut DispatchState,
+        serial: u32,
+        surface: Option<ObjectId>,
         _hotspot_x: i32,
         _hotspot_y: i32,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlPointer", "set_cursor")
+        // Only clients that received pointer events of the seat may set its cursor, requests
+        // with other serials are ignored.
+        let valid = state
+            .validate_serial(serial, Some(InputDevice::Pointer))
+            .is_some_and(|event| event.seat == Some(self.seat));
+        let surface = match surface {
+            Some(surface) if valid => surface,
+            _ => return Ok(()),
+        };
+
+        let self_id = state.self_id;
+        match state.object(surface)? {
+            (surface_id, Interface::WlSurface(WlSurface { surface: s }))
+                if matches!(s.role, None | Some(Role::Cursor)) =>
+            {
+                if let Some(s) = surface::get_surface_mut(state.registry, surface_id) {
+                    s.role = Some(Role::Cursor);
+                }
+                Ok(())
+            }
+            (_, object) => Err(wl_pointer::Error::Role.into_message_error(
+                self_id,
+                format!(
+                    "{}@{} is not a surface without a role or with the cursor role",
+                    object.name(),
+                    surface.raw()
+                ),
+            )),
+        }
     }
 
-    pub fn handle_release(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlPointer", "release")
+    pub fn handle_release(&mut self, state: &mut DispatchState) -> Result<(), MessageError> {
+        release_device(state, self.seat, Capability::POINTER);
+        Ok(())
     }
 }
 
-pub struct WlKeyboard;
+pub struct WlKeyboard {
+    pub seat: SeatId,
+}
+
 impl WlKeyboard {
-    pub fn handle_release(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlKeyboard", "release")
+    pub fn handle_release(&mut self, state: &mut DispatchState) -> Result<(), MessageError> {
+        release_device(state, self.seat, Capability::KEYBOARD);
+        Ok(())
     }
 }
 
-pub struct WlTouch;
+pub struct WlTouch {
+    pub seat: SeatId,
+}
+
 impl WlTouch {
-    pub fn handle_release(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlTouch", "release")
+    pub fn handle_release(&mut self, state: &mut DispatchState) -> Result<(), MessageError> {
+        release_device(state, self.seat, Capability::TOUCH);
+        Ok(())
     }
 }
 
 pub struct WlOutput;
 impl WlOutput {
     pub fn handle_release(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlOutput", "release")
+        Ok(())
     }
 }
 
-pub struct WlRegion;
+pub struct WlRegion {
+    pub region: Region,
+}
 impl WlRegion {
     pub fn handle_destroy(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlRegion", "destroy")
+        Ok(())
     }
 
     pub fn handle_add(
         &mut self,
         _state: &mut DispatchState,
-        _x: i32,
-        _y: i32,
-        _width: i32,
-        _height: i32,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlRegion", "add")
+        self.region.add(Rect::new(x, y, width, height));
+        Ok(())
     }
 
     pub fn handle_subtract(
         &mut self,
         _state: &mut DispatchState,
-        _x: i32,
-        _y: i32,
-        _width: i32,
-        _height: i32,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlRegion", "subtract")
+        self.region.remove(Rect::new(x, y, width, height));
+        Ok(())
     }
 }
 
 pub struct WlSubcompositor;
 impl WlSubcompositor {
     pub fn handle_destroy(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSubcompositor", "destroy")
+        Ok(())
     }
 
     pub fn handle_get_subsurface(
         &mut self,
-        _state: &mut DispatchState,
-        _id: ObjectId,
-        _surface: ObjectId,
-        _parent: ObjectId,
+        state: &mut DispatchState,
+        id: ObjectId,
+        surface: ObjectId,
+        parent: ObjectId,
     ) -> Result<(), MessageError> {
-        todo!(
-            "{}::{} not yet implemented",
-            "WlSubcompositor",
-            "get_subsurface"
-        )
+        let self_id = state.self_id;
+        let bad_surface = |message: String| {
+            wl_subcompositor::Error::BadSurface.into_message_error(self_id, message)
+        };
+
+        let surface_id = match state.object(surface)? {
+            (surface_id, Interface::WlSurface(WlSurface { surface: s }))
+                if s.role.is_none() || s.subsurface().is_some_and(|r| r.parent.is_none()) =>
+            {
+                surface_id
+            }
+            (_, object) => {
+                return Err(bad_surface(format!(
+                    "{}@{} is not a surface without a role",
+                    object.name(),
+                    surface.raw()
+                )))
+            }
+        };
+        // A sub-surface whose parent was destroyed is still bound to its wl_subsurface.
+        let has_subsurface = state.registry.iter().any(
+            |(_, object)| matches!(object, Interface::WlSubsurface(s) if s.surface == surface_id),
+        );
+        if has_subsurface {
+            return Err(bad_surface(format!(
+                "wl_surface@{} already is a sub-surface",
+                surface.raw()
+            )));
+        }
+        let parent_id = match state.object(parent)? {
+            (parent_id, Interface::WlSurface(_)) => parent_id,
+            (_, object) => {
+                return Err(bad_surface(format!(
+                    "parent {}@{} is not a surface",
+                    object.name(),
+                    parent.raw()
+                )))
+            }
+        };
+        // This protocol version has no bad_parent error yet.
+        if surface::is_ancestor(state.registry, surface_id, parent_id) {
+            return Err(bad_surface(format!(
+                "wl_surface@{} can not be a sub-surface of wl_surface@{}",
+                surface.raw(),
+                parent.raw()
+            )));
+        }
+
+        let subsurface = WlSubsurface {
+            surface: surface_id,
+        };
+        state.create_object(id, Interface::WlSubsurface(subsurface))?;
+
+        if let Some(s) = surface::get_surface_mut(state.registry, surface_id) {
+            s.role = Some(Role::Subsurface(SubsurfaceRole {
+                parent: Some(parent_id),
+                position: (0, 0),
+                pending_position: None,
+                sync: true,
+            }));
+        }
+        if let Some(p) = surface::get_surface_mut(state.registry, parent_id) {
+            p.pending_stack.push(StackEntry::Child(surface_id));
+        }
+
+        Ok(())
     }
 }
 
-pub struct WlSubsurface;
+pub struct WlSubsurface {
+    /// The `wl_surface` that has the sub-surface role.
+    pub surface: GlobalObjectId,
+}
 impl WlSubsurface {
-    pub fn handle_destroy(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSubsurface", "destroy")
+    pub fn handle_destroy(&mut self, state: &mut DispatchState) -> Result<(), MessageError> {
+        // The surface keeps its role, but is unmapped until it is made a sub-surface again.
+        let parent = surface::get_surface_mut(state.registry, self.surface)
+            .and_then(Surface::subsurface_mut)
+            .and_then(|role| role.parent.take());
+        if let Some(parent) = parent.and_then(|p| surface::get_surface_mut(state.registry, p)) {
+            remove_from_stack(parent, self.surface);
+        }
+
+        Ok(())
     }
 
     pub fn handle_set_position(
         &mut self,
-        _state: &mut DispatchState,
-        _x: i32,
-        _y: i32,
+        state: &mut DispatchState,
+        x: i32,
+        y: i32,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSubsurface", "set_position")
+        if let Some(role) = self.role_mut(state) {
+            role.pending_position = Some((x, y));
+        }
+
+        Ok(())
     }
 
     pub fn handle_place_above(
         &mut self,
-        _state: &mut DispatchState,
-        _sibling: ObjectId,
+        state: &mut DispatchState,
+        sibling: ObjectId,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSubsurface", "place_above")
+        self.restack(state, sibling, true)
     }
 
     pub fn handle_place_below(
         &mut self,
-        _state: &mut DispatchState,
-        _sibling: ObjectId,
+        state: &mut DispatchState,
+        sibling: ObjectId,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSubsurface", "place_below")
+        self.restack(state, sibling, false)
     }
 
-    pub fn handle_set_sync(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSubsurface", "set_sync")
+    pub fn handle_set_sync(&mut self, state: &mut DispatchState) -> Result<(), MessageError> {
+        if let Some(role) = self.role_mut(state) {
+            role.sync = true;
+        }
+
+        Ok(())
     }
 
-    pub fn handle_set_desync(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "WlSubsurface", "set_desync")
+    pub fn handle_set_desync(&mut self, state: &mut DispatchState) -> Result<(), MessageError> {
+        let role = match self.role_mut(state) {
+            Some(role) => role,
+            None => return Ok(()),
+        };
+        role.sync = false;
+
+        // State cached while synchronized is applied if the parent is not synchronized.
+        let apply = surface::get_surface(state.registry, self.surface).is_some_and(|s| {
+            s.cached.is_some()
+                && s.subsurface()
+                    .is_some_and(|role| !surface::is_synchronized(state.registry, role))
+        });
+        if !apply {
+            return Ok(());
+        }
+
+        let mut released = vec![];
+        if let Some(mut object) = state.registry.take(self.surface) {
+            if let Interface::WlSurface(WlSurface { surface }) = &mut object {
+                surface::apply_tree(surface, state.registry, false, &mut released);
+            }
+            state.registry.restore(self.surface, object);
+        }
+        state.outputs.request_frame();
+
+        for buffer in released {
+            release_buffer(state, buffer)?;
+        }
+
+        Ok(())
+    }
+
+    /// The role of the surface, `None` if the surface or its parent has been destroyed.
+    fn role_mut<'a>(&self, state: &'a mut DispatchState) -> Option<&'a mut SubsurfaceRole> {
+        surface::get_surface_mut(state.registry, self.surface)
+            .and_then(Surface::subsurface_mut)
+            .filter(|role| role.parent.is_some())
     }
+
+    /// Move the sub-surface just above or below `sibling` in the pending stack of the parent.
+    fn restack(
+        &mut self,
+        state: &mut DispatchState,
+        sibling: ObjectId,
+        above: bool,
+    ) -> Result<(), MessageError> {
+        let parent = match self.role_mut(state).and_then(|role| role.parent) {
+            Some(parent) => parent,
+            None => return Ok(()),
+        };
+        let (sibling_id, _) = state.object(sibling)?;
+        let reference = if sibling_id == parent {
+            StackEntry::Parent
+        } else {
+            StackEntry::Child(sibling_id)
+        };
+        let own = StackEntry::Child(self.surface);
+
+        let self_id = state.self_id;
+        let stack = match surface::get_surface_mut(state.registry, parent) {
+            Some(parent) => &mut parent.pending_stack,
+            None => return Ok(()),
+        };
+        if reference == own || !stack.contains(&reference) {
+            return Err(wl_subsurface::Error::BadSurface.into_message_error(
+                self_id,
+                format!(
+                    "wl_surface@{} is not the parent or a sibling",
+                    sibling.raw()
+                ),
+            ));
+        }
+
+        stack.retain(|&entry| entry != own);
+        let idx = stack.iter().position(|&entry| entry == reference).unwrap();
+        stack.insert(if above { idx + 1 } else { idx }, own);
+
+        Ok(())
+    }
+}
+
+/// Remove a sub-surface from the pending and current stack of its parent.
+fn remove_from_stack(parent: &mut Surface, child: GlobalObjectId) {
+    let entry = StackEntry::Child(child);
+    parent.pending_stack.retain(|&e| e != entry);
+    parent.stack.retain(|&e| e != entry);
 }
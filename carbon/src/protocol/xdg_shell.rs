@@ -5,7 +5,7 @@ use crate::{
 pub struct XdgWmBase;
 impl XdgWmBase {
     pub fn handle_destroy(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "XdgWmBase", "destroy")
+        Ok(())
     }
     pub fn handle_create_positioner(
         &mut self,
@@ -28,16 +28,17 @@ impl XdgWmBase {
     }
     pub fn handle_pong(
         &mut self,
-        _state: &mut DispatchState,
-        _serial: u32,
+        state: &mut DispatchState,
+        serial: u32,
     ) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "XdgWmBase", "pong")
+        state.ping.pong(serial, state.client);
+        Ok(())
     }
 }
 pub struct XdgPositioner;
 impl XdgPositioner {
     pub fn handle_destroy(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "XdgPositioner", "destroy")
+        Ok(())
     }
     pub fn handle_set_size(
         &mut self,
@@ -128,7 +129,7 @@ impl XdgPositioner {
 pub struct XdgSurface;
 impl XdgSurface {
     pub fn handle_destroy(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "XdgSurface", "destroy")
+        Ok(())
     }
     pub fn handle_get_toplevel(
         &mut self,
@@ -171,7 +172,7 @@ impl XdgSurface {
 pub struct XdgToplevel;
 impl XdgToplevel {
     pub fn handle_destroy(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "XdgToplevel", "destroy")
+        Ok(())
     }
     pub fn handle_set_parent(
         &mut self,
@@ -282,7 +283,7 @@ impl XdgToplevel {
 pub struct XdgPopup;
 impl XdgPopup {
     pub fn handle_destroy(&mut self, _state: &mut DispatchState) -> Result<(), MessageError> {
-        todo!("{}::{} not yet implemented", "XdgPopup", "destroy")
+        Ok(())
     }
     pub fn handle_grab(
         &mut self,
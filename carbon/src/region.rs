@@ -0,0 +1,428 @@
+//! Rectangles and sets of rectangles in surface or buffer coordinates.
+//!
+//! Regions are stored like pixman does: as rectangles sorted into horizontal bands, where all
+//! rectangles in a band have the same top and bottom edge and do not overlap or touch. Adjacent
+//! bands with the same horizontal spans are merged, so every region has a single
+//! representation and set operations are a sweep over the bands of both operands.
+
+/// An axis aligned rectangle, `width` and `height` are never negative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rect {
+    /// Create a rectangle, a negative size is clamped to an empty rectangle.
+    #[inline]
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Self {
+            x,
+            y,
+            width: width.max(0),
+            height: height.max(0),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    #[inline]
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        let edges = Edges::from(*self);
+        edges.x1 <= x && x < edges.x2 && edges.y1 <= y && y < edges.y2
+    }
+
+    /// The overlap of two rectangles, empty if they do not overlap.
+    #[inline]
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let (a, b) = (Edges::from(*self), Edges::from(*other));
+        Rect::from(Edges {
+            x1: a.x1.max(b.x1),
+            y1: a.y1.max(b.y1),
+            x2: a.x2.min(b.x2),
+            y2: a.y2.min(b.y2),
+        })
+    }
+}
+
+/// A rectangle by its edges, the right and bottom edges are exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Edges {
+    x1: i32,
+    y1: i32,
+    x2: i32,
+    y2: i32,
+}
+
+impl From<Rect> for Edges {
+    #[inline]
+    fn from(rect: Rect) -> Self {
+        Self {
+            x1: rect.x,
+            y1: rect.y,
+            x2: rect.x.saturating_add(rect.width),
+            y2: rect.y.saturating_add(rect.height),
+        }
+    }
+}
+
+impl From<Edges> for Rect {
+    #[inline]
+    fn from(edges: Edges) -> Self {
+        Self::new(
+            edges.x1,
+            edges.y1,
+            edges.x2.saturating_sub(edges.x1),
+            edges.y2.saturating_sub(edges.y1),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Union,
+    Subtract,
+    Intersect,
+}
+
+impl Op {
+    #[inline]
+    fn apply(self, in_a: bool, in_b: bool) -> bool {
+        match self {
+            Self::Union => in_a || in_b,
+            Self::Subtract => in_a && !in_b,
+            Self::Intersect => in_a && in_b,
+        }
+    }
+}
+
+/// A set of pixels described by non-overlapping rectangles.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Region {
+    /// Sorted by band and then from left to right.
+    rects: Vec<Edges>,
+}
+
+impl From<Rect> for Region {
+    #[inline]
+    fn from(rect: Rect) -> Self {
+        let edges = Edges::from(rect);
+        let rects = if edges.x1 < edges.x2 && edges.y1 < edges.y2 {
+            vec![edges]
+        } else {
+            vec![]
+        };
+
+        Self { rects }
+    }
+}
+
+impl Region {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    /// The rectangles of the region, sorted from top to bottom and left to right.
+    #[inline]
+    pub fn rects(&self) -> impl Iterator<Item = Rect> + '_ {
+        self.rects.iter().map(|&edges| Rect::from(edges))
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        // Bands do not overlap, so the bottom edges are sorted as well.
+        let start = self.rects.partition_point(|r| r.y2 <= y);
+        self.rects[start..]
+            .iter()
+            .take_while(|r| r.y1 <= y)
+            .any(|r| r.x1 <= x && x < r.x2)
+    }
+
+    pub fn union(&self, other: &Region) -> Region {
+        combine(self, other, Op::Union)
+    }
+
+    pub fn subtract(&self, other: &Region) -> Region {
+        combine(self, other, Op::Subtract)
+    }
+
+    pub fn intersect(&self, other: &Region) -> Region {
+        combine(self, other, Op::Intersect)
+    }
+
+    /// Add a rectangle to the region.
+    #[inline]
+    pub fn add(&mut self, rect: Rect) {
+        if !rect.is_empty() {
+            *self = self.union(&Region::from(rect));
+        }
+    }
+
+    /// Remove a rectangle from the region.
+    #[inline]
+    pub fn remove(&mut self, rect: Rect) {
+        if !rect.is_empty() && !self.is_empty() {
+            *self = self.subtract(&Region::from(rect));
+        }
+    }
+
+    /// Move the region by `dx`, `dy`.
+    pub fn translate(&mut self, dx: i32, dy: i32) {
+        for r in &mut self.rects {
+            r.x1 = r.x1.saturating_add(dx);
+            r.y1 = r.y1.saturating_add(dy);
+            r.x2 = r.x2.saturating_add(dx);
+            r.y2 = r.y2.saturating_add(dy);
+        }
+    }
+
+    /// Scale the region by a positive factor, partially covered pixels are included.
+    pub fn scale(&self, factor: f64) -> Region {
+        debug_assert!(factor > 0.0);
+        let scale_down = |v: i32| (f64::from(v) * factor).floor() as i32;
+        let scale_up = |v: i32| (f64::from(v) * factor).ceil() as i32;
+
+        // Rounding outwards can make rectangles overlap, so they are merged again.
+        self.rects.iter().fold(Region::new(), |acc, r| {
+            acc.union(&Region {
+                rects: vec![Edges {
+                    x1: scale_down(r.x1),
+                    y1: scale_down(r.y1),
+                    x2: scale_up(r.x2),
+                    y2: scale_up(r.y2),
+                }],
+            })
+        })
+    }
+
+    /// Append a band below the existing ones, merging it with the last band if that has the
+    /// same spans and touches it.
+    fn push_band(&mut self, y1: i32, y2: i32, spans: &[(i32, i32)]) {
+        if spans.is_empty() {
+            return;
+        }
+
+        let start = self.last_band_start();
+        let last = &mut self.rects[start..];
+        let same_spans = last.len() == spans.len()
+            && last
+                .iter()
+                .zip(spans)
+                .all(|(r, &(x1, x2))| r.x1 == x1 && r.x2 == x2);
+        if same_spans && last.first().is_some_and(|r| r.y2 == y1) {
+            for r in last {
+                r.y2 = y2;
+            }
+        } else {
+            self.rects
+                .extend(spans.iter().map(|&(x1, x2)| Edges { x1, y1, x2, y2 }));
+        }
+    }
+
+    fn last_band_start(&self) -> usize {
+        match self.rects.last() {
+            Some(last) => self
+                .rects
+                .iter()
+                .rposition(|r| r.y1 != last.y1)
+                .map_or(0, |i| i + 1),
+            None => 0,
+        }
+    }
+}
+
+/// Combine two regions band by band.
+fn combine(a: &Region, b: &Region, op: Op) -> Region {
+    // The horizontal edges of both regions split the plane into bands in which the spans of
+    // both regions do not change.
+    let mut ys: Vec<i32> = a
+        .rects
+        .iter()
+        .chain(&b.rects)
+        .flat_map(|r| [r.y1, r.y2])
+        .collect();
+    ys.sort_unstable();
+    ys.dedup();
+
+    let mut res = Region::new();
+    let mut spans = vec![];
+    let (mut next_a, mut next_b) = (0, 0);
+    for band in ys.windows(2) {
+        let (y1, y2) = (band[0], band[1]);
+        let band_a = band_at(&a.rects, &mut next_a, y1);
+        let band_b = band_at(&b.rects, &mut next_b, y1);
+
+        spans.clear();
+        combine_spans(band_a, band_b, op, &mut spans);
+        res.push_band(y1, y2, &spans);
+    }
+
+    res
+}
+
+/// The band of `rects` covering `y`, which must not be above the band `next` points to.
+fn band_at<'a>(rects: &'a [Edges], next: &mut usize, y: i32) -> &'a [Edges] {
+    while rects.get(*next).is_some_and(|r| r.y2 <= y) {
+        *next += 1;
+    }
+
+    let rest = &rects[*next..];
+    match rest.first() {
+        Some(first) if first.y1 <= y => {
+            let len = rest.iter().take_while(|r| r.y1 == first.y1).count();
+            &rest[..len]
+        }
+        _ => &[],
+    }
+}
+
+/// Combine the horizontal spans of two bands into `out`, merging spans that touch.
+fn combine_spans(a: &[Edges], b: &[Edges], op: Op, out: &mut Vec<(i32, i32)>) {
+    let mut xs: Vec<i32> = a.iter().chain(b).flat_map(|r| [r.x1, r.x2]).collect();
+    xs.sort_unstable();
+    xs.dedup();
+
+    let (mut next_a, mut next_b) = (0, 0);
+    for segment in xs.windows(2) {
+        let (x1, x2) = (segment[0], segment[1]);
+        while a.get(next_a).is_some_and(|r| r.x2 <= x1) {
+            next_a += 1;
+        }
+        while b.get(next_b).is_some_and(|r| r.x2 <= x1) {
+            next_b += 1;
+        }
+        let in_a = a.get(next_a).is_some_and(|r| r.x1 <= x1);
+        let in_b = b.get(next_b).is_some_and(|r| r.x1 <= x1);
+
+        if op.apply(in_a, in_b) {
+            match out.last_mut() {
+                Some(last) if last.1 == x1 => last.1 = x2,
+                _ => out.push((x1, x2)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(rects: &[(i32, i32, i32, i32)]) -> Region {
+        let mut region = Region::new();
+        for &(x, y, width, height) in rects {
+            region.add(Rect::new(x, y, width, height));
+        }
+        region
+    }
+
+    fn rects(region: &Region) -> Vec<(i32, i32, i32, i32)> {
+        region
+            .rects()
+            .map(|r| (r.x, r.y, r.width, r.height))
+            .collect()
+    }
+
+    #[test]
+    fn union_merges_touching_rects() {
+        let a = region(&[(0, 0, 10, 10)]);
+        let b = region(&[(10, 0, 5, 10)]);
+        assert_eq!(rects(&a.union(&b)), [(0, 0, 15, 10)]);
+        assert_eq!(rects(&b.union(&a)), [(0, 0, 15, 10)]);
+    }
+
+    #[test]
+    fn union_splits_overlapping_rects_into_bands() {
+        let a = region(&[(0, 0, 10, 10)]);
+        let b = region(&[(5, 5, 10, 10)]);
+        let expected = [(0, 0, 10, 5), (0, 5, 15, 5), (5, 10, 10, 5)];
+        assert_eq!(rects(&a.union(&b)), expected);
+        assert_eq!(a.union(&b), b.union(&a));
+    }
+
+    #[test]
+    fn adjacent_bands_with_the_same_spans_merge() {
+        let stacked = region(&[(0, 0, 10, 5), (0, 5, 10, 5)]);
+        assert_eq!(rects(&stacked), [(0, 0, 10, 10)]);
+
+        // Bands only merge when they touch.
+        let apart = region(&[(0, 0, 10, 5), (0, 6, 10, 5)]);
+        assert_eq!(rects(&apart), [(0, 0, 10, 5), (0, 6, 10, 5)]);
+
+        // Filling a hole gives back the single rectangle.
+        let mut holed = region(&[(0, 0, 10, 10)]);
+        holed.remove(Rect::new(3, 3, 2, 2));
+        assert_eq!(holed.rects().count(), 4);
+        holed.add(Rect::new(3, 3, 2, 2));
+        assert_eq!(rects(&holed), [(0, 0, 10, 10)]);
+    }
+
+    #[test]
+    fn subtract() {
+        let a = region(&[(0, 0, 10, 10)]);
+        assert_eq!(a.subtract(&region(&[(10, 0, 5, 10)])), a);
+        assert_eq!(
+            rects(&a.subtract(&region(&[(5, 0, 10, 10)]))),
+            [(0, 0, 5, 10)]
+        );
+        assert_eq!(
+            rects(&a.subtract(&region(&[(3, 3, 2, 2)]))),
+            [(0, 0, 10, 3), (0, 3, 3, 2), (5, 3, 5, 2), (0, 5, 10, 5)]
+        );
+        assert!(a.subtract(&region(&[(-5, -5, 20, 20)])).is_empty());
+    }
+
+    #[test]
+    fn intersect() {
+        let a = region(&[(0, 0, 10, 10)]);
+        assert!(a.intersect(&region(&[(10, 0, 5, 10)])).is_empty());
+        assert!(a.intersect(&region(&[(0, 10, 10, 5)])).is_empty());
+        assert_eq!(
+            rects(&a.intersect(&region(&[(5, 5, 10, 10)]))),
+            [(5, 5, 5, 5)]
+        );
+
+        let b = region(&[(0, 0, 4, 10), (6, 0, 4, 10)]);
+        let c = region(&[(2, 2, 6, 2)]);
+        assert_eq!(rects(&b.intersect(&c)), [(2, 2, 2, 2), (6, 2, 2, 2)]);
+    }
+
+    #[test]
+    fn scale_rounds_outwards() {
+        let a = region(&[(1, 1, 1, 1)]);
+        assert_eq!(rects(&a.scale(1.5)), [(1, 1, 2, 2)]);
+        assert_eq!(rects(&a.scale(0.5)), [(0, 0, 1, 1)]);
+        assert_eq!(rects(&a.scale(2.0)), [(2, 2, 2, 2)]);
+
+        // Rectangles that touch after rounding are merged.
+        let b = region(&[(0, 0, 1, 1), (2, 0, 1, 1)]);
+        assert_eq!(rects(&b.scale(0.5)), [(0, 0, 2, 1)]);
+        let c = region(&[(0, 0, 3, 1), (0, 2, 3, 1)]);
+        assert_eq!(rects(&c.scale(0.5)), [(0, 0, 2, 2)]);
+    }
+
+    #[test]
+    fn contains_excludes_right_and_bottom_edges() {
+        let a = region(&[(0, 0, 10, 5), (5, 5, 10, 5)]);
+        assert!(a.contains(0, 0));
+        assert!(a.contains(9, 4));
+        assert!(!a.contains(10, 4));
+        assert!(!a.contains(-1, 0));
+        assert!(!a.contains(0, -1));
+        assert!(a.contains(14, 5));
+        assert!(!a.contains(4, 5));
+        assert!(!a.contains(15, 5));
+        assert!(a.contains(14, 9));
+        assert!(!a.contains(14, 10));
+        assert!(!Region::new().contains(0, 0));
+    }
+}
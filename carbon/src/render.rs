@@ -0,0 +1,386 @@
+//! Software renderer that composites the surfaces of clients into a framebuffer per output.
+//!
+//! Only the parts of an output that changed since its last frame are redrawn: the damage
+//! clients report on their surfaces, and the old and new area of surfaces that moved, were
+//! restacked, appeared or disappeared.
+
+use crate::{
+    backend::OutputMode,
+    gateway::registry::{GlobalObjectId, ObjectRegistry},
+    output::OutputId,
+    protocol::{wl_output::Transform, wl_shm, Interface, WlBuffer, WlSurface},
+    region::{Rect, Region},
+    surface::{self, StackEntry, Surface},
+};
+
+use slotmap::SecondaryMap;
+
+use std::mem;
+
+/// Color of the parts of an output that are not covered by a surface.
+const BACKGROUND: u32 = 0x002b_2b2b;
+
+/// Pixels of an output, row by row, as `0x00RRGGBB`.
+pub struct Framebuffer {
+    pub width: i32,
+    pub height: i32,
+    pub pixels: Vec<u32>,
+}
+
+impl Framebuffer {
+    pub fn new(width: i32, height: i32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![BACKGROUND; width as usize * height as usize],
+        }
+    }
+
+    /// Copy the damaged part of a framebuffer of the same size, or all of it if the size
+    /// differs.
+    pub fn copy_from(&mut self, other: &Framebuffer, damage: &Region) {
+        if (self.width, self.height) != (other.width, other.height) {
+            self.width = other.width;
+            self.height = other.height;
+            self.pixels.clone_from(&other.pixels);
+            return;
+        }
+
+        for rect in damage.rects() {
+            for y in rect.y..rect.y + rect.height {
+                let start = (y * self.width + rect.x) as usize;
+                let end = start + rect.width as usize;
+                self.pixels[start..end].copy_from_slice(&other.pixels[start..end]);
+            }
+        }
+    }
+
+    /// Fill a rectangle inside the framebuffer with a color.
+    fn fill(&mut self, rect: Rect, color: u32) {
+        for y in rect.y..rect.y + rect.height {
+            let row = (y * self.width) as usize;
+            self.pixels[row + rect.x as usize..row + (rect.x + rect.width) as usize].fill(color);
+        }
+    }
+}
+
+/// A surface in the scene, with its area in global coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Placed {
+    id: GlobalObjectId,
+    rect: Rect,
+}
+
+struct OutputState {
+    framebuffer: Framebuffer,
+    /// The surfaces in the framebuffer, from bottom to top.
+    drawn: Vec<Placed>,
+    /// Damage reported by surfaces since the last frame on this output.
+    damage: Region,
+}
+
+/// A frame that was rendered for an output.
+pub struct RenderedFrame<'a> {
+    pub framebuffer: &'a Framebuffer,
+    /// The part of the framebuffer that changed since the previous frame.
+    pub damage: Region,
+    /// Surfaces that are visible on the output.
+    pub presented: Vec<GlobalObjectId>,
+    /// Buffers that could not be read because their client truncated the pool.
+    pub truncated: Vec<GlobalObjectId>,
+}
+
+pub struct Renderer {
+    outputs: SecondaryMap<OutputId, OutputState>,
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Self {
+            outputs: SecondaryMap::new(),
+        }
+    }
+
+    /// Redraw the damaged parts of an output. Outputs cover the same area starting at the
+    /// origin, as there is no output layout yet.
+    pub fn render(
+        &mut self,
+        id: OutputId,
+        mode: OutputMode,
+        registry: &mut ObjectRegistry,
+    ) -> RenderedFrame<'_> {
+        let scene = build_scene(registry);
+        self.take_surface_damage(&scene, registry);
+
+        let output_rect = Rect::new(0, 0, mode.width, mode.height);
+        let resized = self.outputs.get(id).is_none_or(|output| {
+            output.framebuffer.width != mode.width || output.framebuffer.height != mode.height
+        });
+        if resized {
+            self.outputs.insert(
+                id,
+                OutputState {
+                    framebuffer: Framebuffer::new(mode.width, mode.height),
+                    drawn: vec![],
+                    damage: Region::from(output_rect),
+                },
+            );
+        }
+        let output = &mut self.outputs[id];
+
+        let mut damage = mem::take(&mut output.damage);
+        add_scene_damage(&output.drawn, &scene, &mut damage);
+        let damage = damage.intersect(&Region::from(output_rect));
+
+        let mut truncated = vec![];
+        for rect in damage.rects() {
+            output.framebuffer.fill(rect, BACKGROUND);
+            for placed in &scene {
+                let clip = rect.intersect(&placed.rect);
+                if clip.is_empty() {
+                    continue;
+                }
+                if let Err(buffer) = draw_surface(&mut output.framebuffer, registry, placed, clip) {
+                    if !truncated.contains(&buffer) {
+                        truncated.push(buffer);
+                    }
+                }
+            }
+        }
+
+        let presented = scene
+            .iter()
+            .filter(|placed| !placed.rect.intersect(&output_rect).is_empty())
+            .map(|placed| placed.id)
+            .collect();
+        output.drawn = scene;
+
+        RenderedFrame {
+            framebuffer: &output.framebuffer,
+            damage,
+            presented,
+            truncated,
+        }
+    }
+
+    /// Move the damage committed on the surfaces in the scene to all outputs.
+    fn take_surface_damage(&mut self, scene: &[Placed], registry: &mut ObjectRegistry) {
+        for placed in scene {
+            let state = match surface::get_surface_mut(registry, placed.id) {
+                Some(surface) => &mut surface.current,
+                None => continue,
+            };
+            let bounds = Region::from(Rect::new(0, 0, placed.rect.width, placed.rect.height));
+
+            let mut damage = mem::take(&mut state.surface_damage);
+            let buffer_damage = mem::take(&mut state.buffer_damage);
+            if !buffer_damage.is_empty() {
+                if state.transform == Transform::Normal {
+                    damage = damage.union(&buffer_damage.scale(1.0 / f64::from(state.scale)));
+                } else {
+                    // Damage of transformed buffers is rare, so the whole surface is redrawn.
+                    damage = bounds.clone();
+                }
+            }
+            if damage.is_empty() {
+                continue;
+            }
+
+            let mut damage = damage.intersect(&bounds);
+            damage.translate(placed.rect.x, placed.rect.y);
+            for output in self.outputs.values_mut() {
+                output.damage = output.damage.union(&damage);
+            }
+        }
+    }
+}
+
+/// The topmost surface that accepts input at a point in global coordinates, with the position
+/// of the surface. Surfaces are stacked the same way they are drawn.
+pub fn surface_at(
+    registry: &ObjectRegistry,
+    (x, y): (f64, f64),
+) -> Option<(GlobalObjectId, (i32, i32))> {
+    let (x, y) = (x.floor() as i32, y.floor() as i32);
+    build_scene(registry).into_iter().rev().find_map(|placed| {
+        let surface = surface::get_surface(registry, placed.id)?;
+        let (sx, sy) = (placed.rect.x, placed.rect.y);
+        surface
+            .current
+            .accepts_input(x.saturating_sub(sx), y.saturating_sub(sy))
+            .then_some((placed.id, (sx, sy)))
+    })
+}
+
+/// The mapped surfaces from bottom to top. Surfaces without a role are placed at the origin with
+/// their sub-surfaces relative to them. There is no window stacking yet, so they are stacked in
+/// registry order, which does not follow creation since freed slots are reused.
+fn build_scene(registry: &ObjectRegistry) -> Vec<Placed> {
+    let roots: Vec<_> = registry
+        .iter()
+        .filter_map(|(id, object)| match object {
+            Interface::WlSurface(WlSurface { surface }) if surface.role.is_none() => Some(id),
+            _ => None,
+        })
+        .collect();
+
+    let mut scene = vec![];
+    for id in roots {
+        place_tree(registry, id, (0, 0), &mut scene);
+    }
+    scene
+}
+
+/// Add a surface and its sub-surfaces to the scene, unless it has no buffer, which hides the
+/// sub-surfaces as well.
+fn place_tree(
+    registry: &ObjectRegistry,
+    id: GlobalObjectId,
+    (x, y): (i32, i32),
+    scene: &mut Vec<Placed>,
+) {
+    let surface = match surface::get_surface(registry, id) {
+        Some(surface) => surface,
+        None => return,
+    };
+    let (width, height) = match surface.current.size() {
+        Some(size) => size,
+        None => return,
+    };
+
+    for entry in &surface.stack {
+        match *entry {
+            StackEntry::Parent => scene.push(Placed {
+                id,
+                rect: Rect::new(x, y, width, height),
+            }),
+            StackEntry::Child(child) => {
+                let position = surface::get_surface(registry, child)
+                    .and_then(Surface::subsurface)
+                    .map(|role| role.position);
+                if let Some((dx, dy)) = position {
+                    place_tree(
+                        registry,
+                        child,
+                        (x.saturating_add(dx), y.saturating_add(dy)),
+                        scene,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Damage the old and new area of surfaces that changed their place in the scene.
+fn add_scene_damage(drawn: &[Placed], scene: &[Placed], damage: &mut Region) {
+    for (i, placed) in scene.iter().enumerate() {
+        if drawn.get(i) == Some(placed) {
+            continue;
+        }
+        damage.add(placed.rect);
+        if let Some(old) = drawn.iter().find(|old| old.id == placed.id) {
+            damage.add(old.rect);
+        }
+    }
+
+    for old in drawn {
+        if !scene.iter().any(|placed| placed.id == old.id) {
+            damage.add(old.rect);
+        }
+    }
+}
+
+/// Blend the part of a surface inside `clip` onto the framebuffer. Returns the buffer of the
+/// surface if its pool was truncated.
+fn draw_surface(
+    framebuffer: &mut Framebuffer,
+    registry: &ObjectRegistry,
+    placed: &Placed,
+    clip: Rect,
+) -> Result<(), GlobalObjectId> {
+    let state = match surface::get_surface(registry, placed.id) {
+        Some(surface) => &surface.current,
+        None => return Ok(()),
+    };
+    // The contents of a buffer destroyed while attached are undefined, so nothing is drawn.
+    let (buffer_id, buffer) = match state.buffer.map(|buffer| buffer.global_id) {
+        Some(id) => match registry.get(id) {
+            Some(Interface::WlBuffer(WlBuffer { buffer })) => (id, buffer),
+            _ => return Ok(()),
+        },
+        None => return Ok(()),
+    };
+
+    let stride = buffer.stride as usize;
+    let scale = state.scale;
+    let opaque = buffer.format != wl_shm::Format::Argb8888;
+    let res = buffer.access(|data| {
+        for y in clip.y..clip.y + clip.height {
+            let row = (y * framebuffer.width) as usize;
+            for x in clip.x..clip.x + clip.width {
+                let (bx, by) = surface_to_buffer(
+                    state.transform,
+                    (x - placed.rect.x, y - placed.rect.y),
+                    (placed.rect.width, placed.rect.height),
+                );
+                let offset = (by * scale) as usize * stride + (bx * scale) as usize * 4;
+                let src = match data.get(offset..offset + 4) {
+                    Some(bytes) => u32::from_le_bytes(bytes.try_into().unwrap()),
+                    None => continue,
+                };
+
+                let dst = &mut framebuffer.pixels[row + x as usize];
+                *dst = if opaque {
+                    src & 0xff_ffff
+                } else {
+                    blend(src, *dst)
+                };
+            }
+        }
+    });
+
+    res.map_err(|_| buffer_id)
+}
+
+/// Map a point of a surface to the buffer, in surface coordinates. `size` is the size of the
+/// surface.
+#[inline]
+fn surface_to_buffer(
+    transform: Transform,
+    (x, y): (i32, i32),
+    (width, height): (i32, i32),
+) -> (i32, i32) {
+    match transform {
+        Transform::Normal => (x, y),
+        Transform::U90 => (height - 1 - y, x),
+        Transform::U180 => (width - 1 - x, height - 1 - y),
+        Transform::U270 => (y, width - 1 - x),
+        Transform::Flipped => (width - 1 - x, y),
+        Transform::Flipped90 => (height - 1 - y, width - 1 - x),
+        Transform::Flipped180 => (x, height - 1 - y),
+        Transform::Flipped270 => (y, x),
+    }
+}
+
+/// Blend a premultiplied ARGB pixel over an opaque one.
+#[inline]
+fn blend(src: u32, dst: u32) -> u32 {
+    let alpha = src >> 24;
+    if alpha == 0xff {
+        return src & 0xff_ffff;
+    }
+
+    let inv = 0xff - alpha;
+    let channel = |shift: u32| {
+        let s = (src >> shift) & 0xff;
+        let d = (dst >> shift) & 0xff;
+        (s + (d * inv + 127) / 255).min(0xff) << shift
+    };
+    channel(16) | channel(8) | channel(0)
+}
@@ -0,0 +1,207 @@
+//! Shared memory pools that clients draw their buffers into.
+
+use crate::protocol::wl_shm;
+
+use nix::{
+    libc,
+    sys::{
+        mman::{mmap, mremap, munmap, MRemapFlags, MapFlags, ProtFlags},
+        signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+    },
+};
+use thiserror::Error;
+
+use std::{
+    cell::RefCell,
+    os::unix::io::{AsRawFd, OwnedFd},
+    ptr,
+    rc::Rc,
+    slice,
+    sync::{
+        atomic::{compiler_fence, AtomicBool, AtomicUsize, Ordering},
+        Once,
+    },
+};
+
+/// Formats that are advertised to clients when they bind `wl_shm`.
+pub const SUPPORTED_FORMATS: [wl_shm::Format; 2] =
+    [wl_shm::Format::Argb8888, wl_shm::Format::Xrgb8888];
+
+/// Start and length of the mapping that is being accessed, a `SIGBUS` inside it is caused by
+/// the client truncating the file.
+static ACCESS_START: AtomicUsize = AtomicUsize::new(0);
+static ACCESS_LEN: AtomicUsize = AtomicUsize::new(0);
+/// Set by the `SIGBUS` handler when the mapping being accessed was truncated.
+static ACCESS_FAULTED: AtomicBool = AtomicBool::new(false);
+static INSTALL_SIGBUS_HANDLER: Once = Once::new();
+
+#[derive(Debug, Error)]
+pub enum ShmError {
+    #[error("Failed to map pool: {0}")]
+    Map(#[from] nix::Error),
+    #[error("Pool file was truncated by the client")]
+    Truncated,
+}
+
+/// A file shared by a client, mapped into our address space.
+pub struct ShmPool {
+    _fd: OwnedFd,
+    ptr: *mut libc::c_void,
+    size: usize,
+}
+
+impl Drop for ShmPool {
+    fn drop(&mut self) {
+        // Safety: the mapping is owned by the pool and no slices of it outlive `access`.
+        let _ = unsafe { munmap(self.ptr, self.size) };
+    }
+}
+
+impl ShmPool {
+    pub fn new(fd: OwnedFd, size: usize) -> Result<Self, ShmError> {
+        INSTALL_SIGBUS_HANDLER.call_once(install_sigbus_handler);
+
+        // Safety: a new shared mapping does not alias any memory we use.
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                size,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )?
+        };
+
+        Ok(Self { _fd: fd, ptr, size })
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Remap the pool with a larger size.
+    pub fn grow(&mut self, size: usize) -> Result<(), ShmError> {
+        debug_assert!(size >= self.size);
+        // Safety: no slices of the mapping exist outside of `access`.
+        self.ptr = unsafe { mremap(self.ptr, self.size, size, MRemapFlags::MREMAP_MAYMOVE, None)? };
+        self.size = size;
+
+        Ok(())
+    }
+
+    /// Call `f` with `len` bytes of the pool starting at `offset`.
+    ///
+    /// If the client truncated the file the missing part reads as zeroes and
+    /// `ShmError::Truncated` is returned after `f` completes.
+    pub fn access<R, F>(&self, offset: usize, len: usize, f: F) -> Result<R, ShmError>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        assert!(
+            offset.checked_add(len).is_some_and(|end| end <= self.size),
+            "access out of pool bounds"
+        );
+
+        ACCESS_START.store(self.ptr as usize, Ordering::SeqCst);
+        ACCESS_LEN.store(self.size, Ordering::SeqCst);
+        ACCESS_FAULTED.store(false, Ordering::SeqCst);
+        compiler_fence(Ordering::SeqCst);
+
+        // Safety: the range is inside the mapping, which stays mapped until `f` returns.
+        let bytes = unsafe { slice::from_raw_parts((self.ptr as *const u8).add(offset), len) };
+        let res = f(bytes);
+
+        compiler_fence(Ordering::SeqCst);
+        ACCESS_LEN.store(0, Ordering::SeqCst);
+
+        if ACCESS_FAULTED.swap(false, Ordering::SeqCst) {
+            Err(ShmError::Truncated)
+        } else {
+            Ok(res)
+        }
+    }
+}
+
+/// A buffer in a pool, which keeps the pool alive after the `wl_shm_pool` is destroyed.
+pub struct ShmBuffer {
+    pool: Rc<RefCell<ShmPool>>,
+    pub offset: usize,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per row.
+    pub stride: u32,
+    pub format: wl_shm::Format,
+}
+
+impl ShmBuffer {
+    /// Create a buffer, the caller must make sure it fits in the pool.
+    pub fn new(
+        pool: Rc<RefCell<ShmPool>>,
+        offset: usize,
+        width: u32,
+        height: u32,
+        stride: u32,
+        format: wl_shm::Format,
+    ) -> Self {
+        Self {
+            pool,
+            offset,
+            width,
+            height,
+            stride,
+            format,
+        }
+    }
+
+    /// Call `f` with the pixel data of the buffer, see `ShmPool::access`.
+    pub fn access<R, F>(&self, f: F) -> Result<R, ShmError>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let len = self.stride as usize * self.height as usize;
+        self.pool.borrow().access(self.offset, len, f)
+    }
+}
+
+fn install_sigbus_handler() {
+    let action = SigAction::new(
+        SigHandler::SigAction(handle_sigbus),
+        SaFlags::SA_SIGINFO | SaFlags::SA_NODEFER,
+        SigSet::empty(),
+    );
+    // Safety: the handler only uses async-signal-safe functions.
+    unsafe { sigaction(Signal::SIGBUS, &action) }.expect("Failed to install SIGBUS handler");
+}
+
+extern "C" fn handle_sigbus(_: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // Safety: the kernel passes a valid siginfo for SA_SIGINFO handlers.
+    let addr = unsafe { (*info).si_addr() } as usize;
+    let start = ACCESS_START.load(Ordering::SeqCst);
+    let len = ACCESS_LEN.load(Ordering::SeqCst);
+
+    if len != 0 && addr >= start && addr - start < len {
+        // Replace the pool with zeroed memory so the faulting access can complete.
+        // Safety: the range is the mapping of the pool being accessed.
+        let res = unsafe {
+            mmap(
+                start as *mut libc::c_void,
+                len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_PRIVATE | MapFlags::MAP_FIXED | MapFlags::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if res.is_ok() {
+            ACCESS_FAULTED.store(true, Ordering::SeqCst);
+            return;
+        }
+    }
+
+    // Not caused by a pool access, let the access fault again with the default action.
+    let action = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+    // Safety: restoring the default action is always sound.
+    let _ = unsafe { sigaction(Signal::SIGBUS, &action) };
+}
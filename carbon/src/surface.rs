@@ -0,0 +1,381 @@
+//! Double-buffered state of `wl_surface` objects.
+//!
+//! Requests to a surface only change its pending state, which is applied atomically to the
+//! current state when the client commits. Synchronized sub-surfaces cache the committed state
+//! instead, until the state of their parent is applied.
+
+use crate::{
+    gateway::registry::{GlobalObjectId, ObjectId, ObjectRegistry},
+    protocol::{wl_output::Transform, Interface, WlSurface},
+    region::{Rect, Region},
+};
+
+use std::mem;
+
+/// A `wl_buffer` attached to a surface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttachedBuffer {
+    /// Id of the buffer for the client, used to send `wl_buffer.release`.
+    pub id: ObjectId,
+    pub global_id: GlobalObjectId,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// The committed state of a surface.
+#[derive(Debug, Clone)]
+pub struct SurfaceState {
+    pub buffer: Option<AttachedBuffer>,
+    /// Position of the buffer relative to the previous one, as set by the last commit.
+    pub offset: (i32, i32),
+    /// Damage in surface coordinates, accumulated over commits until it is taken.
+    pub surface_damage: Region,
+    /// Damage in buffer coordinates, accumulated over commits until it is taken.
+    pub buffer_damage: Region,
+    /// `None` if no part of the surface is known to be opaque.
+    pub opaque_region: Option<Region>,
+    /// `None` if the whole surface accepts input.
+    pub input_region: Option<Region>,
+    pub scale: i32,
+    pub transform: Transform,
+    /// `wl_callback` objects waiting for the next frame.
+    pub frame_callbacks: Vec<ObjectId>,
+}
+
+impl Default for SurfaceState {
+    fn default() -> Self {
+        Self {
+            buffer: None,
+            offset: (0, 0),
+            surface_damage: Region::new(),
+            buffer_damage: Region::new(),
+            opaque_region: None,
+            input_region: None,
+            scale: 1,
+            transform: Transform::Normal,
+            frame_callbacks: vec![],
+        }
+    }
+}
+
+impl SurfaceState {
+    /// Size of the surface in surface coordinates, `None` if no buffer is attached.
+    pub fn size(&self) -> Option<(i32, i32)> {
+        self.buffer.map(|buffer| {
+            let (width, height) = if is_rotated(self.transform) {
+                (buffer.height, buffer.width)
+            } else {
+                (buffer.width, buffer.height)
+            };
+            (width / self.scale, height / self.scale)
+        })
+    }
+
+    /// Whether a point in surface coordinates is inside the surface and its input region.
+    pub fn accepts_input(&self, x: i32, y: i32) -> bool {
+        let inside = self
+            .size()
+            .is_some_and(|(width, height)| Rect::new(0, 0, width, height).contains(x, y));
+        inside
+            && self
+                .input_region
+                .as_ref()
+                .is_none_or(|region| region.contains(x, y))
+    }
+}
+
+/// State set by requests since the last commit, `None` leaves the current value unchanged.
+#[derive(Debug, Default)]
+pub struct PendingState {
+    /// `Some(None)` removes the buffer on commit.
+    pub buffer: Option<Option<AttachedBuffer>>,
+    pub offset: (i32, i32),
+    pub surface_damage: Region,
+    pub buffer_damage: Region,
+    pub opaque_region: Option<Option<Region>>,
+    pub input_region: Option<Option<Region>>,
+    pub scale: Option<i32>,
+    pub transform: Option<Transform>,
+    pub frame_callbacks: Vec<ObjectId>,
+}
+
+impl PendingState {
+    /// Add state committed later on top of this state. Returns the buffer that was replaced
+    /// before it could be shown, if any.
+    pub fn merge(&mut self, newer: PendingState) -> Option<AttachedBuffer> {
+        let mut dropped = None;
+        if let Some(buffer) = newer.buffer {
+            dropped = self
+                .buffer
+                .replace(buffer)
+                .flatten()
+                .filter(|&b| Some(b) != buffer);
+        }
+        self.offset = (
+            self.offset.0.saturating_add(newer.offset.0),
+            self.offset.1.saturating_add(newer.offset.1),
+        );
+        self.surface_damage = self.surface_damage.union(&newer.surface_damage);
+        self.buffer_damage = self.buffer_damage.union(&newer.buffer_damage);
+        if newer.opaque_region.is_some() {
+            self.opaque_region = newer.opaque_region;
+        }
+        if newer.input_region.is_some() {
+            self.input_region = newer.input_region;
+        }
+        self.scale = newer.scale.or(self.scale);
+        self.transform = newer.transform.or(self.transform);
+        self.frame_callbacks.extend(newer.frame_callbacks);
+
+        dropped
+    }
+}
+
+/// The role of a surface, which can not be changed once set.
+#[derive(Debug)]
+pub enum Role {
+    Subsurface(SubsurfaceRole),
+    /// Set by `wl_pointer.set_cursor`. Cursor surfaces are not drawn, as the outputs of the
+    /// backends have no cursor plane and the winit window shows the cursor of the host.
+    Cursor,
+}
+
+#[derive(Debug)]
+pub struct SubsurfaceRole {
+    /// `None` once the parent or the `wl_subsurface` is destroyed, which unmaps the sub-surface.
+    pub parent: Option<GlobalObjectId>,
+    /// Position relative to the parent.
+    pub position: (i32, i32),
+    /// Position that is applied together with the state of the parent.
+    pub pending_position: Option<(i32, i32)>,
+    /// Whether commits are cached until the state of the parent is applied.
+    pub sync: bool,
+}
+
+/// An entry in the stacking order of a surface and its sub-surfaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackEntry {
+    /// The surface itself.
+    Parent,
+    Child(GlobalObjectId),
+}
+
+#[derive(Debug)]
+pub struct Surface {
+    pub pending: PendingState,
+    /// State committed while the surface was synchronized.
+    pub cached: Option<PendingState>,
+    pub current: SurfaceState,
+    pub role: Option<Role>,
+    /// Stacking order of the surface and its sub-surfaces from bottom to top, which is applied
+    /// together with the state of the surface.
+    pub pending_stack: Vec<StackEntry>,
+    pub stack: Vec<StackEntry>,
+}
+
+impl Default for Surface {
+    fn default() -> Self {
+        Self {
+            pending: PendingState::default(),
+            cached: None,
+            current: SurfaceState::default(),
+            role: None,
+            pending_stack: vec![StackEntry::Parent],
+            stack: vec![StackEntry::Parent],
+        }
+    }
+}
+
+impl Surface {
+    #[inline]
+    pub fn subsurface(&self) -> Option<&SubsurfaceRole> {
+        match &self.role {
+            Some(Role::Subsurface(role)) => Some(role),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn subsurface_mut(&mut self) -> Option<&mut SubsurfaceRole> {
+        match &mut self.role {
+            Some(Role::Subsurface(role)) => Some(role),
+            _ => None,
+        }
+    }
+
+    /// The buffer and scale the surface would have once the pending state is applied.
+    pub fn pending_buffer_and_scale(&self) -> (Option<AttachedBuffer>, i32) {
+        let cached = self.cached.as_ref();
+        let buffer = self
+            .pending
+            .buffer
+            .or_else(|| cached.and_then(|c| c.buffer))
+            .unwrap_or(self.current.buffer);
+        let scale = self
+            .pending
+            .scale
+            .or_else(|| cached.and_then(|c| c.scale))
+            .unwrap_or(self.current.scale);
+
+        (buffer, scale)
+    }
+
+    /// Move the pending state into the cache, adding buffers that will never be shown to
+    /// `released`.
+    pub fn cache(&mut self, released: &mut Vec<AttachedBuffer>) {
+        let pending = mem::take(&mut self.pending);
+        match &mut self.cached {
+            Some(cached) => released.extend(cached.merge(pending)),
+            None => self.cached = Some(pending),
+        }
+    }
+
+    /// Apply the cached state and stacking order, adding the buffer that was replaced to
+    /// `released`.
+    fn apply_cached(&mut self, released: &mut Vec<AttachedBuffer>) {
+        self.stack.clone_from(&self.pending_stack);
+
+        let cached = match self.cached.take() {
+            Some(cached) => cached,
+            None => return,
+        };
+        let current = &mut self.current;
+
+        match cached.buffer {
+            Some(buffer) if buffer != current.buffer => {
+                released.extend(mem::replace(&mut current.buffer, buffer))
+            }
+            _ => (),
+        }
+        current.offset = cached.offset;
+        current.surface_damage = current.surface_damage.union(&cached.surface_damage);
+        current.buffer_damage = current.buffer_damage.union(&cached.buffer_damage);
+        if let Some(region) = cached.opaque_region {
+            current.opaque_region = region;
+        }
+        if let Some(region) = cached.input_region {
+            current.input_region = region;
+        }
+        if let Some(scale) = cached.scale {
+            current.scale = scale;
+        }
+        if let Some(transform) = cached.transform {
+            current.transform = transform;
+        }
+        current.frame_callbacks.extend(cached.frame_callbacks);
+    }
+}
+
+#[inline]
+pub fn get_surface(registry: &ObjectRegistry, id: GlobalObjectId) -> Option<&Surface> {
+    match registry.get(id)? {
+        Interface::WlSurface(WlSurface { surface }) => Some(surface),
+        _ => None,
+    }
+}
+
+#[inline]
+pub fn get_surface_mut(registry: &mut ObjectRegistry, id: GlobalObjectId) -> Option<&mut Surface> {
+    match registry.get_mut(id)? {
+        Interface::WlSurface(WlSurface { surface }) => Some(surface),
+        _ => None,
+    }
+}
+
+/// Whether a sub-surface caches its commits, because it or one of its ancestors is
+/// synchronized. Unmapped sub-surfaces have no parent to synchronize with.
+pub fn is_synchronized(registry: &ObjectRegistry, role: &SubsurfaceRole) -> bool {
+    let mut role = role;
+    loop {
+        if role.parent.is_none() {
+            return false;
+        }
+        if role.sync {
+            return true;
+        }
+        match role
+            .parent
+            .and_then(|parent| get_surface(registry, parent))
+            .and_then(Surface::subsurface)
+        {
+            Some(parent_role) => role = parent_role,
+            None => return false,
+        }
+    }
+}
+
+/// Whether `ancestor` is `id` or one of its ancestors.
+pub fn is_ancestor(
+    registry: &ObjectRegistry,
+    ancestor: GlobalObjectId,
+    id: GlobalObjectId,
+) -> bool {
+    let mut id = Some(id);
+    while let Some(current) = id {
+        if current == ancestor {
+            return true;
+        }
+        id = get_surface(registry, current)
+            .and_then(Surface::subsurface)
+            .and_then(|role| role.parent);
+    }
+    false
+}
+
+/// The surface at the root of the sub-surface tree `id` belongs to.
+pub fn root(registry: &ObjectRegistry, id: GlobalObjectId) -> GlobalObjectId {
+    let mut id = id;
+    while let Some(parent) = get_surface(registry, id)
+        .and_then(Surface::subsurface)
+        .and_then(|role| role.parent)
+    {
+        id = parent;
+    }
+    id
+}
+
+/// Apply the cached state of a surface, and the positions and cached state of its
+/// sub-surfaces. `synchronized` is whether the surface is applied because its parent is.
+///
+/// Buffers that are no longer used are added to `released`.
+pub fn apply_tree(
+    surface: &mut Surface,
+    registry: &mut ObjectRegistry,
+    synchronized: bool,
+    released: &mut Vec<AttachedBuffer>,
+) {
+    surface.apply_cached(released);
+
+    for entry in &surface.stack {
+        let child_id = match *entry {
+            StackEntry::Child(id) => id,
+            StackEntry::Parent => continue,
+        };
+        let mut object = match registry.take(child_id) {
+            Some(object) => object,
+            None => continue,
+        };
+
+        if let Interface::WlSurface(WlSurface { surface: child }) = &mut object {
+            if let Some(role) = child.subsurface_mut() {
+                if let Some(position) = role.pending_position.take() {
+                    role.position = position;
+                }
+                let child_synchronized = synchronized || role.sync;
+                if child_synchronized {
+                    apply_tree(child, registry, true, released);
+                }
+            }
+        }
+        registry.restore(child_id, object);
+    }
+}
+
+/// Whether the transform swaps the width and height of the buffer.
+#[inline]
+fn is_rotated(transform: Transform) -> bool {
+    matches!(
+        transform,
+        Transform::U90 | Transform::U270 | Transform::Flipped90 | Transform::Flipped270
+    )
+}
@@ -0,0 +1,324 @@
+//! Keymaps and keyboard state from libxkbcommon, which is loaded at runtime.
+//!
+//! Keymaps are compiled from RMLVO names (rules, model, layout, variant, options) and shared
+//! with clients as text in a sealed memfd, so one file can be passed to every client.
+
+use crate::protocol::wl_keyboard::KeyState;
+
+use nix::{
+    fcntl::{fcntl, FcntlArg, SealFlag},
+    libc,
+    sys::memfd::{memfd_create, MemFdCreateFlag},
+};
+use thiserror::Error;
+
+use std::{
+    ffi::{c_char, c_int, CStr, CString, NulError},
+    fmt,
+    fs::File,
+    io::{self, Write},
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+    ptr,
+    rc::Rc,
+};
+
+/// Keycodes of libxkbcommon are evdev scancodes plus 8, for historical X11 reasons.
+const EVDEV_OFFSET: u32 = 8;
+
+mod ffi {
+    use std::ffi::{c_char, c_int};
+
+    #[repr(C)]
+    pub struct Context([u8; 0]);
+    #[repr(C)]
+    pub struct Keymap([u8; 0]);
+    #[repr(C)]
+    pub struct State([u8; 0]);
+
+    #[repr(C)]
+    pub struct RuleNames {
+        pub rules: *const c_char,
+        pub model: *const c_char,
+        pub layout: *const c_char,
+        pub variant: *const c_char,
+        pub options: *const c_char,
+    }
+
+    pub const KEYMAP_FORMAT_TEXT_V1: c_int = 1;
+    pub const KEY_UP: c_int = 0;
+    pub const KEY_DOWN: c_int = 1;
+    pub const STATE_MODS_DEPRESSED: c_int = 1 << 0;
+    pub const STATE_MODS_LATCHED: c_int = 1 << 1;
+    pub const STATE_MODS_LOCKED: c_int = 1 << 2;
+    pub const STATE_LAYOUT_EFFECTIVE: c_int = 1 << 7;
+}
+
+#[derive(Debug, Error)]
+pub enum XkbError {
+    #[error("Failed to load libxkbcommon: {0}")]
+    Load(#[from] libloading::Error),
+    #[error("Failed to create xkb context")]
+    Context,
+    #[error("Invalid keymap names: {0}")]
+    Names(#[from] NulError),
+    #[error("Failed to compile keymap {0}")]
+    Compile(Rmlvo),
+    #[error("Failed to share keymap: {0}")]
+    Share(#[from] io::Error),
+}
+
+/// Names a keymap is compiled from. Empty names use the defaults of libxkbcommon, which can be
+/// set with the `XKB_DEFAULT_*` environment variables.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rmlvo {
+    pub rules: String,
+    pub model: String,
+    /// A comma separated list of layouts, which can be switched between with the group
+    /// switching options.
+    pub layout: String,
+    pub variant: String,
+    pub options: String,
+}
+
+impl fmt::Display for Rmlvo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "(rules: {:?}, model: {:?}, layout: {:?}, variant: {:?}, options: {:?})",
+            self.rules, self.model, self.layout, self.variant, self.options
+        )
+    }
+}
+
+/// The functions of libxkbcommon that are used.
+struct Library {
+    context_new: unsafe extern "C" fn(c_int) -> *mut ffi::Context,
+    context_unref: unsafe extern "C" fn(*mut ffi::Context),
+    keymap_new_from_names:
+        unsafe extern "C" fn(*mut ffi::Context, *const ffi::RuleNames, c_int) -> *mut ffi::Keymap,
+    keymap_get_as_string: unsafe extern "C" fn(*mut ffi::Keymap, c_int) -> *mut c_char,
+    keymap_unref: unsafe extern "C" fn(*mut ffi::Keymap),
+    state_new: unsafe extern "C" fn(*mut ffi::Keymap) -> *mut ffi::State,
+    state_unref: unsafe extern "C" fn(*mut ffi::State),
+    state_update_key: unsafe extern "C" fn(*mut ffi::State, u32, c_int) -> c_int,
+    state_serialize_mods: unsafe extern "C" fn(*mut ffi::State, c_int) -> u32,
+    state_serialize_layout: unsafe extern "C" fn(*mut ffi::State, c_int) -> u32,
+    /// Keeps the function pointers valid.
+    _library: libloading::Library,
+}
+
+impl Library {
+    fn load() -> Result<Self, libloading::Error> {
+        // Safety: libxkbcommon has no initialization routines with preconditions, and the
+        // symbols are declared with the signatures of its public headers.
+        unsafe {
+            let library = libloading::Library::new("libxkbcommon.so.0")?;
+            Ok(Self {
+                context_new: *library.get(b"xkb_context_new\0")?,
+                context_unref: *library.get(b"xkb_context_unref\0")?,
+                keymap_new_from_names: *library.get(b"xkb_keymap_new_from_names\0")?,
+                keymap_get_as_string: *library.get(b"xkb_keymap_get_as_string\0")?,
+                keymap_unref: *library.get(b"xkb_keymap_unref\0")?,
+                state_new: *library.get(b"xkb_state_new\0")?,
+                state_unref: *library.get(b"xkb_state_unref\0")?,
+                state_update_key: *library.get(b"xkb_state_update_key\0")?,
+                state_serialize_mods: *library.get(b"xkb_state_serialize_mods\0")?,
+                state_serialize_layout: *library.get(b"xkb_state_serialize_layout\0")?,
+                _library: library,
+            })
+        }
+    }
+}
+
+/// A context keymaps are compiled in.
+pub struct Xkb {
+    library: Rc<Library>,
+    context: *mut ffi::Context,
+}
+
+impl Drop for Xkb {
+    fn drop(&mut self) {
+        // Safety: the context is owned by us, keymaps hold their own reference.
+        unsafe { (self.library.context_unref)(self.context) };
+    }
+}
+
+impl Xkb {
+    pub fn new() -> Result<Self, XkbError> {
+        let library = Library::load()?;
+        // Safety: no flags are passed, so the default include paths are used.
+        let context = unsafe { (library.context_new)(0) };
+        if context.is_null() {
+            return Err(XkbError::Context);
+        }
+
+        Ok(Self {
+            library: Rc::new(library),
+            context,
+        })
+    }
+
+    pub fn compile(&self, names: &Rmlvo) -> Result<Keymap, XkbError> {
+        let c_string = |name: &str| (!name.is_empty()).then(|| CString::new(name)).transpose();
+        let as_ptr = |name: &Option<CString>| name.as_ref().map_or(ptr::null(), |n| n.as_ptr());
+        let rules = c_string(&names.rules)?;
+        let model = c_string(&names.model)?;
+        let layout = c_string(&names.layout)?;
+        let variant = c_string(&names.variant)?;
+        let options = c_string(&names.options)?;
+        let rule_names = ffi::RuleNames {
+            rules: as_ptr(&rules),
+            model: as_ptr(&model),
+            layout: as_ptr(&layout),
+            variant: as_ptr(&variant),
+            options: as_ptr(&options),
+        };
+
+        // Safety: the names outlive the call, null names select the defaults.
+        let raw = unsafe { (self.library.keymap_new_from_names)(self.context, &rule_names, 0) };
+        if raw.is_null() {
+            return Err(XkbError::Compile(names.clone()));
+        }
+
+        match share_keymap(&self.library, raw) {
+            Ok((fd, size)) => Ok(Keymap {
+                library: self.library.clone(),
+                raw,
+                fd,
+                size,
+            }),
+            Err(e) => {
+                // Safety: the keymap was created above and is not used anymore.
+                unsafe { (self.library.keymap_unref)(raw) };
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Write the text of a keymap into a sealed memfd, returning it with the size of the text.
+fn share_keymap(library: &Library, keymap: *mut ffi::Keymap) -> io::Result<(OwnedFd, u32)> {
+    // Safety: the keymap is valid, the returned string is freed below.
+    let text = unsafe { (library.keymap_get_as_string)(keymap, ffi::KEYMAP_FORMAT_TEXT_V1) };
+    if text.is_null() {
+        return Err(io::Error::other("keymap can not be serialized"));
+    }
+    // Safety: libxkbcommon returns a nul-terminated string that we own.
+    let bytes = unsafe { CStr::from_ptr(text) }.to_bytes_with_nul().to_vec();
+    unsafe { libc::free(text.cast()) };
+
+    let fd = sealed_memfd(&bytes)?;
+    Ok((fd, bytes.len() as u32))
+}
+
+/// A compiled keymap and the file it is shared with clients through.
+pub struct Keymap {
+    library: Rc<Library>,
+    raw: *mut ffi::Keymap,
+    fd: OwnedFd,
+    /// Size of the keymap text including the terminating nul.
+    size: u32,
+}
+
+impl Drop for Keymap {
+    fn drop(&mut self) {
+        // Safety: the keymap is owned by us, states hold their own reference.
+        unsafe { (self.library.keymap_unref)(self.raw) };
+    }
+}
+
+impl Keymap {
+    /// A new fd of the shared keymap file, to pass to a client.
+    #[inline]
+    pub fn fd(&self) -> io::Result<OwnedFd> {
+        self.fd.try_clone()
+    }
+
+    #[inline]
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Create a state that tracks pressed keys and modifiers with this keymap.
+    pub fn state(&self) -> KeyboardState {
+        // Safety: the keymap is valid, the state takes its own reference to it.
+        let raw = unsafe { (self.library.state_new)(self.raw) };
+        assert!(!raw.is_null(), "Failed to allocate xkb state");
+
+        KeyboardState {
+            library: self.library.clone(),
+            raw,
+        }
+    }
+}
+
+/// Modifiers and layout as sent with `wl_keyboard.modifiers`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub depressed: u32,
+    pub latched: u32,
+    pub locked: u32,
+    pub group: u32,
+}
+
+/// The modifiers and active layout resulting from the keys that were pressed.
+pub struct KeyboardState {
+    library: Rc<Library>,
+    raw: *mut ffi::State,
+}
+
+impl Drop for KeyboardState {
+    fn drop(&mut self) {
+        // Safety: the state is owned by us.
+        unsafe { (self.library.state_unref)(self.raw) };
+    }
+}
+
+impl KeyboardState {
+    /// Update the state with a key press or release, `key` is an evdev scancode.
+    pub fn update_key(&mut self, key: u32, state: KeyState) {
+        let direction = match state {
+            KeyState::Pressed => ffi::KEY_DOWN,
+            KeyState::Released => ffi::KEY_UP,
+        };
+        // Safety: the state is valid, unknown keycodes are ignored by libxkbcommon.
+        unsafe { (self.library.state_update_key)(self.raw, key + EVDEV_OFFSET, direction) };
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        let serialize = |component| {
+            // Safety: the state is valid.
+            unsafe { (self.library.state_serialize_mods)(self.raw, component) }
+        };
+
+        Modifiers {
+            depressed: serialize(ffi::STATE_MODS_DEPRESSED),
+            latched: serialize(ffi::STATE_MODS_LATCHED),
+            locked: serialize(ffi::STATE_MODS_LOCKED),
+            // Safety: the state is valid.
+            group: unsafe {
+                (self.library.state_serialize_layout)(self.raw, ffi::STATE_LAYOUT_EFFECTIVE)
+            },
+        }
+    }
+}
+
+/// Create a memfd with `data` that can not be modified anymore, so it can be shared with
+/// clients that map it.
+fn sealed_memfd(data: &[u8]) -> io::Result<OwnedFd> {
+    let fd = memfd_create(
+        c"carbon-keymap",
+        MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+    )?;
+    // Safety: the fd was just created and is not owned by anything else.
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(data)?;
+
+    let seals = SealFlag::F_SEAL_SHRINK
+        | SealFlag::F_SEAL_GROW
+        | SealFlag::F_SEAL_WRITE
+        | SealFlag::F_SEAL_SEAL;
+    fcntl(file.as_raw_fd(), FcntlArg::F_ADD_SEALS(seals))?;
+
+    Ok(OwnedFd::from(file))
+}
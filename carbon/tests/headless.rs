@@ -0,0 +1,547 @@
+//! Runs a gateway on the headless backend and talks to it over its socket with a minimal client
+//! that writes and parses the wire format itself.
+
+use carbon::{
+    backend::{Headless, OutputMode},
+    gateway::{
+        signal::ShutdownSignals,
+        socket::{ListeningSocket, SocketConfig},
+        Gateway, PingConfig,
+    },
+    input::InputEvent,
+    protocol::{wl_keyboard::KeyState, wl_pointer::ButtonState, wl_seat::Capability},
+    xkb::Rmlvo,
+};
+
+use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+
+use std::{
+    fs,
+    io::{self, IoSlice, Read, Write},
+    num::NonZeroU32,
+    os::unix::{io::AsRawFd, net::UnixStream},
+    path::PathBuf,
+    process,
+    time::{Duration, Instant},
+};
+
+const DISPLAY: u32 = 1;
+const REGISTRY: u32 = 2;
+
+const WIDTH: i32 = 64;
+const HEIGHT: i32 = 48;
+const BACKGROUND: u32 = 0x002b_2b2b;
+const RED: u32 = 0x00ff_0000;
+const BTN_LEFT: u32 = 0x110;
+const KEY_LEFTSHIFT: u32 = 42;
+
+/// An event received by the client, with its arguments as words.
+#[derive(Debug)]
+struct Event {
+    object: u32,
+    opcode: u16,
+    args: Vec<u32>,
+}
+
+impl Event {
+    /// The string argument starting at word `idx`.
+    fn string(&self, idx: usize) -> String {
+        let len = self.args[idx] as usize;
+        let bytes: Vec<u8> = self.args[idx + 1..]
+            .iter()
+            .flat_map(|word| word.to_ne_bytes())
+            .take(len.saturating_sub(1))
+            .collect();
+        String::from_utf8(bytes).unwrap()
+    }
+}
+
+/// A gateway on a headless backend with one connected client.
+struct Harness {
+    gateway: Gateway<Headless>,
+    stream: UnixStream,
+    recv_buf: Vec<u8>,
+    next_id: u32,
+    dir: PathBuf,
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl Harness {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("carbon-test-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let socket_path = dir.join("wayland");
+
+        let mode = OutputMode {
+            width: WIDTH,
+            height: HEIGHT,
+            refresh: 60_000,
+        };
+        let mut gateway = Gateway::new(
+            Headless::new(vec![mode]),
+            ListeningSocket::new(SocketConfig::Name(socket_path.clone())),
+            PingConfig::default(),
+            NonZeroU32::new(60_000).unwrap(),
+            Rmlvo::default(),
+            ShutdownSignals::new(),
+        );
+        // Let the backend create its seat before the client looks at the globals.
+        gateway.dispatch(Some(Duration::ZERO)).unwrap();
+
+        let stream = UnixStream::connect(&socket_path).unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        Self {
+            gateway,
+            stream,
+            recv_buf: vec![],
+            next_id: 3,
+            dir,
+        }
+    }
+
+    fn new_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn send(&mut self, object: u32, opcode: u16, args: &[u32]) {
+        let header = [object, ((8 + args.len() as u32 * 4) << 16) | opcode as u32];
+        let bytes: Vec<u8> = header
+            .iter()
+            .chain(args)
+            .flat_map(|word| word.to_ne_bytes())
+            .collect();
+        self.stream.write_all(&bytes).unwrap();
+    }
+
+    fn send_with_fd(&mut self, object: u32, opcode: u16, args: &[u32], fd: &impl AsRawFd) {
+        let header = [object, ((8 + args.len() as u32 * 4) << 16) | opcode as u32];
+        let bytes: Vec<u8> = header
+            .iter()
+            .chain(args)
+            .flat_map(|word| word.to_ne_bytes())
+            .collect();
+        let fds = [fd.as_raw_fd()];
+        let sent = sendmsg::<()>(
+            self.stream.as_raw_fd(),
+            &[IoSlice::new(&bytes)],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(sent, bytes.len());
+    }
+
+    /// Dispatch the gateway until the client receives an event matching `pred`, and return it
+    /// together with the events received before it.
+    fn wait_for(&mut self, mut pred: impl FnMut(&Event) -> bool) -> (Vec<Event>, Event) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut skipped = vec![];
+        loop {
+            assert!(
+                Instant::now() < deadline,
+                "timed out, received {:?}",
+                skipped
+            );
+            self.gateway
+                .dispatch(Some(Duration::from_millis(10)))
+                .unwrap();
+
+            let mut chunk = [0; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => panic!("disconnected, received {:?}", skipped),
+                Ok(len) => self.recv_buf.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => panic!("failed to read from the gateway: {}", e),
+            }
+
+            while let Some(event) = self.parse_event() {
+                if event.object == DISPLAY && event.opcode == 0 {
+                    panic!("protocol error: {}", event.string(2));
+                } else if pred(&event) {
+                    return (skipped, event);
+                }
+                skipped.push(event);
+            }
+        }
+    }
+
+    fn parse_event(&mut self) -> Option<Event> {
+        let word = |buf: &[u8], idx: usize| {
+            u32::from_ne_bytes(buf[idx * 4..idx * 4 + 4].try_into().unwrap())
+        };
+        if self.recv_buf.len() < 8 {
+            return None;
+        }
+        let size = (word(&self.recv_buf, 1) >> 16) as usize;
+        if self.recv_buf.len() < size {
+            return None;
+        }
+
+        let event = Event {
+            object: word(&self.recv_buf, 0),
+            opcode: word(&self.recv_buf, 1) as u16,
+            args: (2..size / 4).map(|idx| word(&self.recv_buf, idx)).collect(),
+        };
+        self.recv_buf.drain(..size);
+        Some(event)
+    }
+
+    /// Wait until the gateway processed all requests sent so far.
+    fn roundtrip(&mut self) -> Vec<Event> {
+        let callback = self.new_id();
+        self.send(DISPLAY, 0, &[callback]);
+        self.wait_for(|event| event.object == callback).0
+    }
+
+    /// Create a surface showing a red buffer at the origin, and wait until it was presented.
+    fn map_surface(&mut self, compositor: u32, shm: u32, width: u32, height: u32) -> u32 {
+        let pixels: Vec<u8> = (0..width * height)
+            .flat_map(|_| RED.to_ne_bytes())
+            .collect();
+        let pool_path = self.dir.join("pool");
+        fs::write(&pool_path, &pixels).unwrap();
+        let pool_file = fs::File::open(&pool_path).unwrap();
+
+        let pool = self.new_id();
+        self.send_with_fd(shm, 0, &[pool, pixels.len() as u32], &pool_file);
+        let buffer = self.new_id();
+        // wl_shm.format.xrgb8888
+        self.send(pool, 0, &[buffer, 0, width, height, width * 4, 1]);
+
+        let surface = self.new_id();
+        self.send(compositor, 0, &[surface]);
+        self.send(surface, 1, &[buffer, 0, 0]);
+        self.send(surface, 2, &[0, 0, width, height]);
+        let callback = self.new_id();
+        self.send(surface, 3, &[callback]);
+        self.send(surface, 6, &[]);
+        self.wait_for(|event| event.object == callback && event.opcode == 0);
+
+        surface
+    }
+
+    /// Bind the globals with the given interfaces, returning their ids in the same order.
+    fn bind(&mut self, interfaces: &[(&str, u32)]) -> Vec<u32> {
+        self.send(DISPLAY, 1, &[REGISTRY]);
+        let globals: Vec<_> = self
+            .roundtrip()
+            .into_iter()
+            .filter(|event| event.object == REGISTRY && event.opcode == 0)
+            .map(|event| (event.args[0], event.string(1)))
+            .collect();
+
+        interfaces
+            .iter()
+            .map(|&(interface, version)| {
+                let name = globals
+                    .iter()
+                    .find(|(_, global)| global == interface)
+                    .unwrap_or_else(|| panic!("no {} global", interface))
+                    .0;
+                let id = self.new_id();
+                let mut args = vec![name];
+                args.extend(string(interface));
+                args.extend([version, id]);
+                self.send(REGISTRY, 0, &args);
+                id
+            })
+            .collect()
+    }
+}
+
+/// Encode a string argument as words.
+fn string(s: &str) -> Vec<u32> {
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0);
+    let len = bytes.len() as u32;
+    bytes.resize(bytes.len().next_multiple_of(4), 0);
+
+    let mut words = vec![len];
+    words.extend(
+        bytes
+            .chunks(4)
+            .map(|chunk| u32::from_ne_bytes(chunk.try_into().unwrap())),
+    );
+    words
+}
+
+#[test]
+fn presents_committed_buffers() {
+    let mut harness = Harness::new("present");
+    let ids = harness.bind(&[("wl_compositor", 4), ("wl_shm", 1)]);
+    harness.map_surface(ids[0], ids[1], 16, 8);
+
+    let framebuffer = harness.gateway.backend().framebuffer(0).unwrap();
+    let pixel = |x: u32, y: u32| framebuffer.pixels[(y * WIDTH as u32 + x) as usize];
+    assert_eq!(pixel(0, 0), RED);
+    assert_eq!(pixel(15, 7), RED);
+    assert_eq!(pixel(16, 0), BACKGROUND);
+    assert_eq!(pixel(0, 8), BACKGROUND);
+}
+
+#[test]
+fn destroyed_subsurfaces_are_unmapped() {
+    let mut harness = Harness::new("subsurface");
+    let ids = harness.bind(&[("wl_compositor", 4), ("wl_shm", 1), ("wl_subcompositor", 1)]);
+    let parent = harness.map_surface(ids[0], ids[1], 8, 8);
+    let child = harness.map_surface(ids[0], ids[1], 16, 16);
+    let present = |harness: &mut Harness| {
+        let callback = harness.new_id();
+        harness.send(parent, 3, &[callback]);
+        harness.send(parent, 6, &[]);
+        harness.wait_for(|event| event.object == callback && event.opcode == 0);
+        harness
+            .gateway
+            .backend()
+            .framebuffer(0)
+            .unwrap()
+            .pixels
+            .clone()
+    };
+    let pixel = |pixels: &[u32], x: u32, y: u32| pixels[(y * WIDTH as u32 + x) as usize];
+
+    let subsurface = harness.new_id();
+    harness.send(ids[2], 1, &[subsurface, child, parent]);
+    harness.send(subsurface, 1, &[24, 0]);
+    let pixels = present(&mut harness);
+    assert_eq!(pixel(&pixels, 0, 0), RED);
+    assert_eq!(pixel(&pixels, 12, 12), BACKGROUND);
+    assert_eq!(pixel(&pixels, 24, 0), RED);
+
+    harness.send(subsurface, 0, &[]);
+    let pixels = present(&mut harness);
+    assert_eq!(pixel(&pixels, 0, 0), RED);
+    assert_eq!(pixel(&pixels, 12, 12), BACKGROUND);
+    assert_eq!(pixel(&pixels, 24, 0), BACKGROUND);
+
+    // The surface can be made a sub-surface again.
+    let subsurface = harness.new_id();
+    harness.send(ids[2], 1, &[subsurface, child, parent]);
+    harness.send(subsurface, 1, &[24, 0]);
+    let pixels = present(&mut harness);
+    assert_eq!(pixel(&pixels, 24, 0), RED);
+}
+
+#[test]
+fn pointer_events_go_to_the_surface_under_the_pointer() {
+    let mut harness = Harness::new("pointer");
+    let injector = harness.gateway.backend().injector();
+    let ids = harness.bind(&[("wl_compositor", 4), ("wl_shm", 1), ("wl_seat", 5)]);
+    let pointer = harness.new_id();
+    harness.send(ids[2], 0, &[pointer]);
+    let surface = harness.map_surface(ids[0], ids[1], 16, 8);
+    // Every event is followed by a `wl_pointer.frame`, which is skipped.
+    let is_pointer = |event: &Event| event.object == pointer && event.opcode != 5;
+
+    injector
+        .inject(InputEvent::PointerMotionAbsolute { x: 4.5, y: 2.0 })
+        .unwrap();
+    let (_, enter) = harness.wait_for(is_pointer);
+    assert_eq!((enter.opcode, enter.args[1]), (0, surface));
+    // Positions are 24.8 fixed point.
+    assert_eq!(&enter.args[2..], &[4 * 256 + 128, 2 * 256]);
+
+    // The cursor can be set with the serial of the enter event.
+    let cursor = harness.new_id();
+    harness.send(ids[0], 0, &[cursor]);
+    harness.send(pointer, 0, &[enter.args[0], cursor, 0, 0]);
+    harness.roundtrip();
+
+    injector
+        .inject(InputEvent::PointerButton {
+            button: BTN_LEFT,
+            state: ButtonState::Pressed,
+        })
+        .unwrap();
+    let (_, button) = harness.wait_for(is_pointer);
+    assert_eq!((button.opcode, button.args[2]), (3, BTN_LEFT));
+
+    // The pointer stays grabbed by the surface while the button is held.
+    injector
+        .inject(InputEvent::PointerMotionAbsolute { x: 40.0, y: 2.0 })
+        .unwrap();
+    let (_, motion) = harness.wait_for(is_pointer);
+    assert_eq!((motion.opcode, motion.args[1]), (2, 40 * 256));
+
+    injector
+        .inject(InputEvent::PointerButton {
+            button: BTN_LEFT,
+            state: ButtonState::Released,
+        })
+        .unwrap();
+    let (_, button) = harness.wait_for(is_pointer);
+    assert_eq!((button.opcode, button.args[3]), (3, 0));
+    let (_, leave) = harness.wait_for(is_pointer);
+    assert_eq!((leave.opcode, leave.args[1]), (1, surface));
+}
+
+#[test]
+fn touch_points_go_to_the_surface_they_went_down_on() {
+    let mut harness = Harness::new("touch");
+    let injector = harness.gateway.backend().injector();
+    let ids = harness.bind(&[("wl_compositor", 4), ("wl_shm", 1), ("wl_seat", 5)]);
+    let touch = harness.new_id();
+    harness.send(ids[2], 2, &[touch]);
+    let surface = harness.map_surface(ids[0], ids[1], 16, 8);
+    let is_touch = |event: &Event| event.object == touch;
+
+    // Points outside of any surface are not delivered.
+    for event in [
+        InputEvent::TouchDown {
+            id: 1,
+            x: 40.0,
+            y: 2.0,
+        },
+        InputEvent::TouchDown {
+            id: 0,
+            x: 4.5,
+            y: 2.0,
+        },
+        InputEvent::TouchFrame,
+    ] {
+        injector.inject(event).unwrap();
+    }
+    let (_, down) = harness.wait_for(is_touch);
+    assert_eq!(down.opcode, 0);
+    assert_eq!(&down.args[2..], [surface, 0, 4 * 256 + 128, 2 * 256]);
+    let (_, frame) = harness.wait_for(is_touch);
+    assert_eq!(frame.opcode, 3);
+
+    // The point keeps its surface when it moves off it.
+    for event in [
+        InputEvent::TouchMotion {
+            id: 0,
+            x: 40.0,
+            y: 2.0,
+        },
+        InputEvent::TouchUp { id: 0 },
+        InputEvent::TouchUp { id: 1 },
+        InputEvent::TouchFrame,
+    ] {
+        injector.inject(event).unwrap();
+    }
+    let (_, motion) = harness.wait_for(is_touch);
+    assert_eq!(motion.opcode, 2);
+    assert_eq!(&motion.args[1..], [0, 40 * 256, 2 * 256]);
+    let (_, up) = harness.wait_for(is_touch);
+    assert_eq!((up.opcode, up.args[2]), (1, 0));
+    let (_, frame) = harness.wait_for(is_touch);
+    assert_eq!(frame.opcode, 3);
+
+    injector
+        .inject(InputEvent::TouchDown {
+            id: 2,
+            x: 1.0,
+            y: 1.0,
+        })
+        .unwrap();
+    injector.inject(InputEvent::TouchCancel).unwrap();
+    let (_, down) = harness.wait_for(is_touch);
+    assert_eq!((down.opcode, down.args[3]), (0, 2));
+    let (_, cancel) = harness.wait_for(is_touch);
+    assert_eq!(cancel.opcode, 4);
+}
+
+#[test]
+fn seat_capabilities_follow_the_backend() {
+    let mut harness = Harness::new("capabilities");
+    let injector = harness.gateway.backend().injector();
+    let seat = harness.bind(&[("wl_seat", 5)])[0];
+    let is_capabilities = |event: &Event| event.object == seat && event.opcode == 0;
+    let (_, capabilities) = harness.wait_for(is_capabilities);
+    assert_eq!(capabilities.args[0], Capability::all().bits());
+
+    injector.set_capabilities(Capability::KEYBOARD).unwrap();
+    let (_, capabilities) = harness.wait_for(is_capabilities);
+    assert_eq!(capabilities.args[0], Capability::KEYBOARD.bits());
+
+    // Devices of capabilities the seat had before can still be created.
+    let pointer = harness.new_id();
+    harness.send(seat, 0, &[pointer]);
+    harness.roundtrip();
+}
+
+#[test]
+fn keyboard_focus_follows_clicks() {
+    let mut harness = Harness::new("keys");
+    let injector = harness.gateway.backend().injector();
+    let ids = harness.bind(&[("wl_compositor", 4), ("wl_shm", 1), ("wl_seat", 5)]);
+    let keyboard = harness.new_id();
+    harness.send(ids[2], 1, &[keyboard]);
+
+    let (_, keymap) = harness.wait_for(|event| event.object == keyboard && event.opcode == 0);
+    // wl_keyboard.keymap_format.xkb_v1
+    assert_eq!(keymap.args[0], 1, "keyboard tests need libxkbcommon");
+    let surface = harness.map_surface(ids[0], ids[1], 16, 8);
+    // Nothing is sent to keyboards before their client has the focus.
+    let events = harness.roundtrip();
+    assert!(!events.iter().any(|event| event.object == keyboard));
+
+    injector
+        .inject(InputEvent::PointerMotionAbsolute { x: 4.0, y: 4.0 })
+        .unwrap();
+    for state in [ButtonState::Pressed, ButtonState::Released] {
+        injector
+            .inject(InputEvent::PointerButton {
+                button: BTN_LEFT,
+                state,
+            })
+            .unwrap();
+    }
+    let is_keyboard = |event: &Event| event.object == keyboard;
+    let (_, enter) = harness.wait_for(is_keyboard);
+    assert_eq!((enter.opcode, enter.args[1]), (1, surface));
+    // The modifiers of the seat are sent right after enter.
+    let is_modifiers = |event: &Event| event.object == keyboard && event.opcode == 4;
+    let (_, modifiers) = harness.wait_for(is_modifiers);
+    assert_eq!(modifiers.args[1], 0);
+
+    injector
+        .inject(InputEvent::Key {
+            key: KEY_LEFTSHIFT,
+            state: KeyState::Pressed,
+        })
+        .unwrap();
+    // The key is sent before the modifiers it changed.
+    let is_key = |event: &Event| event.object == keyboard && event.opcode == 3;
+    let (_, key) = harness.wait_for(is_key);
+    assert_eq!((key.args[2], key.args[3]), (KEY_LEFTSHIFT, 1));
+    let (_, modifiers) = harness.wait_for(is_modifiers);
+    // Shift is the first modifier of every keymap.
+    assert_eq!(modifiers.args[1], 1);
+
+    injector
+        .inject(InputEvent::Key {
+            key: KEY_LEFTSHIFT,
+            state: KeyState::Released,
+        })
+        .unwrap();
+    let (_, key) = harness.wait_for(is_key);
+    assert_eq!((key.args[2], key.args[3]), (KEY_LEFTSHIFT, 0));
+    let (_, modifiers) = harness.wait_for(is_modifiers);
+    assert_eq!(modifiers.args[1], 0);
+
+    // Switching the layout sends the new keymap.
+    let names = Rmlvo {
+        layout: "de".into(),
+        ..Rmlvo::default()
+    };
+    harness.gateway.set_keymap(names).unwrap();
+    let (_, keymap) = harness.wait_for(|event| event.object == keyboard && event.opcode == 0);
+    assert_eq!(keymap.args[0], 1);
+
+    // A keyboard created by the focused client enters the surface right away.
+    let keyboard = harness.new_id();
+    harness.send(ids[2], 1, &[keyboard]);
+    let (_, enter) = harness.wait_for(|event| event.object == keyboard && event.opcode == 1);
+    assert_eq!(enter.args[1], surface);
+}
@@ -1,4 +1,4 @@
-use crate::{Protocol, ValueType};
+use crate::{Callable, Protocol, ValueType};
 
 use convert_case::{Case, Casing};
 use proc_macro2::{Ident, TokenStream};
@@ -78,7 +78,7 @@ impl CodeBuilder {
             use bytemuck::{cast_slice, cast_slice_mut};
             use bitflags::bitflags;
 
-            use std::os::unix::io::RawFd;
+            use std::os::unix::io::{AsRawFd, OwnedFd};
 
             pub type RequestDemarshaller = fn #demarshaller_signature;
             type DispatchEntry = [Option<RequestDemarshaller>; #max_request_count];
@@ -154,6 +154,25 @@ impl CodeBuilder {
                         let name = convert_variant_name(name);
                         quote! { #value => Ok(Self::#name) }
                     });
+                    let protocol_error = (enum_.name == "error").then(|| {
+                        quote! {
+                            impl #name {
+                                /// Create an error that is reported to the client with a
+                                /// `wl_display.error` event on `object_id`.
+                                pub fn into_message_error<S: Into<String>>(
+                                    self,
+                                    object_id: ObjectId,
+                                    message: S,
+                                ) -> MessageError {
+                                    MessageError::Protocol {
+                                        object_id,
+                                        code: self.into(),
+                                        message: message.into(),
+                                    }
+                                }
+                            }
+                        }
+                    });
 
                     quote! {
                         #[repr(u32)]
@@ -181,6 +200,8 @@ impl CodeBuilder {
                                 v as u32
                             }
                         }
+
+                        #protocol_error
                     }
                 }
             });
@@ -201,12 +222,34 @@ impl CodeBuilder {
                             arg_size = quote! { 1 };
                             quote! { #cur_chunk }
                         }
-                        ValueType::Enum { interface, name } => {
+                        ValueType::Enum { interface: enum_interface, name } => {
                             arg_size = quote! { 1 };
                             let name = format_ident!("{}", name.to_case(Case::Pascal));
-                            let interface = format_ident!("{}", interface);
-                            let enum_ty = quote! { #interface::#name };
-                            quote! { #enum_ty::try_from(#cur_chunk)? }
+                            let enum_interface = format_ident!("{}", enum_interface);
+                            let enum_ty = quote! { #enum_interface::#name };
+                            // Report invalid values with the error the interface defines for
+                            // the argument, if there is one.
+                            let error_entry = format!("invalid_{}", arg.name);
+                            let has_error = interface.enums.iter().any(|e| {
+                                e.name == "error" && e.entries.iter().any(|(n, _)| *n == error_entry)
+                            });
+                            if has_error {
+                                let variant = format_ident!("{}", error_entry.to_case(Case::Pascal));
+                                let message = format!("{{}} is not a valid {}", arg.name);
+                                quote! {
+                                    {
+                                        let __v = #cur_chunk;
+                                        #enum_ty::try_from(__v).map_err(|_| {
+                                            Error::#variant.into_message_error(
+                                                state.self_id,
+                                                format!(#message, __v as i32),
+                                            )
+                                        })?
+                                    }
+                                }
+                            } else {
+                                quote! { #enum_ty::try_from(#cur_chunk)? }
+                            }
                         }
                         ValueType::Fixed => {
                             arg_size = quote! { 1 };
@@ -280,17 +323,46 @@ impl CodeBuilder {
 
                 let fn_name = format_ident!("handle_{}", &request.name);
                 let demarshaller_signature = &self.demarshaller_signature;
+                let since = request.since;
+                let request_name = format!("{}.{}", interface.name, request.name);
+                let check_version = (since > 1).then(|| {
+                    quote! {
+                        if state.version < #since {
+                            return Err(MessageError::BadRequest(format!(
+                                "{} requires version {} but object has version {}",
+                                #request_name, #since, state.version,
+                            )));
+                        }
+                    }
+                });
                 let args = request.args.iter().map(|arg| format_ident!("{}", arg.name));
+                let call_handler = if request.is_destructor {
+                    quote! {
+                        object.#fn_name(state, #(#args),*)?;
+                        state.destroy_object(state.self_id)
+                    }
+                } else {
+                    quote! { object.#fn_name(state, #(#args),*) }
+                };
+
+                let (trace_fmt, trace_args) = trace_format(&interface.name, request);
 
                 quote! {
                     pub fn #fn_name #demarshaller_signature {
                         let object = protocol::#interface_struct::downcast(object)
                             .expect("demarshaller called with invalid object");
+                        #check_version
 
                         let mut __a = 0;
                         #(#extract_args)*
                         if __a == args.len() {
-                            object.#fn_name(state, #(#args),*)
+                            if protocol::trace::enabled() {
+                                protocol::trace::request(
+                                    state.send_buf.client_id(),
+                                    format_args!(#trace_fmt, state.self_id.raw(), #(#trace_args),*),
+                                );
+                            }
+                            #call_handler
                         } else {
                             Err(MessageError::BadFormat("argument array too long".to_owned()))
                         }
@@ -401,12 +473,40 @@ impl CodeBuilder {
                 });
 
                 let opcode = u16::try_from(opcode).expect("opcode does not fit in u16");
+                let since = event.since;
+                let (doc, version_arg, check_version) = if since > 1 {
+                    let doc = format!(
+                        " The event is only sent if `version` is at least {}.",
+                        since
+                    );
+                    (
+                        quote! { #[doc = #doc] },
+                        quote! { version: u32, },
+                        quote! {
+                            if version < #since {
+                                return Ok(());
+                            }
+                        },
+                    )
+                } else {
+                    Default::default()
+                };
+                let (trace_fmt, trace_args) = trace_format(&interface.name, event);
                 quote! {
+                    #doc
                     pub fn #fn_name(
                         send_buf: &mut MessageBuf<Write>,
                         self_id: ObjectId,
+                        #version_arg
                         #(#args),*
                     ) -> Result<(), MessageError> {
+                        #check_version
+                        if protocol::trace::enabled() {
+                            protocol::trace::event(
+                                send_buf.client_id(),
+                                format_args!(#trace_fmt, self_id.raw(), #(#trace_args),*),
+                            );
+                        }
                         let __len = 2 #( + #lengths)*;
                         let __buf = send_buf.allocate(__len)?;
                         __buf[0] = self_id.raw();
@@ -467,6 +567,64 @@ impl CodeBuilder {
     }
 }
 
+/// Build the format string and arguments of the trace line for a request or event, which
+/// expects the id of the object it was sent to as first argument.
+fn trace_format(interface_name: &str, callable: &Callable) -> (String, Vec<TokenStream>) {
+    let mut format = format!("{}@{{}}.{}(", interface_name, callable.name);
+    let mut args = vec![];
+
+    for (i, arg) in callable.args.iter().enumerate() {
+        if i != 0 {
+            format.push_str(", ");
+        }
+        format.push_str(&arg.name);
+        format.push_str(": ");
+
+        let name = format_ident!("{}", &arg.name);
+        let (spec, value) = match &arg.value_type {
+            ValueType::I32 | ValueType::U32 | ValueType::Fixed => ("{}", quote! { #name }),
+            ValueType::Enum { .. } => ("{:?}", quote! { #name }),
+            ValueType::ObjectId {
+                interface,
+                optional,
+            } => {
+                let interface = match interface {
+                    Some(interface) => quote! { Some(#interface) },
+                    None => quote! { None },
+                };
+                let id = if *optional {
+                    quote! { #name }
+                } else {
+                    quote! { Some(#name) }
+                };
+                ("{}", quote! { protocol::trace::Object(#interface, #id) })
+            }
+            ValueType::String { optional } => {
+                let value = if *optional {
+                    quote! { #name }
+                } else {
+                    quote! { Some(#name) }
+                };
+                ("{}", quote! { protocol::trace::Str(#value) })
+            }
+            ValueType::Array { optional } => {
+                let value = if *optional {
+                    quote! { #name }
+                } else {
+                    quote! { Some(#name) }
+                };
+                ("{}", quote! { protocol::trace::Array(#value) })
+            }
+            ValueType::Fd => ("fd {}", quote! { #name.as_raw_fd() }),
+        };
+        format.push_str(spec);
+        args.push(value);
+    }
+    format.push(')');
+
+    (format, args)
+}
+
 pub fn emit_stubs(protocol: &Protocol) -> TokenStream {
     let interfaces = protocol.interfaces.iter().map(|interface| {
         let interface_name = interface.name.to_case(Case::Pascal);
@@ -503,7 +661,7 @@ pub fn emit_stubs(protocol: &Protocol) -> TokenStream {
             gateway::{registry::ObjectId, message::MessageError},
         };
 
-        use std::os::unix::io::RawFd;
+        use std::os::unix::io::OwnedFd;
 
         #(#interfaces)*
     };
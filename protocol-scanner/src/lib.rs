@@ -301,14 +301,21 @@ impl ProtocolParser {
 
     fn create_cur_callable(&mut self, start: &BytesStart, is_event: bool) {
         let mut name = None;
+        let mut is_destructor = false;
+        let mut since = 1;
 
         for attribute in start.attributes().map(Result::unwrap) {
             match attribute.key {
                 b"name" => {
                     name = Some(attribute.unescape_and_decode_value(&self.reader).unwrap());
                 }
-                b"type" => (),
-                b"since" => (),
+                b"type" => match attribute.value.as_ref() {
+                    b"destructor" => is_destructor = true,
+                    ty => panic!("unexpected callable type: {}", String::from_utf8_lossy(ty)),
+                },
+                b"since" => {
+                    since = String::from_utf8_lossy(&attribute.value).parse().unwrap();
+                }
                 key => panic!(
                     "unexpected callable attribute: {}",
                     String::from_utf8_lossy(key)
@@ -318,6 +325,8 @@ impl ProtocolParser {
 
         let callable = Callable {
             name: name.expect("callable has no name"),
+            is_destructor,
+            since,
             args: vec![],
         };
 
@@ -435,6 +444,9 @@ struct Interface {
 #[derive(Debug)]
 struct Callable {
     name: String,
+    is_destructor: bool,
+    /// The first interface version the callable is available in.
+    since: u32,
     args: Vec<Argument>,
 }
 
@@ -525,7 +537,7 @@ impl ValueType {
                     quote! { &[u8] }
                 }
             }
-            ValueType::Fd => quote! { RawFd },
+            ValueType::Fd => quote! { OwnedFd },
         }
     }
 }